@@ -1,45 +1,39 @@
 #![allow(dead_code)]
-use std::cell::UnsafeCell;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use ext4::{Disk, Error};
-use ext4::io::{CoreRead};
+use std::io;
+use std::os::unix::fs::FileExt;
+use ext4::{BlockDevice, Error};
+
 #[derive(Debug)]
-pub struct  DiskFile(UnsafeCell<File>);
+pub struct  DiskFile(File);
 impl DiskFile {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: &str) -> io::Result<Self> {
         let file = File::options()
             .write(true)
             .read(true)
-            .open(name).unwrap();
-        Self(UnsafeCell::new(file))
+            .open(name)?;
+        Ok(Self(file))
     }
 }
-impl CoreRead for DiskFile{
-    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        self.read(buf)
+
+fn io_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::UnexpectedEof(e.to_string()),
+        _ => Error::IOError(e.to_string()),
     }
 }
 
-impl Disk for DiskFile{
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        match self.0.get_mut().read(buf){
-            Ok(size) => Ok(size),
-            Err(_) => Err(Error::IOError("".to_string()))
-        }
+impl BlockDevice for DiskFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.0.read_exact_at(buf, offset).map_err(io_error)
     }
-
-    fn read_block(&mut self, block_size: u64, block_num: u64, buf: &mut [u8]) -> Result<usize, Error> {
-        self.seek_to(block_num * block_size);
-        self.read(buf)
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        self.0.write_all_at(buf, offset).map_err(io_error)
     }
-    fn read_at(&mut self, offset: u64,  buf: &mut [u8]) -> Result<usize, Error> {
-        self.seek_to(offset);
-        self.read(buf)
+    fn flush(&self) -> Result<(), Error> {
+        self.0.sync_data().map_err(io_error)
     }
-
-    fn seek_to(&mut self, offset: u64) {
-        self.0.get_mut().seek(SeekFrom::Start(offset)).unwrap();
-
+    fn size(&self) -> Result<u64, Error> {
+        self.0.metadata().map(|m| m.len()).map_err(io_error)
     }
 }
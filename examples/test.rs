@@ -4,7 +4,7 @@
 extern crate core;
 mod disk;
 
-use ext4::{FileSystem};
+use ext4::{FileSystem, MountOptions};
 use disk::DiskFile;
 use clap::Parser;
 #[derive(Parser, Debug)]
@@ -17,12 +17,12 @@ struct Args {
 
 fn main() {
     let args = Args::parse();
-    let disk = DiskFile::new(args.disk.as_str());
-    let mut fs = FileSystem::new(Box::new(disk)).unwrap();
+    let disk = DiskFile::new(args.disk.as_str()).unwrap();
+    let mut fs = FileSystem::mount(Box::new(disk), MountOptions::default()).unwrap();
     println!("{}", fs.info());
     match fs.open("/") {
-        None => {}
-        Some(mut f) => {
+        Err(_) => {}
+        Ok(mut f) => {
             for e in f.read_dir().unwrap() {
                 println!("{} {}", e.name(), e.mode())
             }
@@ -31,8 +31,8 @@ fn main() {
     let file_name = "/test/test.bin";
     let f = fs.open(file_name);
     match f {
-        None => println!("{} not found", file_name),
-        Some(mut f) => {
+        Err(e) => println!("{} {:?}", file_name, e),
+        Ok(mut f) => {
             println!("{} {} {}", f.mode(), f.name(), f.size());
             let mut buffer = vec![0u8; 4096];
             f.read(&mut buffer).unwrap();
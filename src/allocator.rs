@@ -0,0 +1,264 @@
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use crate::ext4::bitmap;
+use crate::ext4::group::GroupFlags;
+use crate::ext4::superblock::FeatureRoCompat;
+use crate::{Error, FileSystem, Mode};
+
+/* every group once, starting at `start` and wrapping around to the ones before it */
+fn groups_from(start: u64, count: u64) -> impl Iterator<Item = u64> {
+    (0..count).map(move |i| (start + i) % count)
+}
+
+impl FileSystem {
+    /// flex groups at least this large keep a regular file's data out of its directory's group
+    const FLEX_SIZE_DIR_ALLOC_SCHEME: u64 = 4;
+
+    /* nothing is handed out or back on a read-only mount, nor with bigalloc, whose bitmaps
+     * count clusters nothing here knows how to hand out */
+    fn check_allocatable(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        match self.sb().feature_ro_compat().contains(FeatureRoCompat::BigAlloc) {
+            true => Err(Error::InvalidInput("cannot allocate on a bigalloc filesystem".to_string())),
+            false => Ok(()),
+        }
+    }
+    /// where to start looking for the data blocks of `inode_num`, as ext4_inode_to_goal_block:
+    /// the first block of its group, or with large flex groups of its flex group, one group on
+    /// for regular files so they stay clear of the directories
+    pub fn block_goal(&self, inode_num: u64, mode: Mode) -> u64 {
+        let sb = self.sb();
+        let mut group = (inode_num - 1) / sb.inodes_per_group();
+        let flex = sb.groups_per_flex();
+        if flex >= Self::FLEX_SIZE_DIR_ALLOC_SCHEME {
+            group &= !(flex - 1);
+            if mode.is_file() {
+                group += 1;
+            }
+        }
+        group.min(sb.get_groups_count() as u64 - 1) * sb.blocks_per_group() + sb.first_data_block()
+    }
+    /// allocate up to `count` contiguous blocks at `goal`, or as soon after it as there is room,
+    /// wrapping around the filesystem; returns the first block and how many, at least one, it got.
+    /// The blocks reserved for root are left alone unless `MountOptions::use_reserved`
+    pub fn alloc_blocks(&mut self, goal: u64, count: u64) -> Result<(u64, u64), Error> {
+        self.check_allocatable()?;
+        let sb = self.sb();
+        /* as ext4_has_free_clusters */
+        let available = match self.options().use_reserved {
+            true => sb.free_block_count(),
+            false => sb.free_block_count().saturating_sub(sb.reserved_block_count()),
+        };
+        if available == 0 {
+            return Err(Error::NoSpace("only reserved blocks are left".to_string()));
+        }
+        let count = count.min(available);
+        let (first_data_block, blocks_per_group) = (sb.first_data_block(), sb.blocks_per_group());
+        let goal = match (first_data_block..sb.block_count()).contains(&goal) {
+            true => goal - first_data_block,
+            false => 0,
+        };
+        let goal_group = goal / blocks_per_group;
+        for group_num in groups_from(goal_group, sb.get_groups_count() as u64) {
+            let Some(group) = self.group(group_num).filter(|g| g.free_blocks_count() > 0) else {
+                continue;
+            };
+            let (first, blocks) = (group.first_block_num(), group.blocks_count());
+            let start = match group_num == goal_group {
+                true => goal % blocks_per_group,
+                false => 0,
+            };
+            let bitmap = self.read_block_bitmap(group_num)?;
+            let Some(bit) = bitmap::find_zero(&bitmap, start, blocks).or_else(|| bitmap::find_zero(&bitmap, 0, start)) else {
+                continue;
+            };
+            let len = bitmap::zero_run(&bitmap, bit, blocks, count.max(1));
+            self.update_block_bitmap(group_num, bitmap, bit, len, true)?;
+            return Ok((first + bit, len));
+        }
+        Err(Error::NoSpace("no free block".to_string()))
+    }
+    /// allocate one block, at `goal` if it is free
+    pub fn alloc_block(&mut self, goal: u64) -> Result<u64, Error> {
+        self.alloc_blocks(goal, 1).map(|(block, _)| block)
+    }
+    /// give back `count` blocks from `start` on, which may span groups and must all be in use
+    pub fn free_blocks(&mut self, start: u64, count: u64) -> Result<(), Error> {
+        self.check_allocatable()?;
+        let sb = self.sb();
+        let (first_data_block, blocks_per_group) = (sb.first_data_block(), sb.blocks_per_group());
+        let end = start + count;
+        if start < first_data_block || end > sb.block_count() {
+            return Err(Error::InvalidInput(format!("blocks {}..{} out of range", start, end)));
+        }
+        let mut block = start;
+        while block < end {
+            let group_num = (block - first_data_block) / blocks_per_group;
+            let bit = (block - first_data_block) % blocks_per_group;
+            let len = (blocks_per_group - bit).min(end - block);
+            let bitmap = self.read_block_bitmap(group_num)?;
+            if let Some(free) = (bit..bit + len).find(|bit| !bitmap::test_bit(&bitmap, *bit)) {
+                return Err(Error::InvalidData(format!("block {} is already free", block - bit + free)));
+            }
+            self.update_block_bitmap(group_num, bitmap, bit, len, false)?;
+            block += len;
+        }
+        Ok(())
+    }
+    /* flip `len` bits of a group's block bitmap from `bit` on and account for them in the
+     * group descriptor and the superblock */
+    fn update_block_bitmap(&mut self, group_num: u64, mut bitmap: Vec<u8>, bit: u64, len: u64, used: bool) -> Result<(), Error> {
+        for bit in bit..bit + len {
+            match used {
+                true => bitmap::set_bit(&mut bitmap, bit),
+                false => bitmap::clear_bit(&mut bitmap, bit),
+            }
+        }
+        let block = self.group(group_num).map(|g| g.block_bitmap()).unwrap_or_default();
+        self.write_meta_block(block, &bitmap)?;
+        self.update_group(group_num, |group| {
+            let free = group.free_blocks_count();
+            group.set_free_blocks_count(match used {
+                true => free.saturating_sub(len),
+                false => free + len,
+            });
+            group.set_flags(group.flags() - GroupFlags::BlockUninit);
+            group.set_block_bitmap_checksum(&bitmap);
+        })?;
+        self.update_sb(|sb| {
+            let free = sb.free_block_count();
+            sb.set_free_block_count(match used {
+                true => free.saturating_sub(len),
+                false => free + len,
+            });
+        })
+    }
+    /* the pre-Orlov find_group_dir: among the groups with at least the average of free inodes,
+     * the one with the most free blocks */
+    fn find_group_dir(&self) -> Option<u64> {
+        let average = self.sb().free_inodes_count() / self.sb().get_groups_count() as u64;
+        self.groups()
+            .filter(|g| g.free_inodes_count() > 0 && g.free_inodes_count() >= average)
+            .max_by_key(|g| g.free_blocks_count())
+            .map(|g| g.num())
+    }
+    /// allocate an inode for a new file of type `mode` in the directory `parent`: a directory
+    /// goes to the group with the most free blocks of those with at least the average of free
+    /// inodes, as ext4 did before Orlov and still does with `oldalloc`, anything else next to its
+    /// parent, starting from the parent's flex group with flex_bg
+    pub fn alloc_inode(&mut self, parent: u64, mode: Mode) -> Result<u64, Error> {
+        self.check_allocatable()?;
+        let sb = self.sb();
+        if parent == 0 || parent > sb.inodes_count() {
+            return Err(Error::InvalidInput(format!("inode {} out of range", parent)));
+        }
+        let (inodes_per_group, first_ino) = (sb.inodes_per_group(), sb.first_ino());
+        let parent_group = (parent - 1) / inodes_per_group;
+        let flex = sb.groups_per_flex();
+        let start = match mode.is_dir() {
+            true => self.find_group_dir().unwrap_or(parent_group),
+            false => parent_group / flex * flex,
+        };
+        for group_num in groups_from(start, sb.get_groups_count() as u64) {
+            if !self.group(group_num).is_some_and(|g| g.free_inodes_count() > 0) {
+                continue;
+            }
+            let bitmap = self.read_inode_bitmap(group_num)?;
+            /* the inodes below s_first_ino are the filesystem's own, all of them in group 0 */
+            let first = match group_num {
+                0 => first_ino - 1,
+                _ => 0,
+            };
+            let Some(bit) = bitmap::find_zero(&bitmap, first, inodes_per_group) else {
+                continue;
+            };
+            self.update_inode_bitmap(group_num, bitmap, bit, true, mode.is_dir())?;
+            return Ok(group_num * inodes_per_group + bit + 1);
+        }
+        Err(Error::NoSpace("no free inode".to_string()))
+    }
+    /// give back `inode_num`, a directory if `mode` says so, which must be in use
+    pub fn free_inode(&mut self, inode_num: u64, mode: Mode) -> Result<(), Error> {
+        self.check_allocatable()?;
+        let sb = self.sb();
+        if inode_num < sb.first_ino() || inode_num > sb.inodes_count() {
+            return Err(Error::InvalidInput(format!("inode {} cannot be freed", inode_num)));
+        }
+        let group_num = (inode_num - 1) / sb.inodes_per_group();
+        let bit = (inode_num - 1) % sb.inodes_per_group();
+        let bitmap = self.read_inode_bitmap(group_num)?;
+        if !bitmap::test_bit(&bitmap, bit) {
+            return Err(Error::InvalidData(format!("inode {} is already free", inode_num)));
+        }
+        self.update_inode_bitmap(group_num, bitmap, bit, false, mode.is_dir())
+    }
+    /* flip one bit of a group's inode bitmap and account for it in the group descriptor, where
+     * a new inode past the never used tail of the table shrinks `itable_unused`, and in the
+     * superblock */
+    fn update_inode_bitmap(&mut self, group_num: u64, mut bitmap: Vec<u8>, bit: u64, used: bool, is_dir: bool) -> Result<(), Error> {
+        match used {
+            true => bitmap::set_bit(&mut bitmap, bit),
+            false => bitmap::clear_bit(&mut bitmap, bit),
+        }
+        let block = self.group(group_num).map(|g| g.inode_bitmap()).unwrap_or_default();
+        self.write_meta_block(block, &bitmap)?;
+        let inodes_per_group = self.sb().inodes_per_group();
+        let has_csum = self.sb().has_group_desc_csum();
+        self.update_group(group_num, |group| {
+            let (free, dirs) = (group.free_inodes_count(), group.used_dirs_count());
+            match used {
+                true => {
+                    group.set_free_inodes_count(free.saturating_sub(1));
+                    if is_dir {
+                        group.set_used_dirs_count(dirs + 1);
+                    }
+                    if has_csum && bit >= inodes_per_group - group.itable_unused() {
+                        group.set_itable_unused(inodes_per_group - bit - 1);
+                    }
+                }
+                false => {
+                    group.set_free_inodes_count(free + 1);
+                    if is_dir {
+                        group.set_used_dirs_count(dirs.saturating_sub(1));
+                    }
+                }
+            }
+            group.set_flags(group.flags() - GroupFlags::InodeUninit);
+            group.set_inode_bitmap_checksum(&bitmap);
+        })?;
+        self.update_sb(|sb| {
+            let free = sb.free_inodes_count();
+            sb.set_free_inodes_count(match used {
+                true => free.saturating_sub(1),
+                false => free + 1,
+            });
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use crate::{Error, FileSystem, MountOptions};
+    use crate::ext4::testdata::MemDevice;
+
+    #[test]
+    fn reserved_blocks() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        let reserved = fs.sb().reserved_block_count();
+        assert_eq!(reserved, 204);
+        let free = fs.sb().free_block_count();
+        let (_, len) = fs.alloc_blocks(0, free).unwrap();
+        assert_eq!(len, free - reserved);
+        assert!(matches!(fs.alloc_block(0), Err(Error::NoSpace(_))));
+        drop(fs);
+        let options = MountOptions { use_reserved: true, ..MountOptions::default() };
+        let mut fs = FileSystem::mount(Box::new(device), options).unwrap();
+        assert_eq!(fs.sb().free_block_count(), reserved);
+        let (_, len) = fs.alloc_blocks(0, free).unwrap();
+        assert_eq!(len, reserved);
+        assert!(matches!(fs.alloc_block(0), Err(Error::NoSpace(_))));
+    }
+}
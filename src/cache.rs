@@ -0,0 +1,380 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use crate::ext4::BlockDevice;
+use crate::Error;
+
+/// how well a `BlockCache` is doing
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// reads of shadowed blocks, which the device could not have served and are no hits
+    pub shadow_reads: u64,
+    /// dirty blocks a write-back cache wrote to the device
+    pub write_backs: u64,
+}
+
+/// least recently used cache of whole blocks in front of a `BlockDevice`, all reads of a mounted
+/// filesystem, metadata and file data alike, go through it. Writes go straight to the device and
+/// update the blocks already held, or with write-back stay in the cache until `flush`, an
+/// eviction or the cache being dropped
+pub struct BlockCache {
+    device: Box<dyn BlockDevice>,
+    block_size: u64,
+    /* most blocks kept, 0 passes every read and write through */
+    capacity: usize,
+    write_back: bool,
+    state: RefCell<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /* block number -> (data, last use) */
+    blocks: BTreeMap<u64, (Vec<u8>, u64)>,
+    /* last use -> block number, least recently used first */
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats,
+    /* blocks read from here instead of the device, never evicted */
+    overlay: BTreeMap<u64, Vec<u8>>,
+    /* held blocks newer than the device's, always in `blocks` */
+    dirty: BTreeSet<u64>,
+}
+
+impl BlockCache {
+    pub fn new(device: Box<dyn BlockDevice>, block_size: u64, capacity: usize) -> Self {
+        Self { device, block_size, capacity, write_back: false, state: RefCell::new(CacheState::default()) }
+    }
+    /// a cache holding writes back until `flush`; without room for any block it writes through
+    pub fn new_write_back(device: Box<dyn BlockDevice>, block_size: u64, capacity: usize) -> Self {
+        let mut cache = Self::new(device, block_size, capacity);
+        cache.write_back = capacity > 0;
+        cache
+    }
+    #[inline]
+    pub fn stats(&self) -> CacheStats {
+        self.state.borrow().stats
+    }
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// number of blocks held right now
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.state.borrow().blocks.len()
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.state.borrow().blocks.is_empty()
+    }
+    /// blocks written to the cache and not yet to the device
+    #[inline]
+    pub fn dirty(&self) -> usize {
+        self.state.borrow().dirty.len()
+    }
+    /// read `data` for `block_num` from now on whatever the device holds, for a journal replayed
+    /// without writing to the device; later writes go to the device and the shadowed copy alike
+    pub fn shadow(&self, block_num: u64, data: Vec<u8>) {
+        let mut state = self.state.borrow_mut();
+        /* a dirty copy is what the device is still to get */
+        if !state.dirty.contains(&block_num) {
+            if let Some((_, used)) = state.blocks.remove(&block_num) {
+                state.lru.remove(&used);
+            }
+        }
+        state.overlay.insert(block_num, data);
+    }
+    /// write `buf` at `offset` into shadowed copies of the blocks it covers, shadowing them
+    /// first, and leave the device alone; how a transaction holds back its changes
+    pub fn shadow_write(&self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        let block_size = self.block_size;
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let block_num = pos / block_size;
+            let start = (pos % block_size) as usize;
+            let n = (block_size as usize - start).min(buf.len() - done);
+            if !self.state.borrow().overlay.contains_key(&block_num) {
+                let mut data = vec![0u8; block_size as usize];
+                if n != block_size as usize {
+                    self.with_block(block_num, |cached| data.copy_from_slice(cached))?;
+                }
+                self.shadow(block_num, data);
+            }
+            let mut state = self.state.borrow_mut();
+            let data = state.overlay.get_mut(&block_num).unwrap();
+            data[start..start + n].copy_from_slice(&buf[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+    /// stop shadowing, handing back what was held by block number; they read from the device
+    /// again
+    pub fn take_shadows(&self) -> BTreeMap<u64, Vec<u8>> {
+        core::mem::take(&mut self.state.borrow_mut().overlay)
+    }
+    /// blocks of the `len` bytes at `offset` not shadowed yet, which `shadow_write` would add
+    pub fn unshadowed(&self, offset: u64, len: usize) -> usize {
+        if len == 0 {
+            return 0;
+        }
+        let state = self.state.borrow();
+        let (first, last) = (offset / self.block_size, (offset + len as u64 - 1) / self.block_size);
+        (first..=last).filter(|block_num| !state.overlay.contains_key(block_num)).count()
+    }
+    /// blocks held by `shadow`
+    #[inline]
+    pub fn shadowed(&self) -> usize {
+        self.state.borrow().overlay.len()
+    }
+    /// forget every cached block, for when the device changed behind our back; shadowed and
+    /// dirty blocks stay
+    pub fn invalidate(&self) {
+        let mut state = self.state.borrow_mut();
+        let CacheState { blocks, lru, dirty, .. } = &mut *state;
+        blocks.retain(|block_num, _| dirty.contains(block_num));
+        lru.retain(|_, block_num| dirty.contains(block_num));
+    }
+    /* hold `data` as the most recently used block, evicting the least recently used one, which
+     * goes to the device first if it is dirty, when the cache is full */
+    fn insert(&self, state: &mut CacheState, block_num: u64, data: Vec<u8>) -> Result<(), Error> {
+        if state.blocks.len() >= self.capacity {
+            if let Some((&used, &oldest)) = state.lru.first_key_value() {
+                if state.dirty.contains(&oldest) {
+                    self.device.write_at(oldest * self.block_size, &state.blocks[&oldest].0)?;
+                    state.dirty.remove(&oldest);
+                    state.stats.write_backs += 1;
+                }
+                state.lru.remove(&used);
+                state.blocks.remove(&oldest);
+                state.stats.evictions += 1;
+            }
+        }
+        state.blocks.insert(block_num, (data, state.clock));
+        state.lru.insert(state.clock, block_num);
+        Ok(())
+    }
+    /// run `f` on the contents of `block_num`, reading it from the device on a miss
+    fn with_block<T>(&self, block_num: u64, f: impl FnOnce(&[u8]) -> T) -> Result<T, Error> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        state.clock += 1;
+        if let Some(data) = state.overlay.get(&block_num) {
+            state.stats.shadow_reads += 1;
+            return Ok(f(data));
+        }
+        if let Some((data, used)) = state.blocks.get_mut(&block_num) {
+            state.lru.remove(used);
+            *used = state.clock;
+            state.lru.insert(state.clock, block_num);
+            state.stats.hits += 1;
+            return Ok(f(data));
+        }
+        state.stats.misses += 1;
+        let mut data = vec![0u8; self.block_size as usize];
+        self.device.read_at(block_num * self.block_size, &mut data)?;
+        let result = f(&data);
+        if self.capacity > 0 {
+            self.insert(state, block_num, data)?;
+        }
+        Ok(result)
+    }
+}
+
+impl BlockDevice for BlockCache {
+    /// fills all of `buf`, which may start and end anywhere inside blocks
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let block_size = self.block_size;
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let n = (block_size as usize - start).min(buf.len() - done);
+            let out = &mut buf[done..done + n];
+            self.with_block(pos / block_size, |data| out.copy_from_slice(&data[start..start + n]))?;
+            done += n;
+        }
+        Ok(())
+    }
+    /// writes through to the device, then patches the cached copies of the blocks it covers;
+    /// with write-back the blocks are cached, read first when only part of one is written, and
+    /// left dirty instead
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        if !self.write_back {
+            self.device.write_at(offset, buf)?;
+        }
+        let block_size = self.block_size;
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let n = (block_size as usize - start).min(buf.len() - done);
+            let block_num = pos / block_size;
+            let src = &buf[done..done + n];
+            if let Some(data) = state.overlay.get_mut(&block_num) {
+                data[start..start + n].copy_from_slice(src);
+            }
+            match state.blocks.get_mut(&block_num) {
+                Some((data, _)) => data[start..start + n].copy_from_slice(src),
+                None if self.write_back => {
+                    let mut data = vec![0u8; block_size as usize];
+                    if n != block_size as usize {
+                        state.stats.misses += 1;
+                        self.device.read_at(block_num * block_size, &mut data)?;
+                    }
+                    data[start..start + n].copy_from_slice(src);
+                    state.clock += 1;
+                    self.insert(state, block_num, data)?;
+                }
+                None => {}
+            }
+            if self.write_back {
+                state.dirty.insert(block_num);
+            }
+            done += n;
+        }
+        Ok(())
+    }
+    /// writes the dirty blocks back, in block order, before flushing the device
+    fn flush(&self) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        while let Some(block_num) = state.dirty.first().copied() {
+            self.device.write_at(block_num * self.block_size, &state.blocks[&block_num].0)?;
+            state.dirty.remove(&block_num);
+            state.stats.write_backs += 1;
+        }
+        self.device.flush()
+    }
+    fn size(&self) -> Result<u64, Error> {
+        self.device.size()
+    }
+    fn block_size(&self) -> u64 {
+        self.device.block_size()
+    }
+}
+
+impl Drop for BlockCache {
+    /* what is still dirty goes to the device; there is no one to tell of an error here, `flush`
+     * first to see them */
+    fn drop(&mut self) {
+        if !self.state.get_mut().dirty.is_empty() {
+            let _ = self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use core::cell::RefCell;
+    use super::BlockCache;
+    use crate::ext4::testdata::MemDevice;
+    use crate::{BlockDevice, FileSystem, Mode, MountOptions};
+
+    fn device() -> MemDevice {
+        MemDevice(Rc::new(RefCell::new(vec![0u8; 16 * 1024])))
+    }
+
+    fn read(cache: &BlockCache, block_num: u64) -> vec::Vec<u8> {
+        let mut data = vec![0u8; 1024];
+        cache.read_at(block_num * 1024, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn write_through() {
+        let device = device();
+        let cache = BlockCache::new(Box::new(device.clone()), 1024, 4);
+        assert_eq!(read(&cache, 1), vec![0; 1024]);
+        cache.write_at(1024 + 10, &[1; 4]).unwrap();
+        assert_eq!(device.block(1)[10..14], [1; 4]);
+        assert_eq!(read(&cache, 1)[10..14], [1; 4]);
+        assert_eq!(cache.dirty(), 0);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.write_backs), (1, 1, 0));
+    }
+
+    #[test]
+    fn write_back() {
+        let device = device();
+        device.set_block(3, &[3; 1024]);
+        let cache = BlockCache::new_write_back(Box::new(device.clone()), 1024, 4);
+        cache.write_at(2048, &[2; 1024]).unwrap();
+        /* only part of block 3, the rest is read from the device */
+        cache.write_at(3 * 1024 + 100, &[4; 100]).unwrap();
+        assert_eq!(cache.dirty(), 2);
+        assert_eq!(device.block(2), vec![0; 1024]);
+        assert_eq!(device.block(3), vec![3; 1024]);
+        assert_eq!(read(&cache, 2), vec![2; 1024]);
+        let expect = [vec![3; 100], vec![4; 100], vec![3; 824]].concat();
+        assert_eq!(read(&cache, 3), expect);
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty(), 0);
+        assert_eq!(device.block(2), vec![2; 1024]);
+        assert_eq!(device.block(3), expect);
+        assert_eq!(cache.stats().write_backs, 2);
+    }
+
+    #[test]
+    fn evict_and_drop() {
+        let device = device();
+        let cache = BlockCache::new_write_back(Box::new(device.clone()), 1024, 2);
+        cache.write_at(1024, &[1; 1024]).unwrap();
+        cache.write_at(2048, &[2; 1024]).unwrap();
+        /* block 1 is the least recently used and makes room for block 5 */
+        read(&cache, 5);
+        assert_eq!(device.block(1), vec![1; 1024]);
+        assert_eq!(device.block(2), vec![0; 1024]);
+        assert_eq!((cache.dirty(), cache.stats().evictions), (1, 1));
+        drop(cache);
+        assert_eq!(device.block(2), vec![2; 1024]);
+    }
+
+    #[test]
+    fn shadow_reads() {
+        let device = device();
+        let cache = BlockCache::new(Box::new(device.clone()), 1024, 4);
+        cache.shadow(7, vec![7; 1024]);
+        assert_eq!(read(&cache, 7), vec![7; 1024]);
+        let stats = cache.stats();
+        assert_eq!((stats.shadow_reads, stats.hits, stats.misses), (1, 0, 0));
+        assert_eq!(device.block(7), vec![0; 1024]);
+    }
+
+    /* a shadowed block keeps its dirty copy, which is what the device is to get */
+    #[test]
+    fn shadow_dirty() {
+        let device = device();
+        let cache = BlockCache::new_write_back(Box::new(device.clone()), 1024, 4);
+        cache.write_at(1024, &[1; 1024]).unwrap();
+        cache.shadow_write(1024, &[9; 10]).unwrap();
+        assert_eq!(read(&cache, 1)[..11], [9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 1]);
+        cache.take_shadows();
+        cache.flush().unwrap();
+        assert_eq!(device.block(1), vec![1; 1024]);
+    }
+
+    #[test]
+    fn mount_write_back() {
+        let device = MemDevice::image();
+        let options = MountOptions { write_back: true, ..MountOptions::default() };
+        let mut fs = FileSystem::mount(Box::new(device.clone()), options).unwrap();
+        fs.create("/f", Mode::from_bits_retain(0o644)).unwrap().write(&[0x42; 5000]).unwrap();
+        assert!(fs.cache_stats().write_backs > 0);
+        drop(fs);
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        let mut data = vec![0u8; 5000];
+        assert_eq!(fs.open("/f").unwrap().read(&mut data).unwrap(), 5000);
+        assert_eq!(data, vec![0x42; 5000]);
+    }
+}
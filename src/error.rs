@@ -8,4 +8,33 @@ pub enum Error {
     UnexpectedEof(String),
     InvalidData(String),
     FileExists(String),
-}
\ No newline at end of file
+    NotADirectory(String),
+    SymlinkLoop(String),
+    /// a directory where the operation wants anything else
+    IsADirectory(String),
+    /// rmdir of a directory that still has entries
+    DirectoryNotEmpty(String),
+    /// no free block or inode left to allocate
+    NoSpace(String),
+    /// a change asked of a filesystem mounted with `MountOptions::read_only`
+    ReadOnly,
+    /// a metadata checksum did not match: what was read and the block it lives in
+    Checksum(MetadataKind, u64),
+}
+
+/// on-disk structures protected by a checksum
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MetadataKind {
+    SuperBlock,
+    GroupDesc,
+    Inode,
+    ExtentBlock,
+    /// a directory leaf block, checked through its `ext4_dir_entry_tail`
+    DirBlock,
+    /// an htree root or interior node, checked through its `dx_tail`
+    DxNode,
+    BlockBitmap,
+    InodeBitmap,
+    /// a block of the journal, the block number being its place in the journal
+    Journal,
+}
@@ -0,0 +1,138 @@
+use alloc::vec::Vec;
+use crate::Mode;
+
+#[repr(u16)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AclTag {
+    UserObj = 0x01,
+    User = 0x02,
+    GroupObj = 0x04,
+    Group = 0x08,
+    Mask = 0x10,
+    Other = 0x20,
+}
+
+impl TryFrom<u16> for AclTag {
+    type Error = u16;
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::UserObj),
+            0x02 => Ok(Self::User),
+            0x04 => Ok(Self::GroupObj),
+            0x08 => Ok(Self::Group),
+            0x10 => Ok(Self::Mask),
+            0x20 => Ok(Self::Other),
+            _ => Err(value),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    /// ACL_READ | ACL_WRITE | ACL_EXECUTE
+    pub perm: u16,
+    /// uid or gid for `User` and `Group` entries, unused otherwise
+    pub id: u32,
+}
+
+/// a POSIX ACL as stored in `system.posix_acl_access` / `system.posix_acl_default`
+#[derive(Debug, Clone)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    pub const VERSION: u32 = 0x0001;
+    pub const READ: u16 = 0x04;
+    pub const WRITE: u16 = 0x02;
+    pub const EXECUTE: u16 = 0x01;
+    /// decode the compact ext4 form: a version word, then 8 byte entries for named users and
+    /// groups and 4 byte entries (tag, perm) for everything else
+    pub fn from_bytes(value: &[u8]) -> Option<Self> {
+        let u16_at = |offset: usize| u16::from_le_bytes([value[offset], value[offset + 1]]);
+        if value.len() < 4 || u32::from_le_bytes(value[..4].try_into().unwrap()) != Self::VERSION {
+            return None;
+        }
+        let mut entries = Vec::new();
+        let mut offset = 4usize;
+        while offset < value.len() {
+            if offset + 4 > value.len() {
+                return None;
+            }
+            let tag = AclTag::try_from(u16_at(offset)).ok()?;
+            let perm = u16_at(offset + 2);
+            let id = match tag {
+                AclTag::User | AclTag::Group => {
+                    if offset + 8 > value.len() {
+                        return None;
+                    }
+                    let id = u32::from_le_bytes(value[offset + 4..offset + 8].try_into().unwrap());
+                    offset += 8;
+                    id
+                }
+                _ => {
+                    offset += 4;
+                    0
+                }
+            };
+            entries.push(AclEntry { tag, perm, id });
+        }
+        Some(Self { entries })
+    }
+    #[inline]
+    pub fn entries(&self) -> &[AclEntry] {
+        &self.entries
+    }
+    /// the mask entry, which caps named users, named groups and the owning group
+    pub fn mask(&self) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == AclTag::Mask).map(|e| e.perm)
+    }
+    /// permissions an entry really grants once the mask is applied
+    pub fn effective_perm(&self, entry: &AclEntry) -> u16 {
+        match entry.tag {
+            AclTag::User | AclTag::Group | AclTag::GroupObj => entry.perm & self.mask().unwrap_or(0x7),
+            _ => entry.perm,
+        }
+    }
+    /// posix_acl_permission: may `uid` in groups `gids` do `want` (ACL_* bits) on a file owned
+    /// by `owner`:`group` carrying this ACL
+    pub fn permits(&self, owner: u32, group: u32, uid: u32, gids: &[u32], want: u16) -> bool {
+        let want = want & 0x7;
+        let mut found = false;
+        for entry in &self.entries {
+            match entry.tag {
+                AclTag::UserObj if owner == uid => return entry.perm & want == want,
+                AclTag::User if entry.id == uid => return self.effective_perm(entry) & want == want,
+                AclTag::GroupObj if gids.contains(&group) => {
+                    found = true;
+                    if entry.perm & want == want {
+                        return self.effective_perm(entry) & want == want;
+                    }
+                }
+                AclTag::Group if gids.contains(&entry.id) => {
+                    found = true;
+                    if entry.perm & want == want {
+                        return self.effective_perm(entry) & want == want;
+                    }
+                }
+                AclTag::Other => return !found && entry.perm & want == want,
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+/// the classic owner/group/other check used when a file has no ACL
+pub fn mode_permits(mode: Mode, owner: u32, group: u32, uid: u32, gids: &[u32], want: u16) -> bool {
+    let perm = mode.perm() as u16;
+    let class = if owner == uid {
+        perm >> 6
+    } else if gids.contains(&group) {
+        perm >> 3
+    } else {
+        perm
+    };
+    class & want & 0x7 == want & 0x7
+}
@@ -0,0 +1,42 @@
+/* block and inode bitmaps: bit n of byte n / 8, least significant first, set when in use */
+
+#[inline]
+pub fn test_bit(bitmap: &[u8], bit: u64) -> bool {
+    bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+}
+
+#[inline]
+pub fn set_bit(bitmap: &mut [u8], bit: u64) {
+    bitmap[(bit / 8) as usize] |= 1 << (bit % 8)
+}
+
+#[inline]
+pub fn clear_bit(bitmap: &mut [u8], bit: u64) {
+    bitmap[(bit / 8) as usize] &= !(1 << (bit % 8))
+}
+
+/// set every bit in `start..end`
+pub fn set_range(bitmap: &mut [u8], start: u64, end: u64) {
+    (start..end).for_each(|bit| set_bit(bitmap, bit))
+}
+
+/// first clear bit in `start..end`, skipping whole bytes that are full
+pub fn find_zero(bitmap: &[u8], start: u64, end: u64) -> Option<u64> {
+    let mut bit = start;
+    while bit < end {
+        if bit.is_multiple_of(8) && bit + 8 <= end && bitmap[(bit / 8) as usize] == 0xff {
+            bit += 8;
+            continue;
+        }
+        if !test_bit(bitmap, bit) {
+            return Some(bit);
+        }
+        bit += 1;
+    }
+    None
+}
+
+/// number of clear bits following `start`, at most `max` and never past `end`
+pub fn zero_run(bitmap: &[u8], start: u64, end: u64, max: u64) -> u64 {
+    (start..end.min(start + max)).take_while(|bit| !test_bit(bitmap, *bit)).count() as u64
+}
@@ -0,0 +1,172 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::{BlockDevice, Error, ExtentTree, FileSystem};
+
+/// ext2/ext3 style block map: 12 direct pointers followed by single, double and triple indirect blocks
+#[derive(Debug, Clone)]
+pub struct IndirectMap {
+    block: [u32; 15],
+    /* last indirect block read as (block number, pointers) */
+    cache: Option<(u64, Vec<u32>)>,
+}
+
+impl IndirectMap {
+    pub const DIRECT_BLOCKS: u64 = 12;
+    const IND_BLOCK: usize = 12;
+    const DIND_BLOCK: usize = 13;
+    const TIND_BLOCK: usize = 14;
+    pub fn new(block: &[u8]) -> Self {
+        let mut pointers = [0u32; 15];
+        for (i, chunk) in block.chunks_exact(4).take(15).enumerate() {
+            pointers[i] = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Self { block: pointers, cache: None }
+    }
+    fn read_pointer(&mut self, f: &dyn BlockDevice, block_size: u64, block_num: u64, index: usize) -> Result<u32, Error> {
+        match &self.cache {
+            Some((num, pointers)) if *num == block_num => return Ok(pointers[index]),
+            _ => {}
+        }
+        let mut buffer = vec![0u8; block_size as usize];
+        f.read_at(block_num * block_size, &mut buffer)?;
+        let pointers = buffer
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect::<Vec<u32>>();
+        let pointer = pointers[index];
+        self.cache = Some((block_num, pointers));
+        Ok(pointer)
+    }
+    /// map a logical file block to its physical block, `None` for a hole
+    pub fn map_block(&mut self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<Option<u64>, Error> {
+        let per_block = block_size / 4;
+        let mut logical = logical;
+        if logical < Self::DIRECT_BLOCKS {
+            return Ok(Some(self.block[logical as usize] as u64).filter(|b| *b != 0));
+        }
+        logical -= Self::DIRECT_BLOCKS;
+        let (root, levels) = if logical < per_block {
+            (Self::IND_BLOCK, 1)
+        } else if logical - per_block < per_block * per_block {
+            logical -= per_block;
+            (Self::DIND_BLOCK, 2)
+        } else if logical - per_block - per_block * per_block < per_block * per_block * per_block {
+            logical -= per_block + per_block * per_block;
+            (Self::TIND_BLOCK, 3)
+        } else {
+            return Ok(None);
+        };
+        let mut block_num = self.block[root] as u64;
+        for level in (0..levels).rev() {
+            if block_num == 0 {
+                return Ok(None);
+            }
+            let index = (logical / per_block.pow(level)) % per_block;
+            block_num = self.read_pointer(f, block_size, block_num, index as usize)? as u64;
+        }
+        Ok(Some(block_num).filter(|b| *b != 0))
+    }
+    /// give back every data and indirect block of the map; returns how many were freed
+    pub fn release(&self, fs: &mut FileSystem) -> Result<u64, Error> {
+        let mut blocks = Vec::new();
+        for (i, pointer) in self.block.iter().enumerate() {
+            let levels = (i + 1).saturating_sub(Self::IND_BLOCK);
+            Self::collect(fs, *pointer as u64, levels, &mut blocks)?;
+        }
+        blocks.sort_unstable();
+        /* runs of neighbouring blocks go back in one go */
+        let mut i = 0;
+        while i < blocks.len() {
+            let len = (i..blocks.len()).take_while(|j| blocks[*j] == blocks[i] + (j - i) as u64).count();
+            fs.free_blocks(blocks[i], len as u64)?;
+            i += len;
+        }
+        Ok(blocks.len() as u64)
+    }
+    /* `block_num` and, for an indirect block `levels` above the data, everything below it */
+    fn collect(fs: &mut FileSystem, block_num: u64, levels: usize, blocks: &mut Vec<u64>) -> Result<(), Error> {
+        if block_num == 0 {
+            return Ok(());
+        }
+        if levels > 0 {
+            let data = fs.read_block(block_num)?;
+            for pointer in data.chunks_exact(4) {
+                Self::collect(fs, u32::from_le_bytes(pointer.try_into().unwrap()) as u64, levels - 1, blocks)?;
+            }
+        }
+        blocks.push(block_num);
+        Ok(())
+    }
+}
+
+/// logical to physical block mapping of an inode, whichever format it uses
+#[derive(Debug, Clone)]
+pub enum BlockMap {
+    Extents(ExtentTree),
+    Indirect(IndirectMap),
+}
+
+impl BlockMap {
+    /// verify extent tree blocks against `seed`, indirect blocks carry no checksum
+    pub fn set_csum_seed(&mut self, seed: u32) {
+        if let BlockMap::Extents(tree) = self {
+            tree.set_csum_seed(seed)
+        }
+    }
+    /// use `block_num` even though its checksum does not match
+    pub fn trust_block(&mut self, block_num: u64) {
+        if let BlockMap::Extents(tree) = self {
+            tree.trust_block(block_num)
+        }
+    }
+    /// map a logical file block to its physical block, `None` for a hole
+    pub fn map_block(&mut self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<Option<u64>, Error> {
+        match self {
+            BlockMap::Extents(tree) => tree.map_block(f, block_size, logical),
+            BlockMap::Indirect(map) => map.map_block(f, block_size, logical),
+        }
+    }
+    /// map a logical file block as `ExtentTree::map_extent`: the physical block, how many blocks
+    /// are mapped alike from it on, one for an indirect map, and whether they are unwritten
+    pub fn map_extent(&mut self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<Option<(u64, u64, bool)>, Error> {
+        match self {
+            BlockMap::Extents(tree) => tree.map_extent(f, block_size, logical),
+            BlockMap::Indirect(map) => Ok(map.map_block(f, block_size, logical)?.map(|block| (block, 1, false))),
+        }
+    }
+    /// first logical block in `logical..end` backed by data
+    pub fn next_data(&mut self, f: &dyn BlockDevice, block_size: u64, logical: u64, end: u64) -> Result<Option<u64>, Error> {
+        match self {
+            BlockMap::Extents(tree) => Ok(tree.next_data(f, block_size, logical)?.filter(|b| *b < end)),
+            BlockMap::Indirect(map) => {
+                for block in logical..end {
+                    if map.map_block(f, block_size, block)?.is_some() {
+                        return Ok(Some(block));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+    /// first logical block in `logical..end` that is not a hole, unwritten ones included
+    pub fn next_mapped(&mut self, f: &dyn BlockDevice, block_size: u64, logical: u64, end: u64) -> Result<Option<u64>, Error> {
+        match self {
+            BlockMap::Extents(tree) => Ok(tree.next_mapped(f, block_size, logical)?.filter(|b| *b < end)),
+            BlockMap::Indirect(_) => self.next_data(f, block_size, logical, end),
+        }
+    }
+    /// first logical block in `logical..end` that is a hole, `end` if there is none
+    pub fn next_hole(&mut self, f: &dyn BlockDevice, block_size: u64, logical: u64, end: u64) -> Result<u64, Error> {
+        match self {
+            BlockMap::Extents(tree) => Ok(tree.next_hole(f, block_size, logical)?.min(end)),
+            BlockMap::Indirect(map) => {
+                for block in logical..end {
+                    if map.map_block(f, block_size, block)?.is_none() {
+                        return Ok(block);
+                    }
+                }
+                Ok(end)
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+/* reflected CRC32C (Castagnoli) polynomial */
+const CRC32C_POLY: u32 = 0x82F63B78;
+/* reflected CRC16 (IBM/ANSI, x^16 + x^15 + x^2 + 1) polynomial, as lib/crc16.c */
+const CRC16_POLY: u16 = 0xA001;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ CRC32C_POLY,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ CRC16_POLY,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+static CRC16_TABLE: [u16; 256] = crc16_table();
+
+/// crc32c_le without pre or post inversion, like the kernel's ext4_chksum
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    data.iter()
+        .fold(crc, |crc, b| (crc >> 8) ^ CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize])
+}
+
+/// crc16 as used by GDT_CSUM group descriptor checksums
+pub fn crc16(crc: u16, data: &[u8]) -> u16 {
+    data.iter()
+        .fold(crc, |crc, b| (crc >> 8) ^ CRC16_TABLE[((crc ^ *b as u16) & 0xff) as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc16, crc32c};
+
+    /* the catalogued check values: CRC-32C inverts on both ends, CRC-16/ARC starts from 0 and
+     * CRC-16/MODBUS, what GDT_CSUM computes, from !0 */
+    #[test]
+    fn check_values() {
+        assert_eq!(!crc32c(!0, b"123456789"), 0xe3069283);
+        assert_eq!(crc16(0, b"123456789"), 0xbb3d);
+        assert_eq!(crc16(!0, b"123456789"), 0x4b37);
+    }
+
+    #[test]
+    fn chaining() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let (head, tail) = data.split_at(17);
+        assert_eq!(crc32c(crc32c(!0, head), tail), crc32c(!0, data));
+        assert_eq!(crc16(crc16(!0, head), tail), crc16(!0, data));
+        assert_eq!(crc32c(0x1234, &[]), 0x1234);
+    }
+}
@@ -1,5 +1,9 @@
 use crate::{CoreRead, Inode, Mode};
+use crate::to_slice;
+use crate::ext4::checksum::crc32c;
 use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
 use core::slice::SlicePattern;
 
 #[repr(C)]
@@ -11,6 +15,54 @@ pub struct DirEntry {
     file_type: u8,
 }
 
+/// fake entry closing a leaf block on metadata_csum filesystems
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DirEntryTail {
+    det_reserved_zero1: u32, /* Pretend to be unused */
+    det_rec_len: u16,        /* 12 */
+    det_reserved_zero2: u8,  /* Zero name length */
+    det_reserved_ft: u8,     /* 0xDE, fake file type */
+    det_checksum: u32,       /* crc32c(uuid+inum+dirblock) */
+}
+
+impl DirEntryTail {
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+    pub const FILE_TYPE: u8 = 0xDE;
+    /// the tail in the last 12 bytes of `block`, if there is one
+    pub fn from_block(block: &[u8]) -> Option<Self> {
+        let mut buf = &block[block.len().checked_sub(Self::SIZE)?..];
+        let tail = buf.read_struct::<Self>().ok()?;
+        match tail.det_reserved_zero1 == 0
+            && tail.det_rec_len as usize == Self::SIZE
+            && tail.det_reserved_zero2 == 0
+            && tail.det_reserved_ft == Self::FILE_TYPE
+        {
+            true => Some(tail),
+            false => None,
+        }
+    }
+    /// put a tail with the block's checksum in the last 12 bytes of `block`
+    pub fn write(block: &mut [u8], seed: u32) {
+        let offset = block.len() - Self::SIZE;
+        let tail = Self {
+            det_reserved_zero1: 0,
+            det_rec_len: Self::SIZE as u16,
+            det_reserved_zero2: 0,
+            det_reserved_ft: Self::FILE_TYPE,
+            det_checksum: crc32c(seed, &block[..offset]),
+        };
+        block[offset..].copy_from_slice(to_slice!(&tail, DirEntryTail));
+    }
+    /// check the crc32c, seeded by the directory inode, of the block up to the tail; a block
+    /// without a tail fails
+    pub fn checksum_valid(block: &[u8], seed: u32) -> bool {
+        match Self::from_block(block) {
+            Some(tail) => crc32c(seed, &block[..block.len() - Self::SIZE]) == tail.det_checksum,
+            None => false,
+        }
+    }
+}
 
 impl DirEntry {
     pub fn from_bytes(buffer: &[u8]) -> Option<(DirEntry, String)> {
@@ -30,7 +82,155 @@ impl DirEntry {
     pub fn len(&self) -> usize{
         self.rec_len as usize
     }
-
+    /// bytes a record for a `name_len` long name needs, rounded up to 4
+    #[inline]
+    pub fn rec_len_for(name_len: usize) -> usize {
+        (core::mem::size_of::<Self>() + name_len + 3) & !3
+    }
+    /// write a record at `offset` of `block`
+    pub fn write(block: &mut [u8], offset: usize, inode: u32, rec_len: usize, name: &[u8], file_type: u8) {
+        let entry = Self { inode, rec_len: rec_len as u16, name_len: name.len() as u8, file_type };
+        let header = core::mem::size_of::<Self>();
+        block[offset..offset + header].copy_from_slice(to_slice!(&entry, DirEntry));
+        block[offset + header..offset + header + name.len()].copy_from_slice(name);
+    }
+    /// add `name` to a leaf block whose records end at `end`, in the slack of a live record or
+    /// in an unused one; false when nothing is large enough
+    pub fn insert(block: &mut [u8], end: usize, inode: u32, name: &str, file_type: u8) -> bool {
+        let needed = Self::rec_len_for(name.len());
+        let mut offset = 0usize;
+        while offset + core::mem::size_of::<Self>() <= end {
+            let mut buf = &block[offset..];
+            let entry = buf.read_struct::<DirEntry>().unwrap();
+            if entry.len() < core::mem::size_of::<Self>() || offset + entry.len() > end {
+                return false;
+            }
+            let used = match entry.inode {
+                0 => 0,
+                _ => Self::rec_len_for(entry.name_len as usize),
+            };
+            if entry.len() - used >= needed {
+                if used > 0 {
+                    block[offset + 4..offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+                }
+                Self::write(block, offset + used, inode, entry.len() - used, name.as_bytes(), file_type);
+                return true;
+            }
+            offset += entry.len();
+        }
+        false
+    }
+    /* offset of the live record of `name` in a leaf block whose records end at `end`, and of
+     * the record before it */
+    fn find(block: &[u8], end: usize, name: &str) -> Option<(usize, Option<usize>)> {
+        let mut offset = 0usize;
+        let mut prev = None;
+        while offset + core::mem::size_of::<Self>() <= end {
+            let mut buf = &block[offset..];
+            let entry = buf.read_struct::<DirEntry>().unwrap();
+            if entry.len() < core::mem::size_of::<Self>() || offset + entry.len() > end {
+                return None;
+            }
+            if entry.inode != 0 && &buf[..entry.name_len as usize] == name.as_bytes() {
+                return Some((offset, prev));
+            }
+            prev = Some(offset);
+            offset += entry.len();
+        }
+        None
+    }
+    /// drop the record of `name` from a leaf block whose records end at `end`: its space goes to
+    /// the record before it, or it is marked unused when it comes first in the block. Returns
+    /// the inode it linked to
+    pub fn remove(block: &mut [u8], end: usize, name: &str) -> Option<u32> {
+        let (offset, prev) = Self::find(block, end, name)?;
+        let inode = u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+        match prev {
+            Some(prev) => {
+                let rec_len = |offset: usize| u16::from_le_bytes([block[offset + 4], block[offset + 5]]);
+                let merged = rec_len(prev) + rec_len(offset);
+                block[prev + 4..prev + 6].copy_from_slice(&merged.to_le_bytes());
+            }
+            None => block[offset..offset + 4].fill(0),
+        }
+        Some(inode)
+    }
+    /// point the record of `name` at `inode` of type `file_type` instead; returns the inode it
+    /// linked to before
+    pub fn retarget(block: &mut [u8], end: usize, name: &str, inode: u32, file_type: u8) -> Option<u32> {
+        let (offset, _) = Self::find(block, end, name)?;
+        let old = u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+        block[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+        block[offset + 7] = file_type;
+        Some(old)
+    }
+    /// live records of a leaf block whose records end at `end`, with their names as stored
+    pub fn raw_entries(block: &[u8], end: usize) -> Vec<(DirEntry, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let mut offset = 0usize;
+        while offset + core::mem::size_of::<Self>() <= end {
+            let mut buf = &block[offset..];
+            let entry = buf.read_struct::<DirEntry>().unwrap();
+            if entry.len() < core::mem::size_of::<Self>() || offset + entry.len() > end {
+                break;
+            }
+            if entry.inode != 0 {
+                entries.push((entry, buf[..entry.name_len as usize].to_vec()));
+            }
+            offset += entry.len();
+        }
+        entries
+    }
+    /// a `block_size` leaf block holding `entries` back to back, the last one stretched to `end`
+    pub fn pack(entries: &[(DirEntry, Vec<u8>)], block_size: usize, end: usize) -> Vec<u8> {
+        let mut block = vec![0u8; block_size];
+        let mut offset = 0usize;
+        for (i, (entry, name)) in entries.iter().enumerate() {
+            let rec_len = match i + 1 == entries.len() {
+                true => end - offset,
+                false => Self::rec_len_for(name.len()),
+            };
+            Self::write(&mut block, offset, entry.inode, rec_len, name, entry.file_type);
+            offset += rec_len;
+        }
+        if entries.is_empty() {
+            block[4..6].copy_from_slice(&(end as u16).to_le_bytes());
+        }
+        block
+    }
+    /// the `DirEntryTail` dressed up as an unused entry
+    #[inline]
+    pub fn is_tail(&self) -> bool {
+        self.inode == 0
+            && self.rec_len as usize == DirEntryTail::SIZE
+            && self.name_len == 0
+            && self.file_type == DirEntryTail::FILE_TYPE
+    }
+    /// an entry that only exists in memory, like the `.` and `..` of an inline directory
+    pub(crate) fn synthetic(inode: u32, name: &str) -> Self {
+        Self { inode, rec_len: 0, name_len: name.len() as u8, file_type: 0 }
+    }
+    /// a record of `name` linking to `inode` for `pack`, which sets its length
+    pub(crate) fn record(inode: u32, name: &[u8], file_type: u8) -> (Self, Vec<u8>) {
+        (Self { inode, rec_len: 0, name_len: name.len() as u8, file_type }, name.to_vec())
+    }
+    /// live entries of a linear directory block, skipping unused (inode 0) records
+    pub fn entries(buffer: &[u8]) -> Vec<(DirEntry, String)> {
+        let mut entries = Vec::new();
+        let mut offset = 0usize;
+        while offset + core::mem::size_of::<DirEntry>() <= buffer.len() {
+            let mut buf = &buffer[offset..];
+            let entry = buf.read_struct::<DirEntry>().unwrap();
+            if entry.is_tail() || entry.len() < core::mem::size_of::<DirEntry>() || offset + entry.len() > buffer.len() {
+                break;
+            }
+            if let Some(entry) = DirEntry::from_bytes(&buffer[offset..]) {
+                entries.push(entry);
+            }
+            offset += entry.len();
+        }
+        entries
+    }
 }
 
 #[derive(Default,Debug, Clone)]
@@ -55,4 +255,29 @@ impl Entry {
     pub fn inode(&self) -> Inode{
         self.inode
     }
-}
\ No newline at end of file
+    pub fn inode_num(&self) -> u64{
+        self.entry.inode as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DirEntryTail;
+    use crate::{Inode, SuperBlock};
+    use crate::ext4::testdata::{unhex, CSUM_ROOT_DIR, CSUM_SB};
+
+    #[test]
+    fn tail_checksum() {
+        let sb = SuperBlock::from_buffer(&unhex(SuperBlock::SIZE, CSUM_SB)).unwrap();
+        /* the root directory has generation 0 */
+        let seed = Inode::default().csum_seed(2, &sb);
+        let block = unhex(1024, CSUM_ROOT_DIR);
+        assert!(DirEntryTail::checksum_valid(&block, seed));
+        assert!(!DirEntryTail::checksum_valid(&block, Inode::default().csum_seed(11, &sb)));
+        let mut copy = block.clone();
+        copy[1012..].fill(0);
+        assert!(!DirEntryTail::checksum_valid(&copy, seed));
+        DirEntryTail::write(&mut copy, seed);
+        assert_eq!(copy, block);
+    }
+}
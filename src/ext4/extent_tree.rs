@@ -1,5 +1,11 @@
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec;
 use alloc::vec::Vec;
-use crate::CoreRead;
+use crate::{BlockDevice, CoreRead, Error, FileSystem};
+use crate::to_slice;
+use crate::error::MetadataKind;
+use crate::ext4::checksum::crc32c;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -35,37 +41,597 @@ struct ExtentIdx {
     ei_unused: u16,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Extent {
+    /* ee_len above this marks an unwritten (preallocated) extent */
+    const INIT_MAX_LEN: u16 = 1 << 15;
+    fn new(logical: u32, start: u64, len: u16) -> Self {
+        Self { ee_block: logical, ee_len: len, ee_start_hi: (start >> 32) as u16, ee_start_lo: start as u32 }
+    }
+    /// an extent of `len` blocks allocated but not written yet, which read as zeros
+    fn new_unwritten(logical: u32, start: u64, len: u16) -> Self {
+        Self::new(logical, start, len + Self::INIT_MAX_LEN)
+    }
+    #[inline]
+    fn start(&self) -> u64 {
+        (self.ee_start_hi as u64) << 32 | self.ee_start_lo as u64
+    }
+    #[inline]
+    fn len(&self) -> u64 {
+        match self.is_unwritten() {
+            true => (self.ee_len - Self::INIT_MAX_LEN) as u64,
+            false => self.ee_len as u64,
+        }
+    }
+    /// first logical block past the extent
+    #[inline]
+    fn end(&self) -> u64 {
+        self.ee_block as u64 + self.len()
+    }
+    #[inline]
+    fn is_unwritten(&self) -> bool {
+        self.ee_len > Self::INIT_MAX_LEN
+    }
+}
+
+impl ExtentTail {
+    /// store the checksum of a tree block in its tail
+    fn set_checksum(block: &mut [u8], header: &ExtentHeader, seed: u32) {
+        let offset = core::mem::size_of::<ExtentHeader>() + header.eh_max as usize * core::mem::size_of::<Extent>();
+        let crc = crc32c(seed, &block[..offset]);
+        block[offset..offset + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+    /// check the crc32c, seeded by the owning inode, of everything up to the tail, which sits
+    /// right after the `eh_max` entry slots
+    fn checksum_valid(block: &[u8], header: &ExtentHeader, seed: u32) -> bool {
+        let offset = core::mem::size_of::<ExtentHeader>() + header.eh_max as usize * core::mem::size_of::<Extent>();
+        if offset + core::mem::size_of::<Self>() > block.len() {
+            return false;
+        }
+        let tail = Self { et_checksum: u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap()) };
+        crc32c(seed, &block[..offset]) == tail.et_checksum
+    }
+}
+
+impl ExtentIdx {
+    fn new(logical: u32, leaf: u64) -> Self {
+        Self { ei_block: logical, ei_leaf_lo: leaf as u32, ei_leaf_hi: (leaf >> 32) as u16, ei_unused: 0 }
+    }
+    #[inline]
+    fn leaf(&self) -> u64 {
+        (self.ei_leaf_hi as u64) << 32 | self.ei_leaf_lo as u64
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ExtentNode {
+    Leaf(Vec<Extent>),
+    Index(Vec<ExtentIdx>),
+}
+
+impl ExtentNode {
+    /// parse a node (header followed by `eh_entries` entries) from `i_block` or an on-disk tree block
+    fn from_bytes(block: &[u8]) -> Option<(ExtentHeader, Self)> {
+        let mut bytes = block;
+        let header = bytes.read_struct::<ExtentHeader>().ok()?;
+        if header.eh_magic != ExtentTree::MAGIC || header.eh_entries > header.eh_max {
+            return None;
+        }
+        let entry_size = core::mem::size_of::<Extent>();
+        if bytes.len() < header.eh_entries as usize * entry_size {
+            return None;
+        }
+        let node = match header.eh_depth {
+            0 => Self::Leaf((0..header.eh_entries).map(|_| bytes.read_struct::<Extent>().unwrap()).collect()),
+            _ => Self::Index((0..header.eh_entries).map(|_| bytes.read_struct::<ExtentIdx>().unwrap()).collect()),
+        };
+        Some((header, node))
+    }
+    #[inline]
+    fn len(&self) -> usize {
+        match self {
+            Self::Leaf(leaf) => leaf.len(),
+            Self::Index(index) => index.len(),
+        }
+    }
+    /// first logical block of the node's last entry
+    fn last_block(&self) -> u32 {
+        match self {
+            Self::Leaf(leaf) => leaf.last().map_or(0, |e| e.ee_block),
+            Self::Index(index) => index.last().map_or(0, |i| i.ei_block),
+        }
+    }
+    /// first logical block the node covers
+    fn first_block(&self) -> u32 {
+        match self {
+            Self::Leaf(leaf) => leaf.first().map_or(0, |e| e.ee_block),
+            Self::Index(index) => index.first().map_or(0, |i| i.ei_block),
+        }
+    }
+    /// move the entries from `at` on into a new node
+    fn split_off(&mut self, at: usize) -> Self {
+        match self {
+            Self::Leaf(leaf) => Self::Leaf(leaf.split_off(at)),
+            Self::Index(index) => Self::Index(index.split_off(at)),
+        }
+    }
+    /// header and entries in `buf`, which has room for `max` entries
+    fn write(&self, buf: &mut [u8], max: u16, depth: u16) {
+        let header = ExtentHeader {
+            eh_magic: ExtentTree::MAGIC,
+            eh_entries: self.len() as u16,
+            eh_max: max,
+            eh_depth: depth,
+            eh_generation: 0,
+        };
+        let mut bytes = Vec::from(to_slice!(&header, ExtentHeader));
+        match self {
+            Self::Leaf(leaf) => leaf.iter().for_each(|e| bytes.extend_from_slice(to_slice!(e, Extent))),
+            Self::Index(index) => index.iter().for_each(|i| bytes.extend_from_slice(to_slice!(i, ExtentIdx))),
+        }
+        buf[..bytes.len()].copy_from_slice(&bytes);
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ExtentTree {
     header: ExtentHeader,
-    extent: [Extent; 4]
+    root: ExtentNode,
+    /* last extent hit by map_block, sequential reads mostly stay inside it */
+    last: Option<Extent>,
+    /* inode checksum seed when tree blocks are to be verified */
+    csum_seed: Option<u32>,
+    /* blocks used despite a checksum mismatch */
+    trusted: Vec<u64>,
 }
 impl ExtentTree {
     pub const MAGIC: u16 = 0xf30a;
+    /// ext4 never builds trees deeper than this
+    pub const MAX_DEPTH: u16 = 5;
+    /// entries of the root in `i_block`
+    pub const ROOT_MAX: u16 = 4;
+    /// most blocks an initialized extent maps
+    pub const MAX_LEN: u64 = Extent::INIT_MAX_LEN as u64;
     pub fn new(block: &[u8]) -> Option<Self>{
-        let mut bytes = block;
-        let header = bytes.read_struct::<ExtentHeader>().unwrap();
-        let extent = bytes.read_struct::<[Extent;4]>().unwrap();
-        //TODO: eh_depth > 0
-        let extent_tree = Self{header, extent};
+        let (header, root) = ExtentNode::from_bytes(block)?;
+        let extent_tree = Self{header, root, last: None, csum_seed: None, trusted: Vec::new()};
         match extent_tree.is_valid() {
             true => Some(extent_tree),
             false => None
         }
     }
+    /// the tree of a new file: an empty leaf in `i_block`
+    pub fn empty() -> Self {
+        let header = ExtentHeader { eh_magic: Self::MAGIC, eh_entries: 0, eh_max: Self::ROOT_MAX, eh_depth: 0, eh_generation: 0 };
+        Self { header, root: ExtentNode::Leaf(Vec::new()), last: None, csum_seed: None, trusted: Vec::new() }
+    }
+    /// the root node as it goes back into `i_block`
+    pub fn root_bytes(&self) -> [u8; 60] {
+        let mut block = [0u8; 60];
+        self.root.write(&mut block, self.header.eh_max, self.header.eh_depth);
+        block
+    }
     #[inline]
     pub const fn is_valid(&self) -> bool{
-        self.header.eh_magic == Self::MAGIC
+        self.header.eh_magic == Self::MAGIC && self.header.eh_depth <= Self::MAX_DEPTH
     }
-    pub fn data_blocks(&self) -> Vec<u64>{
-        let mut blocks = Vec::new();
-        for extent in self.extent {
-            let start = (extent.ee_start_hi as u64) << 32 | extent.ee_start_lo as u64;
-            let len = extent.ee_len as u64;
-            blocks.extend((start..start+len).into_iter().collect::<Vec<u64>>())
+    #[inline]
+    pub const fn depth(&self) -> u16 {
+        self.header.eh_depth
+    }
+    /// verify the tail of every tree block read from now on, `seed` as from `Inode::csum_seed`
+    pub fn set_csum_seed(&mut self, seed: u32) {
+        self.csum_seed = Some(seed)
+    }
+    /// use `block_num` even though its checksum does not match
+    pub fn trust_block(&mut self, block_num: u64) {
+        self.trusted.push(block_num)
+    }
+    fn read_node(&self, f: &dyn BlockDevice, block_size: u64, block_num: u64, depth: u16) -> Result<ExtentNode, Error> {
+        let mut buffer = vec![0u8; block_size as usize];
+        f.read_at(block_num * block_size, &mut buffer)?;
+        let (header, node) = match ExtentNode::from_bytes(&buffer) {
+            Some((header, node)) if header.eh_depth == depth => (header, node),
+            _ => return Err(Error::InvalidData(format!("bad extent tree node at block {}", block_num))),
+        };
+        if let Some(seed) = self.csum_seed {
+            if !self.trusted.contains(&block_num) && !ExtentTail::checksum_valid(&buffer, &header, seed) {
+                return Err(Error::Checksum(MetadataKind::ExtentBlock, block_num));
+            }
         }
-        blocks
-
+        Ok(node)
+    }
+    /// find the leaf extent that starts at or before `logical`, descending through index nodes
+    fn find_extent(&self, f: &dyn BlockDevice, block_size: u64, logical: u32) -> Result<Option<Extent>, Error> {
+        let mut depth = self.header.eh_depth;
+        let mut child;
+        let mut node = &self.root;
+        loop {
+            match node {
+                ExtentNode::Leaf(leaf) => {
+                    let pos = leaf.partition_point(|e| e.ee_block <= logical);
+                    return Ok(pos.checked_sub(1).map(|i| leaf[i]));
+                }
+                ExtentNode::Index(index) => {
+                    let pos = index.partition_point(|i| i.ei_block <= logical);
+                    let idx = match pos.checked_sub(1) {
+                        None => return Ok(None),
+                        Some(i) => index[i],
+                    };
+                    depth -= 1;
+                    child = self.read_node(f, block_size, idx.leaf(), depth)?;
+                    node = &child;
+                }
+            }
+        }
+    }
+    /// map a logical file block to its physical block, `None` for a hole or an unwritten extent
+    pub fn map_block(&mut self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<Option<u64>, Error> {
+        Ok(self.map_extent(f, block_size, logical)?.filter(|(_, _, unwritten)| !unwritten).map(|(block, _, _)| block))
+    }
+    /// map a logical file block like `map_block`, unwritten extents included: the physical
+    /// block, how many blocks of the extent follow from it on and whether they are unwritten
+    pub fn map_extent(&mut self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<Option<(u64, u64, bool)>, Error> {
+        let logical = match u32::try_from(logical) {
+            Ok(logical) => logical,
+            Err(_) => return Ok(None),
+        };
+        let extent = match self.last {
+            Some(e) if e.ee_block <= logical && (logical as u64) < e.end() => Some(e),
+            _ => self.find_extent(f, block_size, logical)?,
+        };
+        match extent {
+            Some(e) if (logical as u64) < e.end() => {
+                self.last = Some(e);
+                let offset = (logical - e.ee_block) as u64;
+                Ok(Some((e.start() + offset, e.len() - offset, e.is_unwritten())))
+            }
+            _ => Ok(None),
+        }
+    }
+    fn next_in_node(&self, f: &dyn BlockDevice, block_size: u64, node: &ExtentNode, depth: u16, logical: u64) -> Result<Option<Extent>, Error> {
+        match node {
+            ExtentNode::Leaf(leaf) => {
+                let pos = leaf.partition_point(|e| e.end() <= logical);
+                Ok(leaf.get(pos).copied())
+            }
+            ExtentNode::Index(index) => {
+                let start = index.partition_point(|i| i.ei_block as u64 <= logical).saturating_sub(1);
+                for idx in &index[start..] {
+                    let child = self.read_node(f, block_size, idx.leaf(), depth - 1)?;
+                    if let Some(extent) = self.next_in_node(f, block_size, &child, depth - 1, logical)? {
+                        return Ok(Some(extent));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+    /// first extent that ends after `logical`, either covering it or lying past it
+    fn next_extent(&self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<Option<Extent>, Error> {
+        self.next_in_node(f, block_size, &self.root, self.header.eh_depth, logical)
+    }
+    /// first logical block at or after `logical` backed by written data
+    pub fn next_data(&self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<Option<u64>, Error> {
+        let mut logical = logical;
+        while let Some(extent) = self.next_extent(f, block_size, logical)? {
+            match extent.is_unwritten() {
+                true => logical = extent.end(),
+                false => return Ok(Some(logical.max(extent.ee_block as u64))),
+            }
+        }
+        Ok(None)
+    }
+    /// first logical block at or after `logical` mapped by an extent, written or not
+    pub fn next_mapped(&self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<Option<u64>, Error> {
+        Ok(self.next_extent(f, block_size, logical)?.map(|extent| logical.max(extent.ee_block as u64)))
+    }
+    /// first logical block at or after `logical` that is a hole or unwritten
+    pub fn next_hole(&self, f: &dyn BlockDevice, block_size: u64, logical: u64) -> Result<u64, Error> {
+        let mut logical = logical;
+        while let Some(extent) = self.next_extent(f, block_size, logical)? {
+            if extent.is_unwritten() || extent.ee_block as u64 > logical {
+                break;
+            }
+            logical = extent.end();
+        }
+        Ok(logical)
+    }
+    /// map `len` logical blocks from `logical` on to the blocks from `start` on, merging with
+    /// a neighbouring extent where both are contiguous; full nodes are split and a full root
+    /// moves into a new block, one level deeper, with new tree blocks allocated near `start`.
+    /// `seed` checksums the blocks written on metadata_csum filesystems. Returns how many tree
+    /// blocks were allocated
+    pub fn insert_extent(&mut self, fs: &mut FileSystem, logical: u32, start: u64, len: u64, seed: Option<u32>) -> Result<u64, Error> {
+        if len == 0 || len > Self::MAX_LEN {
+            return Err(Error::InvalidInput(format!("extent of {} blocks", len)));
+        }
+        let extent = Extent::new(logical, start, len as u16);
+        self.edit_leaf(fs, logical, start, seed, &mut |leaf| Self::insert_leaf(leaf, extent))
+    }
+    /// mark the `len` unwritten blocks from `logical` on, all in one extent, as written: the
+    /// extent is split around them, as ext4_ext_convert_to_initialized does, and they join a
+    /// written extent right before them when the blocks line up. Takes `seed` and returns how
+    /// many tree blocks were allocated as `insert_extent`
+    pub fn mark_written(&mut self, fs: &mut FileSystem, logical: u32, len: u64, seed: Option<u32>) -> Result<u64, Error> {
+        let goal = self.map_extent(&fs.disk, fs.sb().block_size(), logical as u64)?.map_or(0, |(block, _, _)| block);
+        self.edit_leaf(fs, logical, goal, seed, &mut |leaf| Self::convert_leaf(leaf, logical, len))
+    }
+    /* run `edit` on the leaf covering `logical`, then split what it overfilled on the way up;
+     * a root left too full moves into a new block allocated near `goal` */
+    fn edit_leaf(&mut self, fs: &mut FileSystem, logical: u32, goal: u64, seed: Option<u32>, edit: &mut dyn FnMut(&mut Vec<Extent>) -> Result<(), Error>) -> Result<u64, Error> {
+        let mut root = core::mem::replace(&mut self.root, ExtentNode::Leaf(Vec::new()));
+        let result = self.edit_node(fs, &mut root, self.header.eh_depth, logical, seed, edit);
+        self.root = root;
+        self.last = None;
+        let mut allocated = result?;
+        if self.root.len() > self.header.eh_max as usize {
+            if self.header.eh_depth >= Self::MAX_DEPTH {
+                return Err(Error::NoSpace("extent tree too deep".to_string()));
+            }
+            /* grow in depth: the whole root moves into a new block the root now points to */
+            let block = fs.alloc_block(goal)?;
+            allocated += 1;
+            let child = core::mem::replace(&mut self.root, ExtentNode::Index(Vec::new()));
+            Self::write_node(fs, block, &child, self.header.eh_depth, seed)?;
+            self.root = ExtentNode::Index(vec![ExtentIdx::new(child.first_block(), block)]);
+            self.header.eh_depth += 1;
+        }
+        self.header.eh_entries = self.root.len() as u16;
+        Ok(allocated)
+    }
+    /// unmap every block from `logical` on: extents past it are freed and removed, one running
+    /// over it is cut short and index nodes left empty go too, their blocks freed; a tree left
+    /// empty is a leaf in `i_block` again. Returns how many blocks, tree blocks included, were
+    /// freed
+    pub fn truncate(&mut self, fs: &mut FileSystem, logical: u64, seed: Option<u32>) -> Result<u64, Error> {
+        let mut root = core::mem::replace(&mut self.root, ExtentNode::Leaf(Vec::new()));
+        let result = self.truncate_node(fs, &mut root, self.header.eh_depth, logical, seed);
+        self.root = root;
+        self.last = None;
+        if self.root.len() == 0 {
+            self.root = ExtentNode::Leaf(Vec::new());
+            self.header.eh_depth = 0;
+        }
+        self.header.eh_entries = self.root.len() as u16;
+        result
+    }
+    fn truncate_node(&self, fs: &mut FileSystem, node: &mut ExtentNode, depth: u16, logical: u64, seed: Option<u32>) -> Result<u64, Error> {
+        let mut freed = 0;
+        let index = match node {
+            ExtentNode::Leaf(leaf) => {
+                while let Some(last) = leaf.last_mut() {
+                    if last.end() <= logical {
+                        break;
+                    }
+                    let keep = logical.saturating_sub(last.ee_block as u64);
+                    fs.free_blocks(last.start() + keep, last.len() - keep)?;
+                    freed += last.len() - keep;
+                    match keep {
+                        0 => {
+                            leaf.pop();
+                        }
+                        /* the unwritten flag lives in the length */
+                        _ => last.ee_len -= (last.len() - keep) as u16,
+                    }
+                }
+                return Ok(freed);
+            }
+            ExtentNode::Index(index) => index,
+        };
+        let block_size = fs.sb().block_size();
+        /* children are sorted, so walk back until the one holding `logical` */
+        while let Some(idx) = index.last().copied() {
+            let mut child = self.read_node(&fs.disk, block_size, idx.leaf(), depth - 1)?;
+            let child_freed = self.truncate_node(fs, &mut child, depth - 1, logical, seed)?;
+            freed += child_freed;
+            if child.len() == 0 {
+                fs.free_blocks(idx.leaf(), 1)?;
+                freed += 1;
+                index.pop();
+            } else if child_freed > 0 {
+                Self::write_node(fs, idx.leaf(), &child, depth - 1, seed)?;
+            }
+            if idx.ei_block as u64 <= logical {
+                break;
+            }
+        }
+        Ok(freed)
+    }
+    /* run `edit` on the leaf below `node` covering `logical`, which may leave `node` over its
+     * limit for the caller to split; children that overflow are split here */
+    fn edit_node(&self, fs: &mut FileSystem, node: &mut ExtentNode, depth: u16, logical: u32, seed: Option<u32>, edit: &mut dyn FnMut(&mut Vec<Extent>) -> Result<(), Error>) -> Result<u64, Error> {
+        let index = match node {
+            ExtentNode::Leaf(leaf) => return edit(leaf).map(|_| 0),
+            ExtentNode::Index(index) => index,
+        };
+        if index.is_empty() {
+            return Err(Error::InvalidData("empty extent index".to_string()));
+        }
+        let pos = index.partition_point(|i| i.ei_block <= logical).saturating_sub(1);
+        /* an extent before everything the index covers widens its first child */
+        index[pos].ei_block = index[pos].ei_block.min(logical);
+        let child_block = index[pos].leaf();
+        let block_size = fs.sb().block_size();
+        let mut child = self.read_node(&fs.disk, block_size, child_block, depth - 1)?;
+        let mut allocated = self.edit_node(fs, &mut child, depth - 1, logical, seed, edit)?;
+        let max = Self::block_max(block_size) as usize;
+        if child.len() > max {
+            /* appending leaves the full node full and starts the sibling with the new entry,
+             * anything else splits in half */
+            let at = match child.len() == max + 1 && child.last_block() <= logical {
+                true => child.len() - 1,
+                false => child.len() / 2,
+            };
+            let sibling = child.split_off(at);
+            let block = fs.alloc_block(child_block)?;
+            allocated += 1;
+            Self::write_node(fs, block, &sibling, depth - 1, seed)?;
+            index.insert(pos + 1, ExtentIdx::new(sibling.first_block(), block));
+        }
+        Self::write_node(fs, child_block, &child, depth - 1, seed)?;
+        Ok(allocated)
+    }
+    /* put `extent` in its place in a leaf, extending the extent before or after it instead when
+     * the blocks line up on both sides */
+    fn insert_leaf(leaf: &mut Vec<Extent>, extent: Extent) -> Result<(), Error> {
+        let (logical, len) = (extent.ee_block, extent.ee_len as u64);
+        let pos = leaf.partition_point(|e| e.ee_block <= logical);
+        let overlaps = pos.checked_sub(1).is_some_and(|i| leaf[i].end() > logical as u64)
+            || leaf.get(pos).is_some_and(|e| (e.ee_block as u64) < logical as u64 + len);
+        if overlaps {
+            return Err(Error::InvalidInput(format!("blocks from {} are already mapped", logical)));
+        }
+        let mergeable = |e: &Extent| !e.is_unwritten() && e.len() + len <= Self::MAX_LEN;
+        if let Some(prev) = pos.checked_sub(1).map(|i| &mut leaf[i]) {
+            if mergeable(prev) && prev.end() == logical as u64 && prev.start() + prev.len() == extent.start() {
+                prev.ee_len += len as u16;
+                return Ok(());
+            }
+        }
+        if let Some(next) = leaf.get_mut(pos) {
+            if mergeable(next) && logical as u64 + len == next.ee_block as u64 && extent.start() + len == next.start() {
+                *next = Extent::new(logical, extent.start(), next.ee_len + len as u16);
+                return Ok(());
+            }
+        }
+        leaf.insert(pos, extent);
+        Ok(())
+    }
+    /* replace the unwritten extent holding `logical..logical + len` by its pieces, the blocks
+     * written in the middle merged into the extent before them if they continue it */
+    fn convert_leaf(leaf: &mut Vec<Extent>, logical: u32, len: u64) -> Result<(), Error> {
+        let pos = leaf.partition_point(|e| e.ee_block <= logical).checked_sub(1)
+            .filter(|i| leaf[*i].is_unwritten() && leaf[*i].end() >= logical as u64 + len)
+            .ok_or_else(|| Error::InvalidInput(format!("blocks from {} are not unwritten", logical)))?;
+        let extent = leaf[pos];
+        let (head, tail) = ((logical - extent.ee_block) as u64, extent.end() - logical as u64 - len);
+        let mut pieces = Vec::new();
+        if head > 0 {
+            pieces.push(Extent::new_unwritten(extent.ee_block, extent.start(), head as u16));
+        }
+        let written = Extent::new(logical, extent.start() + head, len as u16);
+        pieces.push(written);
+        if tail > 0 {
+            pieces.push(Extent::new_unwritten((logical as u64 + len) as u32, written.start() + len, tail as u16));
+        }
+        let at = pos + (head > 0) as usize;
+        leaf.splice(pos..pos + 1, pieces);
+        if let Some(prev) = at.checked_sub(1).map(|i| leaf[i]) {
+            if !prev.is_unwritten() && prev.end() == logical as u64 && prev.start() + prev.len() == written.start()
+                && prev.len() + len <= Self::MAX_LEN {
+                leaf[at - 1].ee_len += len as u16;
+                leaf.remove(at);
+            }
+        }
+        Ok(())
+    }
+    /// entries of a tree block: what fits between the header and the tail
+    #[inline]
+    fn block_max(block_size: u64) -> u16 {
+        ((block_size as usize - core::mem::size_of::<ExtentHeader>() - core::mem::size_of::<ExtentTail>())
+            / core::mem::size_of::<Extent>()) as u16
+    }
+    fn write_node(fs: &mut FileSystem, block_num: u64, node: &ExtentNode, depth: u16, seed: Option<u32>) -> Result<(), Error> {
+        let block_size = fs.sb().block_size();
+        let max = Self::block_max(block_size);
+        let mut block = vec![0u8; block_size as usize];
+        node.write(&mut block, max, depth);
+        if let Some(seed) = seed {
+            let (header, _) = ExtentNode::from_bytes(&block)
+                .ok_or_else(|| Error::InvalidData(format!("bad extent tree node for block {}", block_num)))?;
+            ExtentTail::set_checksum(&mut block, &header, seed);
+        }
+        fs.write_meta_block(block_num, &block)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use super::{ExtentNode, ExtentTail};
+    use crate::{BlockMap, CoreRead, FileSystem, Inode, Mode, MountOptions, SuperBlock};
+    use crate::ext4::testdata::{unhex, MemDevice, CSUM_EXTENT_LEAF, CSUM_INODE, CSUM_SB};
+
+    #[test]
+    fn tail_checksum() {
+        let sb = SuperBlock::from_buffer(&unhex(SuperBlock::SIZE, CSUM_SB)).unwrap();
+        let inode = unhex(256, CSUM_INODE).as_slice().read_struct::<Inode>().unwrap();
+        let seed = inode.csum_seed(12, &sb);
+        let leaf = unhex(1024, CSUM_EXTENT_LEAF);
+        let (header, node) = ExtentNode::from_bytes(&leaf).unwrap();
+        assert_eq!((header.eh_max, node.len()), (84, 6));
+        assert!(ExtentTail::checksum_valid(&leaf, &header, seed));
+        assert!(!ExtentTail::checksum_valid(&leaf, &header, inode.csum_seed(13, &sb)));
+        let mut block = leaf.clone();
+        block[1020..].fill(0);
+        ExtentTail::set_checksum(&mut block, &header, seed);
+        assert_eq!(block, leaf);
+    }
+
+    fn depth(fs: &mut FileSystem, inode_num: u64) -> u16 {
+        match fs.read_inode(inode_num).unwrap().block_map() {
+            Some(BlockMap::Extents(tree)) => tree.depth(),
+            _ => panic!("inode {} has no extent tree", inode_num),
+        }
+    }
+
+    /* give `path` a block every other one up to `count` of them, so no two extents merge */
+    fn fragment(fs: &mut FileSystem, path: &str, count: u64) {
+        let mut file = fs.open(path).unwrap();
+        for i in file.size().div_ceil(2048)..count {
+            file.seek(i * 2048);
+            file.write(&vec![(i % 255) as u8 + 1; 1024]).unwrap();
+        }
+    }
+
+    /* 4 extents fit the inode, 84 a leaf and 336 the leaves under a full root, which takes a
+     * second level of index blocks past that */
+    #[test]
+    fn split() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        let free = fs.sb().free_block_count();
+        let inode_num = fs.create("/f", Mode::from_bits_retain(0o644)).unwrap().inode_num();
+        for (count, expect) in [(4, 0), (5, 1), (84, 1), (336, 1), (400, 2)] {
+            fragment(&mut fs, "/f", count);
+            assert_eq!(depth(&mut fs, inode_num), expect, "{} extents", count);
+        }
+        /* 400 data blocks, 5 leaves and the index block the full root moved into */
+        assert_eq!(free - fs.sb().free_block_count(), 400 + 5 + 1);
+        drop(fs);
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        let mut file = fs.open("/f").unwrap();
+        for i in 0..400 {
+            assert_eq!(file.read_block(i * 2).unwrap(), vec![(i % 255) as u8 + 1; 1024]);
+            assert_eq!(file.read_block(i * 2 + 1).unwrap(), vec![0; 1024]);
+        }
+    }
+
+    #[test]
+    fn truncate() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        let free = fs.sb().free_block_count();
+        let inode_num = fs.create("/f", Mode::from_bits_retain(0o644)).unwrap().inode_num();
+        fragment(&mut fs, "/f", 400);
+        /* in the hole after the 101st extent: 2 leaves are left under the index block */
+        fs.open("/f").unwrap().set_len(201 * 1024 + 100).unwrap();
+        assert_eq!(free - fs.sb().free_block_count(), 101 + 2 + 1);
+        assert_eq!(depth(&mut fs, inode_num), 2);
+        /* in the middle of a run of blocks, the rest of the last one kept reads as zeros */
+        let mut file = fs.create("/g", Mode::from_bits_retain(0o644)).unwrap();
+        file.write(&vec![0x5a; 10 * 1024]).unwrap();
+        file.set_len(4 * 1024 + 1).unwrap();
+        assert_eq!(file.read_block(4).unwrap(), [vec![0x5a], vec![0; 1023]].concat());
+        file.set_len(5 * 1024).unwrap();
+        assert_eq!(free - fs.sb().free_block_count(), 101 + 2 + 1 + 5);
+        fs.open("/f").unwrap().set_len(0).unwrap();
+        fs.open("/g").unwrap().set_len(0).unwrap();
+        assert_eq!(fs.sb().free_block_count(), free);
+        assert_eq!(depth(&mut fs, inode_num), 0);
+        drop(fs);
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        assert_eq!(fs.open("/f").unwrap().size(), 0);
+    }
+}
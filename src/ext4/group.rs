@@ -1,6 +1,21 @@
-use alloc::boxed::Box;
+use bitflags::bitflags;
 use crate::io::{CoreRead};
-use crate::{Disk, SuperBlock};
+use crate::to_slice;
+use crate::ext4::bitmap;
+use crate::ext4::checksum::{crc16, crc32c};
+use crate::ext4::superblock::FeatureInCompat;
+use crate::{BlockDevice, Error, SuperBlock};
+use alloc::vec;
+use alloc::vec::Vec;
+
+bitflags! {
+    #[derive(Debug, Default, Copy, Clone)]
+    pub struct GroupFlags: u16 {
+        const InodeUninit = 0x0001; /* Inode table/bitmap not in use */
+        const BlockUninit = 0x0002; /* Block bitmap not in use */
+        const InodeZeroed = 0x0004; /* On-disk itable initialized to zero */
+    }
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -11,7 +26,7 @@ pub struct GroupDesc {
     free_blocks_count_lo: u16, /* Free blocks count */
     free_inodes_count_lo: u16, /* Free inodes count */
     used_dirs_count_lo: u16,   /* Directories count */
-    flags: u16,                /* EXT4_BG_flags (INODE_UNINIT, etc) */
+    flags: GroupFlags,         /* EXT4_BG_flags (INODE_UNINIT, etc) */
     exclude_bitmap_lo: u32,    /* Exclude bitmap for snapshots */
     block_bitmap_csum_lo: u16, /* crc32c(s_uuid+grp_num+bbitmap) LE */
     inode_bitmap_csum_lo: u16, /* crc32c(s_uuid+grp_num+ibitmap) LE */
@@ -29,33 +44,166 @@ pub struct GroupDesc {
     inode_bitmap_csum_hi: u16, /* crc32c(s_uuid+grp_num+ibitmap) BE */
     _reserved: u32,
 }
+impl GroupDesc {
+    /// parse `desc_size` bytes of the table; without 64bit only the first 32 bytes exist and
+    /// every `_hi` field reads as 0
+    pub fn from_bytes(raw: &[u8], is_64bit: bool) -> Option<Self> {
+        let mut bytes = [0u8; core::mem::size_of::<Self>()];
+        let len = match is_64bit {
+            true => raw.len().min(bytes.len()),
+            false => raw.len().min(Group::DESC_SIZE_32BIT),
+        };
+        bytes[..len].copy_from_slice(&raw[..len]);
+        bytes.as_slice().read_struct::<Self>().ok()
+    }
+}
+
 #[derive(Debug)]
 pub struct Group<'a> {
     pub desc: GroupDesc,
     pub sb: &'a SuperBlock,
-    num: u64
+    num: u64,
+    /* the descriptor as on disk, `desc_size` bytes, which its checksum covers */
+    raw: Vec<u8>,
 }
 impl<'a> Group<'a> {
     pub const DESC_SIZE: usize = core::mem::size_of::<GroupDesc>();
+    /// descriptor size of filesystems without 64bit
+    pub const DESC_SIZE_32BIT: usize = 32;
     pub fn new(
-        f: &mut Box<dyn Disk>,
+        f: &dyn BlockDevice,
         sb: &'a SuperBlock,
         group_num: u64,
     ) -> Option<Group<'a>> {
+        let mut raw = vec![0u8; sb.desc_size() as usize];
+        f.read_at(Self::desc_offset(sb, group_num), &mut raw).ok()?;
+        Self::from_bytes(sb, group_num, &raw)
+    }
+    /// a group from its `desc_size` descriptor bytes, e.g. a slice of `read_table`
+    pub fn from_bytes(sb: &'a SuperBlock, group_num: u64, raw: &[u8]) -> Option<Group<'a>> {
+        let desc = GroupDesc::from_bytes(raw, sb.is_64bit())?;
+        Some(Self { desc, sb, num: group_num, raw: raw.to_vec() })
+    }
+    /// the whole descriptor table, `desc_size` bytes per group, read a table block at a time
+    pub fn read_table(f: &dyn BlockDevice, sb: &SuperBlock) -> Result<Vec<u8>, Error> {
+        let (block_size, desc_size) = (sb.block_size(), sb.desc_size());
+        let count = sb.get_groups_count() as u64;
+        let per_block = block_size / desc_size;
+        let mut table = Vec::with_capacity((count * desc_size) as usize);
+        let mut block = vec![0u8; block_size as usize];
+        for nr in 0..count.div_ceil(per_block) {
+            f.read_at(Self::desc_block(sb, nr) * block_size, &mut block)?;
+            let len = (count - nr * per_block).min(per_block) * desc_size;
+            table.extend_from_slice(&block[..len as usize]);
+        }
+        Ok(table)
+    }
+    /// byte offset of the descriptor of `group_num`
+    pub fn desc_offset(sb: &SuperBlock, group_num: u64) -> u64 {
         let block_size = sb.block_size();
-        let offset = group_num * Self::DESC_SIZE as u64
-            + if block_size == SuperBlock::SIZE as u64 {
-            SuperBlock::SIZE as u64 * 2
-            } else {
-                block_size
-            };
-        f.seek_to(offset);
-        let desc = f.read_struct::<GroupDesc>().unwrap();
-        Some(Self { desc, sb, num: group_num })
+        let per_block = block_size / sb.desc_size();
+        let block = Self::desc_block(sb, group_num / per_block);
+        block * block_size + group_num % per_block * sb.desc_size()
+    }
+    /// where block `nr` of the descriptor table lives, as descriptor_loc: in the blocks right
+    /// after the one holding the superblock, or with META_BG from `first_meta_bg` on, in the first group of its meta group
+    /// (which also keeps copies in its second and last group)
+    pub fn desc_block(sb: &SuperBlock, nr: u64) -> u64 {
+        let first_meta_bg = sb.first_meta_bg();
+        if !sb.feature_in_compat().contains(FeatureInCompat::MetaBg) || nr < first_meta_bg {
+            /* not first_data_block, which is 0 on a 1k-block bigalloc filesystem with the superblock
+             * still in block 1 */
+            return SuperBlock::OFFSET as u64 / sb.block_size() + nr + 1;
+        }
+        let group = nr * (sb.block_size() / sb.desc_size());
+        let mut has_super = sb.group_has_super(group) as u64;
+        /* group 0 of a 1k-block filesystem starting at block 0 (bigalloc) still has its GDT at block 2 */
+        if sb.block_size() == 1024 && nr == 0 && sb.first_data_block() == 0 {
+            has_super += 1;
+        }
+        group * sb.blocks_per_group() + sb.first_data_block() + has_super
+    }
+    #[inline]
+    pub fn num(&self) -> u64 {
+        self.num
+    }
+    /// `bg_checksum` of the descriptor bytes: the low 16 bits of crc32c with metadata_csum,
+    /// crc16 with GDT_CSUM, both over the group number and the descriptor with the checksum
+    /// left out; `None` when neither feature is on
+    fn checksum(&self) -> Option<u16> {
+        let raw = self.raw.as_slice();
+        let offset = core::mem::offset_of!(GroupDesc, checksum);
+        let group = (self.num as u32).to_le_bytes();
+        match (self.sb.has_metadata_csum(), self.sb.has_group_desc_csum()) {
+            (true, _) => {
+                let mut crc = crc32c(self.sb.csum_seed(), &group);
+                crc = crc32c(crc, &raw[..offset]);
+                crc = crc32c(crc, &[0, 0]);
+                Some(crc32c(crc, &raw[offset + 2..]) as u16)
+            }
+            (false, true) => {
+                let mut crc = crc16(!0, &self.sb.raw_uuid());
+                crc = crc16(crc, &group);
+                crc = crc16(crc, &raw[..offset]);
+                Some(crc16(crc, &raw[offset + 2..]))
+            }
+            (false, false) => None,
+        }
+    }
+    /// check `bg_checksum`, true when the filesystem has no descriptor checksums
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum().is_none_or(|csum| csum == self.desc.checksum)
+    }
+    /// the descriptor as it goes on disk, `desc_size` bytes
+    #[inline]
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+    /// copy the fields back into the on-disk bytes and checksum them again, after any setter
+    pub fn update_checksum(&mut self) {
+        let desc = to_slice!(&self.desc, GroupDesc);
+        let len = self.raw.len().min(desc.len());
+        self.raw[..len].copy_from_slice(&desc[..len]);
+        if let Some(csum) = self.checksum() {
+            self.desc.checksum = csum;
+            let offset = core::mem::offset_of!(GroupDesc, checksum);
+            self.raw[offset..offset + 2].copy_from_slice(&csum.to_le_bytes());
+        }
     }
     pub fn first_block_num(&self) -> u64{
         self.num * self.sb.blocks_per_group() + self.sb.first_data_block()
     }
+    /// blocks in the group, fewer than `blocks_per_group` only in the last one
+    pub fn blocks_count(&self) -> u64 {
+        (self.sb.block_count() - self.first_block_num()).min(self.sb.blocks_per_group())
+    }
+    /// the block bitmap of a BLOCK_UNINIT group, as ext4_init_block_bitmap: the superblock and
+    /// descriptor copies, and the group's own bitmaps and inode table when they live in it
+    pub fn init_block_bitmap(&self) -> Vec<u8> {
+        let block_size = self.sb.block_size();
+        let mut bitmap = vec![0u8; block_size as usize];
+        bitmap::set_range(&mut bitmap, 0, self.sb.group_meta_blocks(self.num));
+        let first = self.first_block_num();
+        let in_group = |block: u64| (first..first + self.blocks_count()).contains(&block);
+        for block in [self.block_bitmap(), self.inode_bitmap()] {
+            if in_group(block) {
+                bitmap::set_bit(&mut bitmap, block - first);
+            }
+        }
+        let table = self.inode_table();
+        (table..table + self.sb.inode_table_blocks())
+            .filter(|block| in_group(*block))
+            .for_each(|block| bitmap::set_bit(&mut bitmap, block - first));
+        bitmap::set_range(&mut bitmap, self.blocks_count(), block_size * 8);
+        bitmap
+    }
+    /// the inode bitmap of an INODE_UNINIT group: nothing used, the padding past the group full
+    pub fn init_inode_bitmap(&self) -> Vec<u8> {
+        let block_size = self.sb.block_size();
+        let mut bitmap = vec![0u8; block_size as usize];
+        bitmap::set_range(&mut bitmap, self.sb.inodes_per_group(), block_size * 8);
+        bitmap
+    }
     #[inline(always)]
     pub fn first_inode_num(&self) -> u64{
         self.num * self.sb.inodes_per_group() + 1
@@ -65,9 +213,185 @@ impl<'a> Group<'a> {
         self.desc.inode_table_lo as u64| (self.desc.inode_table_hi as u64) << 32
     }
 
-    pub fn get_group(f: &mut Box<dyn Disk>, sb: &'a SuperBlock, inode_num: u64) -> Option<Group<'a>>{
+    /// byte offset of `inode_num`, which must belong to this group, in the inode table
+    pub fn inode_offset(&self, inode_num: u64) -> u64 {
+        self.inode_table() * self.sb.block_size() + (inode_num - self.first_inode_num()) * self.sb.inode_size()
+    }
+    #[inline]
+    pub fn free_blocks_count(&self) -> u64 {
+        self.desc.free_blocks_count_lo as u64 | (self.desc.free_blocks_count_hi as u64) << 16
+    }
+    #[inline]
+    pub fn free_inodes_count(&self) -> u64 {
+        self.desc.free_inodes_count_lo as u64 | (self.desc.free_inodes_count_hi as u64) << 16
+    }
+    #[inline]
+    pub fn used_dirs_count(&self) -> u64 {
+        self.desc.used_dirs_count_lo as u64 | (self.desc.used_dirs_count_hi as u64) << 16
+    }
+    /// inodes at the end of the table never used, which need not be read
+    #[inline]
+    pub fn itable_unused(&self) -> u64 {
+        self.desc.itable_unused_lo as u64 | (self.desc.itable_unused_hi as u64) << 16
+    }
+    #[inline]
+    pub fn flags(&self) -> GroupFlags {
+        self.desc.flags
+    }
+    pub fn set_free_blocks_count(&mut self, count: u64) {
+        self.desc.free_blocks_count_lo = count as u16;
+        self.desc.free_blocks_count_hi = (count >> 16) as u16;
+    }
+    pub fn set_free_inodes_count(&mut self, count: u64) {
+        self.desc.free_inodes_count_lo = count as u16;
+        self.desc.free_inodes_count_hi = (count >> 16) as u16;
+    }
+    pub fn set_used_dirs_count(&mut self, count: u64) {
+        self.desc.used_dirs_count_lo = count as u16;
+        self.desc.used_dirs_count_hi = (count >> 16) as u16;
+    }
+    pub fn set_itable_unused(&mut self, count: u64) {
+        self.desc.itable_unused_lo = count as u16;
+        self.desc.itable_unused_hi = (count >> 16) as u16;
+    }
+    #[inline]
+    pub fn set_flags(&mut self, flags: GroupFlags) {
+        self.desc.flags = flags
+    }
+    #[inline]
+    pub fn block_bitmap(&self) -> u64 {
+        self.desc.block_bitmap_lo as u64 | (self.desc.block_bitmap_hi as u64) << 32
+    }
+    #[inline]
+    pub fn inode_bitmap(&self) -> u64 {
+        self.desc.inode_bitmap_lo as u64 | (self.desc.inode_bitmap_hi as u64) << 32
+    }
+    /// crc32c of the first `len` bytes of a bitmap against the stored halves, the high one only
+    /// counts when the descriptor is large enough to hold it
+    fn bitmap_checksum_valid(&self, bitmap: &[u8], len: u64, lo: u16, hi: u16, hi_end: usize) -> bool {
+        if !self.sb.has_metadata_csum() {
+            return true;
+        }
+        let crc = crc32c(self.sb.csum_seed(), &bitmap[..(len as usize).min(bitmap.len())]);
+        match self.sb.desc_size() as usize >= hi_end {
+            true => crc == lo as u32 | (hi as u32) << 16,
+            false => crc as u16 == lo,
+        }
+    }
+    /* the crc32c halves of a bitmap's first `len` bytes */
+    fn bitmap_checksum(&self, bitmap: &[u8], len: u64) -> (u16, u16) {
+        let crc = crc32c(self.sb.csum_seed(), &bitmap[..(len as usize).min(bitmap.len())]);
+        (crc as u16, (crc >> 16) as u16)
+    }
+    /// store the checksum of a block bitmap about to be written, a no-op without metadata_csum
+    pub fn set_block_bitmap_checksum(&mut self, bitmap: &[u8]) {
+        if self.sb.has_metadata_csum() {
+            let (lo, hi) = self.bitmap_checksum(bitmap, self.sb.clusters_per_group() / 8);
+            self.desc.block_bitmap_csum_lo = lo;
+            self.desc.block_bitmap_csum_hi = hi;
+        }
+    }
+    /// store the checksum of an inode bitmap about to be written, a no-op without metadata_csum
+    pub fn set_inode_bitmap_checksum(&mut self, bitmap: &[u8]) {
+        if self.sb.has_metadata_csum() {
+            let (lo, hi) = self.bitmap_checksum(bitmap, self.sb.inodes_per_group() / 8);
+            self.desc.inode_bitmap_csum_lo = lo;
+            self.desc.inode_bitmap_csum_hi = hi;
+        }
+    }
+    /// check the block bitmap read from `block_bitmap()`, one bit per cluster of the group
+    pub fn block_bitmap_checksum_valid(&self, bitmap: &[u8]) -> bool {
+        let hi_end = core::mem::offset_of!(GroupDesc, block_bitmap_csum_hi) + 2;
+        let (lo, hi) = (self.desc.block_bitmap_csum_lo, self.desc.block_bitmap_csum_hi);
+        self.bitmap_checksum_valid(bitmap, self.sb.clusters_per_group() / 8, lo, hi, hi_end)
+    }
+    /// check the inode bitmap read from `inode_bitmap()`, one bit per inode of the group
+    pub fn inode_bitmap_checksum_valid(&self, bitmap: &[u8]) -> bool {
+        let hi_end = core::mem::offset_of!(GroupDesc, inode_bitmap_csum_hi) + 2;
+        let (lo, hi) = (self.desc.inode_bitmap_csum_lo, self.desc.inode_bitmap_csum_hi);
+        self.bitmap_checksum_valid(bitmap, self.sb.inodes_per_group() / 8, lo, hi, hi_end)
+    }
+
+    pub fn get_group(f: &dyn BlockDevice, sb: &'a SuperBlock, inode_num: u64) -> Option<Group<'a>>{
         let group_num = (inode_num - 1) / sb.inodes_per_group();
         Self::new(f,sb ,group_num)
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Group;
+    use crate::SuperBlock;
+    use crate::ext4::testdata::{unhex, CSUM_BLOCK_BITMAP, CSUM_GROUP, CSUM_INODE_BITMAP, CSUM_SB, GDT_CSUM_GROUP, GDT_CSUM_SB};
+
+    #[test]
+    fn crc32c_checksum() {
+        let sb = SuperBlock::from_buffer(&unhex(SuperBlock::SIZE, CSUM_SB)).unwrap();
+        let raw = unhex(64, CSUM_GROUP);
+        let mut group = Group::from_bytes(&sb, 0, &raw).unwrap();
+        assert!(group.checksum_valid());
+        assert_eq!(group.desc.checksum, 0x8c9b);
+        /* the group number is part of the checksum */
+        assert!(!Group::from_bytes(&sb, 1, &raw).unwrap().checksum_valid());
+        group.desc.checksum = 0;
+        group.update_checksum();
+        assert_eq!(group.raw(), raw.as_slice());
+    }
+
+    #[test]
+    fn crc16_checksum() {
+        let sb = SuperBlock::from_buffer(&unhex(SuperBlock::SIZE, GDT_CSUM_SB)).unwrap();
+        let raw = unhex(32, GDT_CSUM_GROUP);
+        let mut group = Group::from_bytes(&sb, 0, &raw).unwrap();
+        assert!(group.checksum_valid());
+        assert_eq!(group.desc.checksum, 0x0197);
+        let mut bad = raw.clone();
+        bad[12] ^= 1;
+        assert!(!Group::from_bytes(&sb, 0, &bad).unwrap().checksum_valid());
+        group.desc.checksum = 0;
+        group.update_checksum();
+        assert_eq!(group.raw(), raw.as_slice());
+    }
+
+    #[test]
+    fn desc_block() {
+        let mut raw = unhex(SuperBlock::SIZE, CSUM_SB);
+        let sb = SuperBlock::from_buffer(&raw).unwrap();
+        assert_eq!((sb.block_size(), sb.first_data_block()), (1024, 1));
+        assert_eq!(Group::desc_block(&sb, 0), 2);
+        /* s_first_data_block is 0 with 1k-block bigalloc, the table stays behind the superblock */
+        raw[0x14..0x18].copy_from_slice(&0u32.to_le_bytes());
+        let sb = SuperBlock::from_buffer(&raw).unwrap();
+        assert_eq!(Group::desc_block(&sb, 0), 2);
+        assert_eq!(Group::desc_offset(&sb, 17), 3 * 1024 + 64);
+        /* 4k blocks put the superblock and the table start in block 0 */
+        raw[0x18..0x1c].copy_from_slice(&2u32.to_le_bytes());
+        let sb = SuperBlock::from_buffer(&raw).unwrap();
+        assert_eq!(Group::desc_block(&sb, 0), 1);
+    }
+
+    #[test]
+    fn bitmap_checksums() {
+        let sb = SuperBlock::from_buffer(&unhex(SuperBlock::SIZE, CSUM_SB)).unwrap();
+        let mut group = Group::from_bytes(&sb, 0, &unhex(64, CSUM_GROUP)).unwrap();
+        let (block_bitmap, inode_bitmap) = (unhex(1024, CSUM_BLOCK_BITMAP), unhex(1024, CSUM_INODE_BITMAP));
+        assert!(group.block_bitmap_checksum_valid(&block_bitmap));
+        assert!(group.inode_bitmap_checksum_valid(&inode_bitmap));
+        /* the inode bitmap checksum stops at the last inode of the group */
+        let mut bitmap = inode_bitmap.clone();
+        bitmap[8] = 0;
+        assert!(group.inode_bitmap_checksum_valid(&bitmap));
+        bitmap[7] = 1;
+        assert!(!group.inode_bitmap_checksum_valid(&bitmap));
+        let mut bitmap = block_bitmap.clone();
+        bitmap[50] |= 1;
+        assert!(!group.block_bitmap_checksum_valid(&bitmap));
+        group.set_block_bitmap_checksum(&bitmap);
+        assert!(group.block_bitmap_checksum_valid(&bitmap));
+        group.set_block_bitmap_checksum(&block_bitmap);
+        group.set_inode_bitmap_checksum(&inode_bitmap);
+        group.update_checksum();
+        assert_eq!(group.raw(), unhex(64, CSUM_GROUP).as_slice());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::ext4::superblock::HashAlgorithm;
+
+/* default seed when the superblock has none */
+const DEFAULT_SEED: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+/* the end-of-directory hash value that readdir cookies reserve */
+const HTREE_EOF_32BIT: u32 = 0x7fffffff;
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32]) {
+    const DELTA: u32 = 0x9E3779B9;
+    let mut sum = 0u32;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let round = |fun: &dyn Fn(u32, u32, u32) -> u32, a: u32, b: u32, c: u32, d: u32, x: u32, s: u32| {
+        a.wrapping_add(fun(b, c, d)).wrapping_add(x).rotate_left(s)
+    };
+    let (mut a, mut b, mut c, mut d) = (buf[0], buf[1], buf[2], buf[3]);
+    /* Round 1 */
+    a = round(&f, a, b, c, d, input[0], 3);
+    d = round(&f, d, a, b, c, input[1], 7);
+    c = round(&f, c, d, a, b, input[2], 11);
+    b = round(&f, b, c, d, a, input[3], 19);
+    a = round(&f, a, b, c, d, input[4], 3);
+    d = round(&f, d, a, b, c, input[5], 7);
+    c = round(&f, c, d, a, b, input[6], 11);
+    b = round(&f, b, c, d, a, input[7], 19);
+    /* Round 2 */
+    a = round(&g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    d = round(&g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    c = round(&g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    b = round(&g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    a = round(&g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    d = round(&g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    c = round(&g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    b = round(&g, b, c, d, a, input[6].wrapping_add(K2), 13);
+    /* Round 3 */
+    a = round(&h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    d = round(&h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    c = round(&h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    b = round(&h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    a = round(&h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    d = round(&h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    c = round(&h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    b = round(&h, b, c, d, a, input[4].wrapping_add(K3), 15);
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+/// the original dx_hack_hash, `signed` picks how bytes above 0x7f are widened
+fn legacy_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3fe2du32, 0x37abe8f9u32);
+    for &c in name {
+        let c = match signed {
+            true => c as i8 as i32 as u32,
+            false => c as u32,
+        };
+        let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7152373));
+        if hash & 0x80000000 != 0 {
+            hash = hash.wrapping_sub(0x7fffffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// pack up to `num * 4` bytes of `msg` into `buf` the way str2hashbuf does
+fn str2hashbuf(msg: &[u8], buf: &mut [u32], num: usize, signed: bool) {
+    let len = msg.len() as u32;
+    let mut pad = len | (len << 8);
+    pad |= pad << 16;
+    let mut val = pad;
+    let mut out = 0usize;
+    for (i, &c) in msg.iter().take(num * 4).enumerate() {
+        let c = match signed {
+            true => c as i8 as i32 as u32,
+            false => c as u32,
+        };
+        val = c.wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[out] = val;
+            out += 1;
+            val = pad;
+        }
+    }
+    if out < num {
+        buf[out] = val;
+        out += 1;
+    }
+    buf[out..num].fill(pad);
+}
+
+/// ext4fs_dirhash: returns (major, minor) hash of a file name
+pub fn dir_hash(version: HashAlgorithm, name: &[u8], seed: &[u32; 4]) -> (u32, u32) {
+    let mut buf = match seed.iter().any(|s| *s != 0) {
+        true => *seed,
+        false => DEFAULT_SEED,
+    };
+    let mut input = [0u32; 8];
+    let (hash, minor) = match version {
+        HashAlgorithm::Legacy => (legacy_hash(name, true), 0),
+        HashAlgorithm::LegacyUnsigned => (legacy_hash(name, false), 0),
+        HashAlgorithm::HalfMD4 | HashAlgorithm::HalfMD4Unsigned => {
+            let signed = matches!(version, HashAlgorithm::HalfMD4);
+            for offset in (0..name.len()).step_by(32) {
+                str2hashbuf(&name[offset..], &mut input, 8, signed);
+                half_md4_transform(&mut buf, &input);
+            }
+            (buf[1], buf[2])
+        }
+        HashAlgorithm::Tea | HashAlgorithm::TeaUnsigned => {
+            let signed = matches!(version, HashAlgorithm::Tea);
+            for offset in (0..name.len()).step_by(16) {
+                str2hashbuf(&name[offset..], &mut input, 4, signed);
+                tea_transform(&mut buf, &input);
+            }
+            (buf[0], buf[1])
+        }
+    };
+    let hash = match hash & !1 {
+        h if h == HTREE_EOF_32BIT << 1 => (HTREE_EOF_32BIT - 1) << 1,
+        h => h,
+    };
+    (hash, minor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* the seed debugfs' dx_hash reads from the uuid 00112233-4455-6677-8899-aabbccddeeff */
+    const SEED: [u32; 4] = [0x33221100, 0x77665544, 0xbbaa9988, 0xffeeddcc];
+    /* bytes above 0x7f, and long enough to take more than one block of either transform */
+    const LONG: &[u8] = b"caf\xe9_na\xefve_name_longer_than_thirty_two_bytes";
+
+    /* expected values from e2fsprogs' debugfs dx_hash */
+    #[test]
+    fn legacy() {
+        assert_eq!(dir_hash(HashAlgorithm::Legacy, b"hello", &[0; 4]), (0x32252546, 0));
+        assert_eq!(dir_hash(HashAlgorithm::Legacy, b"lost+found", &SEED), (0x5e2aba24, 0));
+        assert_eq!(dir_hash(HashAlgorithm::Legacy, LONG, &[0; 4]), (0x11de6844, 0));
+        assert_eq!(dir_hash(HashAlgorithm::LegacyUnsigned, LONG, &[0; 4]), (0xdbda037c, 0));
+    }
+
+    #[test]
+    fn half_md4() {
+        assert_eq!(dir_hash(HashAlgorithm::HalfMD4, b"hello", &[0; 4]), (0x1746da32, 0x420013b5));
+        assert_eq!(dir_hash(HashAlgorithm::HalfMD4, b"lost+found", &[0; 4]), (0x591de422, 0x6ffc56e0));
+        assert_eq!(dir_hash(HashAlgorithm::HalfMD4, b"lost+found", &DEFAULT_SEED), (0x591de422, 0x6ffc56e0));
+        assert_eq!(dir_hash(HashAlgorithm::HalfMD4, b"lost+found", &SEED), (0x1efb822c, 0x2a3bb222));
+        assert_eq!(dir_hash(HashAlgorithm::HalfMD4, LONG, &[0; 4]), (0xf6733ac0, 0x57046d77));
+        assert_eq!(dir_hash(HashAlgorithm::HalfMD4Unsigned, LONG, &[0; 4]), (0x050cbf0e, 0xc0a41c6c));
+        assert_eq!(dir_hash(HashAlgorithm::HalfMD4, LONG, &SEED), (0x7a8e3820, 0x2c642c0c));
+    }
+
+    #[test]
+    fn tea() {
+        assert_eq!(dir_hash(HashAlgorithm::Tea, b"hello", &[0; 4]), (0x6f5bb1a8, 0x231917c2));
+        assert_eq!(dir_hash(HashAlgorithm::Tea, b"lost+found", &[0; 4]), (0x2dbf9e80, 0xbfebee4f));
+        assert_eq!(dir_hash(HashAlgorithm::Tea, b"lost+found", &SEED), (0xd06209f0, 0x605a7d48));
+        assert_eq!(dir_hash(HashAlgorithm::Tea, LONG, &[0; 4]), (0xe4e17c4e, 0x7279790f));
+        assert_eq!(dir_hash(HashAlgorithm::TeaUnsigned, LONG, &[0; 4]), (0x1f7363ae, 0xdef03ff5));
+        assert_eq!(dir_hash(HashAlgorithm::Tea, LONG, &SEED), (0x9561648e, 0xa7baacfc));
+    }
+}
@@ -0,0 +1,262 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::{to_slice, CoreRead};
+use crate::ext4::superblock::HashAlgorithm;
+use crate::ext4::checksum::crc32c;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DxRootInfo {
+    reserved_zero: u32,
+    hash_version: u8,    /* HashAlgorithm of this directory */
+    info_length: u8,     /* 8 */
+    indirect_levels: u8, /* depth of the tree below the root */
+    unused_flags: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct DxCountLimit {
+    limit: u16, /* max number of entries that fit in the node */
+    count: u16, /* number of entries in use, including the first */
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DxEntry {
+    pub(crate) hash: u32,  /* lowest hash of the block; overlaid by count/limit in the first entry */
+    pub(crate) block: u32, /* logical block of the directory */
+}
+
+/// follows the `limit` entry slots of a root or node on metadata_csum filesystems
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct DxTail {
+    dt_reserved: u32,
+    dt_checksum: u32, /* crc32c(uuid+inum+dirblock) */
+}
+
+impl DxRootInfo {
+    /* "." takes 12 bytes and ".." 12 more before the root info */
+    const OFFSET: usize = 24;
+    /// parse the dx_root of block 0, returns its info and index entries
+    pub fn from_bytes(block: &[u8]) -> Option<(Self, Vec<DxEntry>)> {
+        let mut buf = &block[Self::OFFSET..];
+        let info = buf.read_struct::<Self>().ok()?;
+        if info.reserved_zero != 0 || info.info_length != 8 {
+            return None;
+        }
+        let entries = dx_entries(&block[Self::OFFSET + info.info_length as usize..])?;
+        Some((info, entries))
+    }
+    /* count/limit and the entries follow the info */
+    const ENTRIES_OFFSET: usize = Self::OFFSET + core::mem::size_of::<Self>();
+    /// info for a new root hashing names with `hash_version`, the leaves right below it
+    pub fn new(hash_version: u8) -> Self {
+        Self { reserved_zero: 0, hash_version, info_length: 8, indirect_levels: 0, unused_flags: 0 }
+    }
+    #[inline]
+    pub fn indirect_levels(&self) -> u8 {
+        self.indirect_levels
+    }
+    #[inline]
+    pub fn set_indirect_levels(&mut self, levels: u8) {
+        self.indirect_levels = levels
+    }
+    /// index entries the root of a `block_size` directory holds, fewer with a dx_tail
+    pub fn limit(block_size: usize, csum: bool) -> usize {
+        dx_limit(block_size, Self::ENTRIES_OFFSET, csum)
+    }
+    /// write the info and `entries` into the root `block`, behind its `.` and `..`
+    pub fn write(&self, block: &mut [u8], entries: &[DxEntry], csum: bool) {
+        block[Self::OFFSET..Self::ENTRIES_OFFSET].copy_from_slice(to_slice!(self, DxRootInfo));
+        dx_write_entries(block, Self::ENTRIES_OFFSET, Self::limit(block.len(), csum), entries);
+    }
+    pub fn hash_version(&self) -> Option<HashAlgorithm> {
+        HashAlgorithm::try_from(self.hash_version).ok()
+    }
+}
+
+/// index entries a dx_node of a `block_size` directory holds, fewer with a dx_tail
+pub fn dx_node_limit(block_size: usize, csum: bool) -> usize {
+    dx_limit(block_size, 8, csum)
+}
+
+/// a dx_node holding `entries`: an empty directory record spanning the block, then the index
+pub fn dx_node_block(block_size: usize, entries: &[DxEntry], csum: bool) -> Vec<u8> {
+    let mut block = vec![0u8; block_size];
+    block[4..6].copy_from_slice(&(block_size as u16).to_le_bytes());
+    dx_write_entries(&mut block, 8, dx_node_limit(block_size, csum), entries);
+    block
+}
+
+fn dx_limit(block_size: usize, offset: usize, csum: bool) -> usize {
+    let tail = match csum {
+        true => core::mem::size_of::<DxTail>(),
+        false => 0,
+    };
+    (block_size - offset - tail) / core::mem::size_of::<DxEntry>()
+}
+
+/* lay `entries` out at `offset`, count and limit over the hash of the first, and clear the
+ * slots after them up to the tail */
+fn dx_write_entries(block: &mut [u8], offset: usize, limit: usize, entries: &[DxEntry]) {
+    let size = core::mem::size_of::<DxEntry>();
+    block[offset..offset + limit * size].fill(0);
+    for (i, entry) in entries.iter().enumerate() {
+        block[offset + i * size..offset + (i + 1) * size].copy_from_slice(to_slice!(entry, DxEntry));
+    }
+    let count_limit = DxCountLimit { limit: limit as u16, count: entries.len() as u16 };
+    block[offset..offset + 4].copy_from_slice(to_slice!(&count_limit, DxCountLimit));
+}
+
+/// entries of a dx_node, which hides behind an empty directory record spanning the block
+pub fn dx_node_entries(block: &[u8]) -> Option<Vec<DxEntry>> {
+    let rec_len = u16::from_le_bytes([block[4], block[5]]) as usize;
+    let inode = u32::from_le_bytes(block[..4].try_into().unwrap());
+    match inode == 0 && rec_len == block.len() {
+        true => dx_entries(&block[8..]),
+        false => None,
+    }
+}
+
+/// check the dx_tail of a root or node: crc32c, seeded by the directory inode, of the block up
+/// to the last used entry, then of the tail with its checksum zeroed
+pub fn dx_checksum_valid(block: &[u8], seed: u32) -> bool {
+    match dx_tail(block) {
+        Some((size, _, tail)) => dx_checksum(block, size, &tail, seed) == tail.dt_checksum,
+        None => false,
+    }
+}
+
+/// recompute the dx_tail checksum of a root or node after it changed
+pub fn dx_set_checksum(block: &mut [u8], seed: u32) {
+    if let Some((size, tail_offset, tail)) = dx_tail(block) {
+        let crc = dx_checksum(block, size, &tail, seed);
+        let offset = tail_offset + core::mem::offset_of!(DxTail, dt_checksum);
+        block[offset..offset + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+/* the bytes the dx_tail of a root or node covers, where the tail is and the tail */
+fn dx_tail(block: &[u8]) -> Option<(usize, usize, DxTail)> {
+    let rec_len = |offset: usize| u16::from_le_bytes([block[offset + 4], block[offset + 5]]) as usize;
+    /* where count/limit sits: after the fake empty entry of a node, after `..` and the info of a root */
+    let count_offset = match rec_len(0) {
+        len if len == block.len() => 8,
+        12 if rec_len(12) == block.len() - 12 => DxRootInfo::ENTRIES_OFFSET,
+        _ => return None,
+    };
+    let mut buf = &block[count_offset..];
+    let count_limit = buf.read_struct::<DxCountLimit>().ok()?;
+    let tail_offset = count_offset + count_limit.limit as usize * core::mem::size_of::<DxEntry>();
+    let size = count_offset + count_limit.count as usize * core::mem::size_of::<DxEntry>();
+    if tail_offset + core::mem::size_of::<DxTail>() > block.len() || size > tail_offset {
+        return None;
+    }
+    let mut buf = &block[tail_offset..];
+    let tail = buf.read_struct::<DxTail>().ok()?;
+    Some((size, tail_offset, tail))
+}
+
+fn dx_checksum(block: &[u8], size: usize, tail: &DxTail, seed: u32) -> u32 {
+    let crc = crc32c(seed, &block[..size]);
+    let crc = crc32c(crc, &tail.dt_reserved.to_le_bytes());
+    crc32c(crc, &[0; 4])
+}
+
+fn dx_entries(bytes: &[u8]) -> Option<Vec<DxEntry>> {
+    let mut buf = bytes;
+    let count_limit = buf.read_struct::<DxCountLimit>().ok()?;
+    let count = count_limit.count as usize;
+    if count == 0 || count > count_limit.limit as usize || count * 8 > bytes.len() {
+        return None;
+    }
+    let mut buf = bytes;
+    let mut entries = (0..count).map(|_| buf.read_struct::<DxEntry>().unwrap()).collect::<Vec<DxEntry>>();
+    entries[0].hash = 0;
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use super::DxRootInfo;
+    use crate::{FileSystem, Mode, MountOptions};
+    use crate::ext4::testdata::MemDevice;
+
+    /* long enough that a 1 KiB block holds 4 */
+    fn name(i: usize) -> String {
+        format!("{:0>200}", i)
+    }
+
+    /* indirect levels and index entries of the root of /d, none while it is not indexed */
+    fn root(fs: &mut FileSystem) -> Option<(u8, usize)> {
+        let block = fs.open("/d").unwrap().read_block(0).unwrap();
+        DxRootInfo::from_bytes(&block).map(|(info, entries)| (info.indirect_levels(), entries.len()))
+    }
+
+    /* names are added until the first block is indexed, and its leaves split until the root
+     * is full and a level of index nodes goes in below it */
+    #[test]
+    fn split() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        fs.mkdir("/d", Mode::from_bits_retain(0o755)).unwrap();
+        fs.create("/d/f", Mode::from_bits_retain(0o644)).unwrap();
+        let mut count = 0;
+        let link = |fs: &mut FileSystem, count: &mut usize| {
+            fs.link("/d/f", &format!("/d/{}", name(*count))).unwrap();
+            *count += 1;
+        };
+        while root(&mut fs).is_none() {
+            link(&mut fs, &mut count);
+        }
+        /* 4 fit beside ".", ".." and "f", the fifth takes a second block */
+        assert_eq!(count, 5);
+        assert_eq!(root(&mut fs), Some((0, 2)));
+        while root(&mut fs).unwrap().0 == 0 {
+            link(&mut fs, &mut count);
+        }
+        /* the full root moved its entries to a node below it, which has room for more */
+        assert_eq!(root(&mut fs), Some((1, 1)));
+        let split = count;
+        while count < split + 200 {
+            link(&mut fs, &mut count);
+        }
+        /* and that node split in two */
+        assert_eq!(root(&mut fs), Some((1, 2)));
+        drop(fs);
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        let mut names = fs.open("/d").unwrap().read_dir().unwrap().iter().map(|e| String::from(e.name())).collect::<Vec<_>>();
+        names.sort();
+        let mut expect = (0..count).map(name).chain([".", "..", "f"].map(String::from)).collect::<Vec<_>>();
+        expect.sort();
+        assert_eq!(names, expect);
+        for i in 0..count {
+            assert!(fs.open(&format!("/d/{}", name(i))).is_ok(), "{}", i);
+        }
+    }
+
+    /* `.` and `..` stay in the root block, where no hash leads */
+    #[test]
+    fn dots() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        let d = fs.mkdir("/d", Mode::from_bits_retain(0o755)).unwrap().inode_num();
+        fs.create("/d/f", Mode::from_bits_retain(0o644)).unwrap();
+        for i in 0..5 {
+            fs.link("/d/f", &format!("/d/{}", name(i))).unwrap();
+        }
+        assert!(root(&mut fs).is_some());
+        let mut dir = fs.open("/d").unwrap();
+        assert_eq!(dir.lookup(".").unwrap().map(|e| e.inode_num()), Some(d));
+        assert_eq!(dir.lookup("..").unwrap().map(|e| e.inode_num()), Some(2));
+        assert_eq!(fs.open("/d/..").unwrap().inode_num(), 2);
+        assert_eq!(fs.open("/d/.").unwrap().inode_num(), d);
+        assert!(fs.open(&format!("/d/./{}", name(4))).is_ok());
+    }
+}
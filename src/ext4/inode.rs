@@ -1,8 +1,14 @@
-use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 use crate::ext4::stat::IFlags;
+use crate::ext4::xattr::XattrEntry;
+use crate::ext4::checksum::crc32c;
+use crate::ext4::superblock::OS;
 
 use crate::io::{CoreRead};
-use crate::{Disk, ExtentTree, Group, Mode, SuperBlock};
+use crate::to_slice;
+use crate::ext4::block_map::IndirectMap;
+use crate::{BlockDevice, BlockMap, ExtentTree, Group, Mode, SuperBlock};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -81,36 +87,294 @@ impl Default for Inode{
 
 impl Inode {
     pub const SIZE: usize = core::mem::size_of::<Self>();
-    pub fn new(f: &mut Box<dyn Disk>, inode_num: u64, sb: &SuperBlock) -> Option<Self> {
-        let group = Group::get_group(f, sb, inode_num)?;
-        let block_size = sb.block_size();
-        let inode_size = sb.inode_size();
-        let offset =
-            block_size * group.inode_table() + (inode_num - group.first_inode_num()) * inode_size;
-        f.seek_to(offset);
-        let inode = f.read_struct::<Self>().unwrap();
-        Some(inode)
+    /// size of the fields every inode has, `extra_isize` counts from here
+    pub const GOOD_OLD_SIZE: u64 = 128;
+    /// bytes of file data stored in `i_block` when the inode has inline data
+    pub const INLINE_DATA_SIZE: usize = 60;
+    pub fn new(f: &dyn BlockDevice, inode_num: u64, sb: &SuperBlock) -> Option<Self> {
+        let raw = Self::read_raw(f, inode_num, sb)?;
+        raw.as_slice().read_struct::<Self>().ok()
+    }
+    /// the whole on-disk inode, including the in-inode extended attribute area
+    pub fn read_raw(f: &dyn BlockDevice, inode_num: u64, sb: &SuperBlock) -> Option<Vec<u8>> {
+        let offset = Self::offset(f, inode_num, sb)?;
+        let mut raw = vec![0u8; sb.inode_size() as usize];
+        f.read_at(offset, &mut raw).ok()?;
+        Some(raw)
+    }
+    /// byte offset of the inode in its group's inode table
+    pub fn offset(f: &dyn BlockDevice, inode_num: u64, sb: &SuperBlock) -> Option<u64> {
+        Some(Group::get_group(f, sb, inode_num)?.inode_offset(inode_num))
+    }
+    /// seed of the inode's own checksum and of the extent and directory blocks it owns
+    pub fn csum_seed(&self, inode_num: u64, sb: &SuperBlock) -> u32 {
+        let crc = crc32c(sb.csum_seed(), &(inode_num as u32).to_le_bytes());
+        crc32c(crc, &self.generation.to_le_bytes())
+    }
+    /* the crc32c of `raw`, the whole on-disk inode with the checksum fields left out, and whether
+     * `checksum_hi` is part of the inode */
+    fn checksum(&self, raw: &[u8], inode_num: u64, sb: &SuperBlock) -> (u32, bool) {
+        let good_old = Self::GOOD_OLD_SIZE as usize;
+        let lo = core::mem::offset_of!(Inode, checksum_lo);
+        let hi = core::mem::offset_of!(Inode, checksum_hi);
+        let has_hi = raw.len() > good_old && good_old + self.extra_isize() as usize >= hi + 2;
+        let mut crc = crc32c(self.csum_seed(inode_num, sb), &raw[..lo]);
+        crc = crc32c(crc, &[0, 0]);
+        crc = crc32c(crc, &raw[lo + 2..good_old]);
+        if raw.len() > good_old {
+            crc = crc32c(crc, &raw[good_old..hi]);
+            crc = match has_hi {
+                true => crc32c(crc32c(crc, &[0, 0]), &raw[hi + 2..]),
+                false => crc32c(crc, &raw[hi..]),
+            };
+        }
+        (crc, has_hi)
+    }
+    /// check the crc32c of `raw`, the whole on-disk inode, seeded with the inode number and
+    /// generation; only the low 16 bits are stored when `i_extra_isize` leaves out `checksum_hi`
+    pub fn checksum_valid(&self, raw: &[u8], inode_num: u64, sb: &SuperBlock) -> bool {
+        if !sb.has_metadata_csum() || !matches!(sb.os(), OS::Linux) || raw.iter().all(|b| *b == 0) {
+            return true;
+        }
+        match self.checksum(raw, inode_num, sb) {
+            (crc, true) => crc == self.checksum_lo as u32 | ((self.checksum_hi as u32) << 16),
+            (crc, false) => crc as u16 == self.checksum_lo,
+        }
+    }
+    /// lay the inode over `raw`, the on-disk inode as from `read_raw`, and checksum it; of the
+    /// fields past the first 128 bytes only the `i_extra_isize` ones are copied, the rest of the
+    /// inode keeps its in-inode extended attributes
+    pub fn write_raw(&mut self, raw: &mut [u8], inode_num: u64, sb: &SuperBlock) {
+        let fields = to_slice!(self, Inode);
+        let len = match raw.len() as u64 > Self::GOOD_OLD_SIZE {
+            true => (Self::GOOD_OLD_SIZE + self.extra_isize()) as usize,
+            false => Self::GOOD_OLD_SIZE as usize,
+        };
+        let len = len.min(fields.len()).min(raw.len());
+        raw[..len].copy_from_slice(&fields[..len]);
+        if !sb.has_metadata_csum() {
+            return;
+        }
+        let (crc, has_hi) = self.checksum(raw, inode_num, sb);
+        let lo = core::mem::offset_of!(Inode, checksum_lo);
+        let hi = core::mem::offset_of!(Inode, checksum_hi);
+        self.checksum_lo = crc as u16;
+        raw[lo..lo + 2].copy_from_slice(&self.checksum_lo.to_le_bytes());
+        if has_hi {
+            self.checksum_hi = (crc >> 16) as u16;
+            raw[hi..hi + 2].copy_from_slice(&self.checksum_hi.to_le_bytes());
+        }
     }
     #[inline]
     pub fn mode(&self) -> Mode {
         self.mode
     }
     #[inline]
+    pub fn uid(&self) -> u32 {
+        self.uid as u32 | ((self.uid_high as u32) << 16)
+    }
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.gid as u32 | ((self.gid_high as u32) << 16)
+    }
+    #[inline]
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode
+    }
+    pub fn set_uid(&mut self, uid: u32) {
+        self.uid = uid as u16;
+        self.uid_high = (uid >> 16) as u16;
+    }
+    pub fn set_gid(&mut self, gid: u32) {
+        self.gid = gid as u16;
+        self.gid_high = (gid >> 16) as u16;
+    }
+    #[inline]
+    pub fn links_count(&self) -> u16 {
+        self.links_count
+    }
+    #[inline]
+    pub fn set_links_count(&mut self, count: u16) {
+        self.links_count = count
+    }
+    #[inline]
     pub fn flags(&self) -> IFlags {
         self.flags
     }
     #[inline]
+    pub fn set_flags(&mut self, flags: IFlags) {
+        self.flags = flags
+    }
+    #[inline]
     pub fn blocks_count(&self) -> u64 {
         self.blocks_lo as u64 | ((self.blocks_high as u64) << 32)
     }
+    /// `i_blocks`, in 512-byte sectors as long as HUGE_FILE is not set
+    pub fn set_blocks_count(&mut self, count: u64) {
+        self.blocks_lo = count as u32;
+        self.blocks_high = (count >> 32) as u16;
+    }
+    #[inline]
+    pub fn file_acl(&self) -> u64 {
+        self.file_acl_lo as u64 | ((self.file_acl_high as u64) << 32)
+    }
+    /// target of a symlink kept in `i_block`, `None` when it lives in data blocks or inline data
+    pub fn fast_symlink(&self, block_size: u64) -> Option<&[u8]> {
+        /* an external xattr block is the only thing a fast symlink may have allocated */
+        let ea_blocks = match self.file_acl() {
+            0 => 0,
+            _ => block_size / 512,
+        };
+        let fast = self.mode.is_symlink()
+            && !self.has_inline_data()
+            && self.size() < Self::INLINE_DATA_SIZE as u64
+            && self.blocks_count() == ea_blocks;
+        match fast {
+            true => Some(&self.block[..self.size() as usize]),
+            false => None,
+        }
+    }
     #[inline]
     pub fn blocks(&self) -> Option<ExtentTree> {
         ExtentTree::new(&self.block)
     }
+    pub fn block_map(&self) -> Option<BlockMap> {
+        match self.flags.contains(IFlags::Extents) {
+            true => self.blocks().map(BlockMap::Extents),
+            false => Some(BlockMap::Indirect(IndirectMap::new(&self.block))),
+        }
+    }
+    /// replace `i_block`, e.g. with the root of a changed extent tree
+    #[inline]
+    pub fn set_block(&mut self, block: [u8; 60]) {
+        self.block = block
+    }
+    #[inline]
+    pub fn has_inline_data(&self) -> bool {
+        self.flags.contains(IFlags::InlineData)
+    }
+    #[inline]
+    pub fn extra_isize(&self) -> u64 {
+        self.extra_isize as u64
+    }
+    #[inline]
+    pub fn set_extra_isize(&mut self, extra_isize: u16) {
+        self.extra_isize = extra_isize
+    }
+    /// `i_block` followed by the `system.data` xattr continuation, `raw` as from `read_raw`
+    pub fn inline_data(&self, raw: &[u8]) -> Vec<u8> {
+        let mut data = self.block.to_vec();
+        let continuation = XattrEntry::ibody_entries(raw, self.extra_isize())
+            .into_iter()
+            .find(|e| e.is_inline_data());
+        if let Some(xattr) = continuation {
+            data.extend_from_slice(xattr.value());
+        }
+        data
+    }
     #[inline]
     pub fn size(&self) -> u64{
         (self.size_lo as u64) | ((self.size_high as u64)<< 32)
     }
+    pub fn set_size(&mut self, size: u64) {
+        self.size_lo = size as u32;
+        self.size_high = (size >> 32) as u32;
+    }
+    /* seconds since the epoch as a 32-bit field plus the 2 epoch bits of its `_extra`, which
+     * keeps the nanoseconds above them; the field reads back signed, so the epoch counts from
+     * 1901 as ext4_encode_extra_time does */
+    fn encode_time(secs: u64) -> (u32, u32) {
+        let secs = secs as i64;
+        (secs as u32, (((secs - secs as i32 as i64) >> 32) & 3) as u32)
+    }
+    /* `encode_time` undone, as ext4_decode_extra_time */
+    fn decode_time(time: u32, extra: u32) -> i64 {
+        time as i32 as i64 + (((extra & 3) as i64) << 32)
+    }
+    /// last access, in seconds since the epoch
+    #[inline]
+    pub fn atime(&self) -> i64 {
+        Self::decode_time(self.atime, self.atime_extra)
+    }
+    /// last change of the contents
+    #[inline]
+    pub fn mtime(&self) -> i64 {
+        Self::decode_time(self.mtime, self.mtime_extra)
+    }
+    /// last change of the inode
+    #[inline]
+    pub fn ctime(&self) -> i64 {
+        Self::decode_time(self.ctime, self.ctime_extra)
+    }
+    #[inline]
+    pub fn crtime(&self) -> i64 {
+        Self::decode_time(self.crtime, self.crtime_extra)
+    }
+    pub fn set_atime(&mut self, secs: u64) {
+        (self.atime, self.atime_extra) = Self::encode_time(secs)
+    }
+    pub fn set_mtime(&mut self, secs: u64) {
+        (self.mtime, self.mtime_extra) = Self::encode_time(secs)
+    }
+    pub fn set_ctime(&mut self, secs: u64) {
+        (self.ctime, self.ctime_extra) = Self::encode_time(secs)
+    }
+    pub fn set_crtime(&mut self, secs: u64) {
+        (self.crtime, self.crtime_extra) = Self::encode_time(secs)
+    }
+    #[inline]
+    pub fn set_dtime(&mut self, secs: u64) {
+        self.dtime = secs as u32
+    }
+    /// references to an EA_INODE value inode, which keeps them in `ctime` and `version`
+    #[inline]
+    pub fn ea_refcount(&self) -> u64 {
+        (self.ctime as u64) << 32 | self.version as u64
+    }
+    #[inline]
+    pub fn set_ea_refcount(&mut self, count: u64) {
+        self.ctime = (count >> 32) as u32;
+        self.version = count as u32;
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::Inode;
+    use crate::io::CoreRead;
+    use crate::SuperBlock;
+    use crate::ext4::testdata::{unhex, CSUM_INODE, CSUM_SB};
+
+    #[test]
+    fn time_round_trip() {
+        for secs in [0, 1_700_000_000, (1 << 31) - 1, 1 << 31, (1 << 32) - 1, 1 << 32, (1 << 33) + 12345] {
+            let mut inode = Inode::default();
+            inode.set_mtime(secs);
+            assert_eq!(inode.mtime(), secs as i64, "{}", secs);
+        }
+    }
+
+    #[test]
+    fn time_epoch_bits() {
+        /* 2038-01-19 and later is past i32::MAX, the epoch bits say so */
+        assert_eq!(Inode::encode_time(1 << 31), (1 << 31, 1));
+        assert_eq!(Inode::encode_time((1 << 32) - 1), (u32::MAX, 1));
+        assert_eq!(Inode::encode_time(1 << 32), (0, 1));
+        assert_eq!(Inode::encode_time((1 << 32) + (1 << 31)), (1 << 31, 2));
+        assert_eq!(Inode::encode_time(1_700_000_000), (1_700_000_000, 0));
+    }
 
+    #[test]
+    fn checksum() {
+        let sb = SuperBlock::from_buffer(&unhex(SuperBlock::SIZE, CSUM_SB)).unwrap();
+        let raw = unhex(256, CSUM_INODE);
+        let inode = raw.as_slice().read_struct::<Inode>().unwrap();
+        assert_eq!(inode.csum_seed(12, &sb), 0x1e29f3fa);
+        assert!(inode.checksum_valid(&raw, 12, &sb));
+        /* seeded with the inode number */
+        assert!(!inode.checksum_valid(&raw, 13, &sb));
+        let mut bad = raw.clone();
+        bad[0xa0] = 1;
+        assert!(!inode.checksum_valid(&bad, 12, &sb));
+    }
+}
@@ -0,0 +1,404 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use crate::ext4::checksum::crc32c;
+
+/// first four bytes of every journal metadata block, and of the journal superblock
+pub const JBD2_MAGIC: u32 = 0xC03B_3998;
+/* s_checksum_type of a journal checksummed with crc32c */
+const JBD2_CRC32C_CHKSUM: u8 = 4;
+/* fast commit blocks at the end of the journal when s_num_fc_blks says nothing */
+const JBD2_DEFAULT_FAST_COMMIT_BLOCKS: u64 = 256;
+
+bitflags! {
+    #[derive(Debug, Default, Copy, Clone)]
+    pub struct JournalFeatureCompat: u32 {
+        /* crc32 of the data blocks in the commit block, the old v1 checksums */
+        const Checksum = 0x1;
+    }
+    #[derive(Debug, Default, Copy, Clone)]
+    pub struct JournalFeatureInCompat: u32 {
+        const Revoke = 0x1;
+        const Bit64 = 0x2;
+        const AsyncCommit = 0x4;
+        const CsumV2 = 0x8;
+        const CsumV3 = 0x10;
+        const FastCommit = 0x20;
+    }
+    #[derive(Debug, Default, Copy, Clone)]
+    pub struct TagFlags: u32 {
+        /* the block started with JBD2_MAGIC, which was zeroed in the log */
+        const Escape = 0x1;
+        /* same uuid as the previous tag, none follows this one */
+        const SameUuid = 0x2;
+        const Deleted = 0x4;
+        const LastTag = 0x8;
+    }
+}
+
+impl JournalFeatureInCompat {
+    /// everything recovery knows how to read; fast commits are never replayed, so a journal
+    /// that may hold them is refused rather than recovered without them
+    pub const SUPPORTED: Self = Self::all().difference(Self::FastCommit);
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JournalBlockType {
+    Descriptor = 1,
+    Commit = 2,
+    SuperBlockV1 = 3,
+    SuperBlockV2 = 4,
+    Revoke = 5,
+}
+
+impl JournalBlockType {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Descriptor),
+            2 => Some(Self::Commit),
+            3 => Some(Self::SuperBlockV1),
+            4 => Some(Self::SuperBlockV2),
+            5 => Some(Self::Revoke),
+            _ => None,
+        }
+    }
+}
+
+#[inline]
+fn be32(block: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+#[inline]
+fn be16(block: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(block[offset..offset + 2].try_into().unwrap())
+}
+
+/// `journal_header_t`, the start of every journal metadata block; the journal is big endian
+#[derive(Debug, Copy, Clone)]
+pub struct JournalHeader {
+    pub block_type: JournalBlockType,
+    /// transaction the block belongs to
+    pub sequence: u32,
+}
+
+impl JournalHeader {
+    pub const SIZE: usize = 12;
+    /// `None` for anything that is not a journal metadata block, e.g. stale data
+    pub fn parse(block: &[u8]) -> Option<Self> {
+        if block.len() < Self::SIZE || be32(block, 0) != JBD2_MAGIC {
+            return None;
+        }
+        Some(Self { block_type: JournalBlockType::from_u32(be32(block, 4))?, sequence: be32(block, 8) })
+    }
+    pub fn write(block: &mut [u8], block_type: JournalBlockType, sequence: u32) {
+        block[0..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+        block[4..8].copy_from_slice(&(block_type as u32).to_be_bytes());
+        block[8..12].copy_from_slice(&sequence.to_be_bytes());
+    }
+}
+
+/// one `journal_block_tag_t` of a descriptor block: where the next logged block goes home
+#[derive(Debug, Copy, Clone)]
+pub struct BlockTag {
+    pub block: u64,
+    pub flags: TagFlags,
+    /* crc32c of the sequence and the block, only the low 16 bits with csum v2 */
+    checksum: u32,
+}
+
+/// `journal_superblock_t`, block 0 of the journal
+#[derive(Debug, Clone)]
+pub struct JournalSuperBlock {
+    block_size: u32,
+    /* blocks in the journal */
+    max_len: u32,
+    /* first block of the log */
+    first: u32,
+    /* first transaction expected in the log */
+    sequence: u32,
+    /* block of that transaction, 0 when the journal is empty */
+    start: u32,
+    feature_compat: JournalFeatureCompat,
+    feature_in_compat: JournalFeatureInCompat,
+    uuid: [u8; 16],
+    checksum_type: u8,
+    num_fc_blocks: u32,
+    checksum: u32,
+}
+
+impl JournalSuperBlock {
+    /// bytes covered by `s_checksum`
+    pub const SIZE: usize = 1024;
+    const START_OFFSET: usize = 0x1C;
+    const SEQUENCE_OFFSET: usize = 0x18;
+    const FEATURE_COMPAT_OFFSET: usize = 0x24;
+    const CHECKSUM_OFFSET: usize = 0xFC;
+    /* h_chksum[0] of a commit block, after h_chksum_type, h_chksum_size and padding */
+    const COMMIT_CHECKSUM_OFFSET: usize = JournalHeader::SIZE + 4;
+    pub fn parse(block: &[u8]) -> Option<Self> {
+        if block.len() < Self::SIZE {
+            return None;
+        }
+        let header = JournalHeader::parse(block)?;
+        /* a v1 superblock ends after s_errno, none of the features exist there */
+        let v2 = match header.block_type {
+            JournalBlockType::SuperBlockV1 => false,
+            JournalBlockType::SuperBlockV2 => true,
+            _ => return None,
+        };
+        let field = |offset: usize| match v2 {
+            true => be32(block, offset),
+            false => 0,
+        };
+        Some(Self {
+            block_size: be32(block, 0x0C),
+            max_len: be32(block, 0x10),
+            first: be32(block, 0x14),
+            sequence: be32(block, Self::SEQUENCE_OFFSET),
+            start: be32(block, Self::START_OFFSET),
+            feature_compat: JournalFeatureCompat::from_bits_retain(field(Self::FEATURE_COMPAT_OFFSET)),
+            feature_in_compat: JournalFeatureInCompat::from_bits_retain(field(0x28)),
+            uuid: block[0x30..0x40].try_into().unwrap(),
+            checksum_type: block[0x50],
+            num_fc_blocks: field(0x54),
+            checksum: field(Self::CHECKSUM_OFFSET),
+        })
+    }
+    #[inline]
+    pub fn block_size(&self) -> u64 {
+        self.block_size as u64
+    }
+    #[inline]
+    pub fn first(&self) -> u64 {
+        self.first as u64
+    }
+    /// one past the last block of the log, which stops short of the fast commit area
+    pub fn last(&self) -> u64 {
+        let fc_blocks = match self.feature_in_compat.contains(JournalFeatureInCompat::FastCommit) {
+            true => match self.num_fc_blocks {
+                0 => JBD2_DEFAULT_FAST_COMMIT_BLOCKS,
+                n => n as u64,
+            },
+            false => 0,
+        };
+        (self.max_len as u64).saturating_sub(fc_blocks)
+    }
+    #[inline]
+    pub fn max_len(&self) -> u64 {
+        self.max_len as u64
+    }
+    #[inline]
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+    #[inline]
+    pub fn start(&self) -> u64 {
+        self.start as u64
+    }
+    /// features recovery would have to understand and does not
+    pub fn unknown_features(&self) -> u32 {
+        self.feature_in_compat.bits() & !JournalFeatureInCompat::SUPPORTED.bits()
+    }
+    #[inline]
+    pub fn is_64bit(&self) -> bool {
+        self.feature_in_compat.contains(JournalFeatureInCompat::Bit64)
+    }
+    /// v2 and v3 checksum blocks and tags with crc32c, v1 checksums are not looked at
+    pub fn has_csum(&self) -> bool {
+        self.feature_in_compat.intersects(JournalFeatureInCompat::CsumV2 | JournalFeatureInCompat::CsumV3)
+    }
+    /// what every checksum but the superblock's starts from, `j_csum_seed`
+    pub fn csum_seed(&self) -> u32 {
+        crc32c(!0, &self.uuid)
+    }
+    /// crc32c of the superblock with `s_checksum` zeroed, true without checksums
+    pub fn checksum_valid(&self, block: &[u8]) -> bool {
+        !self.has_csum()
+            || (self.checksum_type == JBD2_CRC32C_CHKSUM && Self::compute_checksum(block) == self.checksum)
+    }
+    fn compute_checksum(block: &[u8]) -> u32 {
+        let crc = crc32c(!0, &block[..Self::CHECKSUM_OFFSET]);
+        let crc = crc32c(crc, &[0; 4]);
+        crc32c(crc, &block[Self::CHECKSUM_OFFSET + 4..Self::SIZE])
+    }
+    /// point the log at `start`, 0 for an empty journal, where transaction `sequence` is expected,
+    /// in the raw superblock `block`, and checksum it. v1 checksums are never written, so their
+    /// flag goes too, which is safe as nothing older than `sequence` is read again
+    pub fn write_start(&mut self, block: &mut [u8], start: u64, sequence: u32) {
+        self.start = start as u32;
+        self.sequence = sequence;
+        self.feature_compat.remove(JournalFeatureCompat::Checksum);
+        block[Self::START_OFFSET..Self::START_OFFSET + 4].copy_from_slice(&self.start.to_be_bytes());
+        block[Self::SEQUENCE_OFFSET..Self::SEQUENCE_OFFSET + 4].copy_from_slice(&sequence.to_be_bytes());
+        if block[4..8] == (JournalBlockType::SuperBlockV2 as u32).to_be_bytes() {
+            block[Self::FEATURE_COMPAT_OFFSET..Self::FEATURE_COMPAT_OFFSET + 4]
+                .copy_from_slice(&self.feature_compat.bits().to_be_bytes());
+        }
+        if self.has_csum() {
+            self.checksum = Self::compute_checksum(block);
+            block[Self::CHECKSUM_OFFSET..Self::CHECKSUM_OFFSET + 4].copy_from_slice(&self.checksum.to_be_bytes());
+        }
+    }
+    /// bytes of one descriptor tag, as journal_tag_bytes
+    pub fn tag_bytes(&self) -> usize {
+        if self.feature_in_compat.contains(JournalFeatureInCompat::CsumV3) {
+            return 16;
+        }
+        /* t_blocknr, t_checksum, t_flags, t_blocknr_high, and two bytes more with csum v2 */
+        let size = match self.feature_in_compat.contains(JournalFeatureInCompat::CsumV2) {
+            true => 14,
+            false => 12,
+        };
+        match self.is_64bit() {
+            true => size,
+            false => size - 4,
+        }
+    }
+    /* bytes at the end of descriptor and revoke blocks taken by `jbd2_journal_block_tail` */
+    fn tail_size(&self) -> usize {
+        match self.has_csum() {
+            true => 4,
+            false => 0,
+        }
+    }
+    /// the tags of a descriptor block, one per logged block following it
+    pub fn tags(&self, block: &[u8]) -> Vec<BlockTag> {
+        let tag_bytes = self.tag_bytes();
+        let csum_v3 = self.feature_in_compat.contains(JournalFeatureInCompat::CsumV3);
+        let end = block.len() - self.tail_size();
+        let mut tags = Vec::new();
+        let mut offset = JournalHeader::SIZE;
+        while offset + tag_bytes <= end {
+            let tag = &block[offset..offset + tag_bytes];
+            let (flags, checksum) = match csum_v3 {
+                true => (be32(tag, 4), be32(tag, 12)),
+                false => (be16(tag, 6) as u32, be16(tag, 4) as u32),
+            };
+            let high = match self.is_64bit() {
+                true => (be32(tag, 8) as u64) << 32,
+                false => 0,
+            };
+            let flags = TagFlags::from_bits_retain(flags);
+            tags.push(BlockTag { block: high | be32(tag, 0) as u64, flags, checksum });
+            offset += tag_bytes;
+            if !flags.contains(TagFlags::SameUuid) {
+                offset += 16;
+            }
+            if flags.contains(TagFlags::LastTag) {
+                break;
+            }
+        }
+        tags
+    }
+    /// the blocks a revoke block revokes
+    pub fn revoked(&self, block: &[u8]) -> Vec<u64> {
+        /* r_count counts the bytes used, header and r_count included */
+        let used = (be32(block, JournalHeader::SIZE) as usize).min(block.len() - self.tail_size());
+        let record = match self.is_64bit() {
+            true => 8,
+            false => 4,
+        };
+        block.get(JournalHeader::SIZE + 4..used).unwrap_or_default()
+            .chunks_exact(record)
+            .map(|r| match self.is_64bit() {
+                true => u64::from_be_bytes(r.try_into().unwrap()),
+                false => be32(r, 0) as u64,
+            })
+            .collect()
+    }
+    /// a descriptor or revoke block against the checksum in its tail
+    pub fn block_tail_valid(&self, block: &[u8]) -> bool {
+        !self.has_csum() || self.tail_checksum(block) == be32(block, block.len() - 4)
+    }
+    fn tail_checksum(&self, block: &[u8]) -> u32 {
+        let tail = block.len() - 4;
+        let crc = crc32c(self.csum_seed(), &block[..tail]);
+        crc32c(crc, &[0; 4])
+    }
+    /// a commit block against `h_chksum[0]`
+    pub fn commit_valid(&self, block: &[u8]) -> bool {
+        !self.has_csum() || self.commit_checksum(block) == be32(block, Self::COMMIT_CHECKSUM_OFFSET)
+    }
+    fn commit_checksum(&self, block: &[u8]) -> u32 {
+        let offset = Self::COMMIT_CHECKSUM_OFFSET;
+        let crc = crc32c(self.csum_seed(), &block[..offset]);
+        let crc = crc32c(crc, &[0; 4]);
+        crc32c(crc, &block[offset + 4..])
+    }
+    /// a logged block of transaction `sequence` against the checksum in its tag
+    pub fn data_valid(&self, tag: &BlockTag, sequence: u32, block: &[u8]) -> bool {
+        if !self.has_csum() {
+            return true;
+        }
+        let crc = self.data_checksum(sequence, block);
+        match self.feature_in_compat.contains(JournalFeatureInCompat::CsumV3) {
+            true => crc == tag.checksum,
+            false => crc & 0xffff == tag.checksum,
+        }
+    }
+    fn data_checksum(&self, sequence: u32, block: &[u8]) -> u32 {
+        let crc = crc32c(self.csum_seed(), &sequence.to_be_bytes());
+        crc32c(crc, block)
+    }
+    /// tags one descriptor block has room for, the first followed by the uuid
+    pub fn tags_per_descriptor(&self) -> usize {
+        (self.block_size() as usize - JournalHeader::SIZE - self.tail_size() - 16) / self.tag_bytes()
+    }
+    /// the descriptor block of transaction `sequence` for `blocks`: where each goes home, the
+    /// flags of its tag and the copy logged after the descriptor
+    pub fn descriptor_block(&self, sequence: u32, blocks: &[(u64, TagFlags, &[u8])]) -> Vec<u8> {
+        let mut block = vec![0u8; self.block_size() as usize];
+        JournalHeader::write(&mut block, JournalBlockType::Descriptor, sequence);
+        let tag_bytes = self.tag_bytes();
+        let csum_v3 = self.feature_in_compat.contains(JournalFeatureInCompat::CsumV3);
+        let mut offset = JournalHeader::SIZE;
+        for (i, (home, flags, data)) in blocks.iter().enumerate() {
+            let mut flags = *flags;
+            flags.set(TagFlags::SameUuid, i > 0);
+            flags.set(TagFlags::LastTag, i + 1 == blocks.len());
+            let checksum = match self.has_csum() {
+                true => self.data_checksum(sequence, data),
+                false => 0,
+            };
+            let tag = &mut block[offset..offset + tag_bytes];
+            tag[0..4].copy_from_slice(&(*home as u32).to_be_bytes());
+            match csum_v3 {
+                true => {
+                    tag[4..8].copy_from_slice(&flags.bits().to_be_bytes());
+                    tag[12..16].copy_from_slice(&checksum.to_be_bytes());
+                }
+                false => {
+                    tag[4..6].copy_from_slice(&(checksum as u16).to_be_bytes());
+                    tag[6..8].copy_from_slice(&(flags.bits() as u16).to_be_bytes());
+                }
+            }
+            if self.is_64bit() {
+                tag[8..12].copy_from_slice(&((*home >> 32) as u32).to_be_bytes());
+            }
+            offset += tag_bytes;
+            if i == 0 {
+                block[offset..offset + 16].copy_from_slice(&self.uuid);
+                offset += 16;
+            }
+        }
+        if self.has_csum() {
+            let tail = block.len() - 4;
+            let checksum = self.tail_checksum(&block);
+            block[tail..].copy_from_slice(&checksum.to_be_bytes());
+        }
+        block
+    }
+    /// the commit block of transaction `sequence`, committed at `time`
+    pub fn commit_block(&self, sequence: u32, time: u64) -> Vec<u8> {
+        let mut block = vec![0u8; self.block_size() as usize];
+        JournalHeader::write(&mut block, JournalBlockType::Commit, sequence);
+        /* h_commit_sec, after h_chksum[JBD2_CHECKSUM_BYTES] */
+        block[0x30..0x38].copy_from_slice(&time.to_be_bytes());
+        if self.has_csum() {
+            let checksum = self.commit_checksum(&block);
+            block[Self::COMMIT_CHECKSUM_OFFSET..Self::COMMIT_CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_be_bytes());
+        }
+        block
+    }
+}
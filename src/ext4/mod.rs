@@ -1,3 +1,6 @@
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use core::cell::RefCell;
 use crate::{CoreRead, Error};
 
 pub mod superblock;
@@ -6,6 +9,16 @@ pub mod inode;
 pub mod stat;
 pub mod dir;
 pub mod extent_tree;
+pub mod block_map;
+pub mod xattr;
+pub mod hash;
+pub mod htree;
+pub mod acl;
+pub mod checksum;
+pub mod bitmap;
+pub mod journal;
+#[cfg(test)]
+pub(crate) mod testdata;
 pub const ROOT_INODE_NUM: u64 = 2;
 
 #[macro_export]
@@ -26,6 +39,24 @@ macro_rules! align_up {
     };
 }
 
+/// storage a filesystem lives on; every access is positioned, so a device keeps no cursor and
+/// one can be shared by several readers
+pub trait BlockDevice {
+    /// fill all of `buf` with the bytes at `offset`, running out of device is an error
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error>;
+    /// write all of `buf` at `offset`
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<(), Error>;
+    /// make everything written so far durable
+    fn flush(&self) -> Result<(), Error>;
+    /// size of the device in bytes
+    fn size(&self) -> Result<u64, Error>;
+    /// smallest unit the device reads or writes
+    fn block_size(&self) -> u64 {
+        512
+    }
+}
+
+/// cursor based storage, see `BlockDevice` and `DiskDevice` to use it with a filesystem
 pub trait Disk{
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
 
@@ -34,8 +65,48 @@ pub trait Disk{
     fn seek_to(&mut self, offset: u64);
 }
 
-impl CoreRead for dyn Disk{
+impl CoreRead for dyn Disk + '_ {
     fn read_bytes(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
         self.read(buf)
     }
 }
+
+/// a `BlockDevice` over a `Disk`: reads are retried until full and writes are refused, the old
+/// trait having no way to write
+pub struct DiskDevice {
+    disk: RefCell<Box<dyn Disk>>,
+    size: Option<u64>,
+}
+
+impl DiskDevice {
+    pub fn new(disk: Box<dyn Disk>) -> Self {
+        Self { disk: RefCell::new(disk), size: None }
+    }
+    /// a `Disk` cannot tell its size, pass it here when `size()` should know it
+    pub fn with_size(disk: Box<dyn Disk>, size: u64) -> Self {
+        Self { disk: RefCell::new(disk), size: Some(size) }
+    }
+}
+
+impl BlockDevice for DiskDevice {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let mut disk = self.disk.borrow_mut();
+        let mut done = 0usize;
+        while done < buf.len() {
+            match disk.read_at(offset + done as u64, &mut buf[done..])? {
+                0 => return Err(Error::UnexpectedEof("failed to fill whole buffer".to_string())),
+                n => done += n,
+            }
+        }
+        Ok(())
+    }
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<(), Error> {
+        Err(Error::IOError("a Disk is read-only".to_string()))
+    }
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn size(&self) -> Result<u64, Error> {
+        self.size.ok_or_else(|| Error::IOError("a Disk does not know its size".to_string()))
+    }
+}
@@ -62,36 +62,55 @@ impl Display for Mode {
 }
 
 impl Mode {
+    /* the file types share bits (a socket contains DIRECTORY), so compare the whole type field */
+    #[inline]
+    fn is_type(&self, file_type: Mode) -> bool {
+        self.file_type().bits() == file_type.bits()
+    }
+
     pub fn is_dir(&self) -> bool {
-        self.contains(Self::DIRECTORY)
+        self.is_type(Self::DIRECTORY)
     }
 
     pub fn is_file(&self) -> bool {
-        self.contains(Self::FILE)
+        self.is_type(Self::FILE)
     }
 
     pub fn is_symlink(&self) -> bool {
-        self.contains(Self::SYMLINK)
+        self.is_type(Self::SYMLINK)
     }
 
     /// Returns true if this mode represents a fifo, also known as a named pipe.
     pub fn is_fifo(&self) -> bool {
-        self.contains(Self::FIFO)
+        self.is_type(Self::FIFO)
     }
 
     /// Returns true if this mode represents a character device.
     pub fn is_char_device(&self) -> bool {
-        self.contains(Self::CHAR_DEVICE)
+        self.is_type(Self::CHAR_DEVICE)
     }
 
     /// Returns true if this mode represents a block device.
     pub fn is_block_device(&self) -> bool {
-        self.contains(Self::BLOCK_DEVICE)
+        self.is_type(Self::BLOCK_DEVICE)
     }
 
     /// Returns true if this mode represents a Unix-domain socket.
     pub fn is_socket(&self) -> bool {
-        self.contains(Self::SOCKET)
+        self.is_type(Self::SOCKET)
+    }
+    /// the EXT4_FT_* a directory entry records for this type
+    pub fn dir_file_type(&self) -> u8 {
+        match self.file_type() {
+            t if t.bits() == Self::FILE.bits() => 1,
+            t if t.bits() == Self::DIRECTORY.bits() => 2,
+            t if t.bits() == Self::CHAR_DEVICE.bits() => 3,
+            t if t.bits() == Self::BLOCK_DEVICE.bits() => 4,
+            t if t.bits() == Self::FIFO.bits() => 5,
+            t if t.bits() == Self::SOCKET.bits() => 6,
+            t if t.bits() == Self::SYMLINK.bits() => 7,
+            _ => 0,
+        }
     }
     pub fn file_type(&self) -> Mode {
         Mode::from_bits_truncate(self.bits() & 0xf000)
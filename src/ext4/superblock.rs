@@ -1,13 +1,14 @@
 #![allow(dead_code)]
 use alloc::string::{String, ToString};
 use alloc::{format};
-use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::fmt::{Display, Formatter};
 
 use bitflags::{bitflags};
-use crate::ext4::Disk;
+use crate::ext4::BlockDevice;
+use crate::ext4::checksum::crc32c;
 use crate::io::{CoreRead};
+use crate::to_slice;
 
 bitflags! {
     #[derive(Debug, Default, Copy, Clone)]
@@ -95,6 +96,31 @@ pub enum HashAlgorithm {
     HalfMD4Unsigned = 0x4,
     TeaUnsigned = 0x5,
 }
+impl TryFrom<u8> for HashAlgorithm {
+    type Error = u8;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(Self::Legacy),
+            0x1 => Ok(Self::HalfMD4),
+            0x2 => Ok(Self::Tea),
+            0x3 => Ok(Self::LegacyUnsigned),
+            0x4 => Ok(Self::HalfMD4Unsigned),
+            0x5 => Ok(Self::TeaUnsigned),
+            _ => Err(value),
+        }
+    }
+}
+impl HashAlgorithm {
+    /// the unsigned-char twin of a signed hash, used when the superblock says so
+    pub fn to_unsigned(self) -> Self {
+        match self {
+            Self::Legacy => Self::LegacyUnsigned,
+            Self::HalfMD4 => Self::HalfMD4Unsigned,
+            Self::Tea => Self::TeaUnsigned,
+            other => other,
+        }
+    }
+}
 #[repr(u32)]
 #[derive(Debug, Copy, Clone)]
 pub enum EncryptAlgos {
@@ -242,13 +268,10 @@ impl SuperBlock {
     pub const OFFSET: usize = Self::SIZE;
     pub const MAGIC: u16 = 0xef53;
 
-    pub fn new(f: &mut Box<dyn Disk>) -> Option<Self>{
-        f.seek_to(Self::OFFSET as u64);
-        let sb = f.read_struct::<Self>().unwrap();
-        match sb.is_valid() {
-            true => Some(sb),
-            false => None
-        }
+    pub fn new(f: &dyn BlockDevice) -> Option<Self>{
+        let mut buffer = [0u8; Self::SIZE];
+        f.read_at(Self::OFFSET as u64, &mut buffer).ok()?;
+        Self::from_buffer(&buffer)
     }
     pub fn from_buffer(buffer: &[u8]) -> Option<Self>{
         let mut buf = buffer;
@@ -258,9 +281,28 @@ impl SuperBlock {
             false => None
         }
     }
+    /// smallest and largest `s_desc_size` of a 64bit filesystem
+    pub const MIN_DESC_SIZE_64BIT: u64 = 64;
+    pub const MAX_DESC_SIZE: u64 = 1024;
     #[inline]
     pub fn is_valid(&self)-> bool{
-        self.magic == Self::MAGIC
+        self.magic == Self::MAGIC && self.desc_size_valid()
+    }
+    fn desc_size_valid(&self) -> bool {
+        let size = self.desc_size as u64;
+        !self.is_64bit() || ((Self::MIN_DESC_SIZE_64BIT..=Self::MAX_DESC_SIZE).contains(&size) && size.is_power_of_two())
+    }
+    #[inline]
+    pub fn is_64bit(&self) -> bool {
+        self.feature_in_compat.contains(FeatureInCompat::Is64bit)
+    }
+    /* the upper half of a split counter, which only 64bit filesystems use */
+    #[inline]
+    fn hi(&self, value: u32) -> u64 {
+        match self.is_64bit() {
+            true => (value as u64) << 32,
+            false => 0,
+        }
     }
     #[inline]
     pub fn feature_compat(&self) -> FeatureCompat{
@@ -293,11 +335,16 @@ impl SuperBlock {
     }
     #[inline]
     pub fn block_count(&self) -> u64{
-        self.blocks_count_lo as u64| ((self.blocks_count_hi as u64) << 32)
+        self.blocks_count_lo as u64 | self.hi(self.blocks_count_hi)
     }
     #[inline]
     pub fn free_block_count(&self) -> u64{
-        self.free_blocks_count_lo as u64| ((self.free_blocks_count_hi as u64) << 32)
+        self.free_blocks_count_lo as u64 | self.hi(self.free_blocks_count_hi)
+    }
+    /// blocks kept back for root, `s_r_blocks_count`
+    #[inline]
+    pub fn reserved_block_count(&self) -> u64 {
+        self.r_blocks_count_lo as u64 | self.hi(self.r_blocks_count_hi)
     }
     #[inline]
     pub fn inodes_count(&self) -> u64{
@@ -307,6 +354,17 @@ impl SuperBlock {
     pub fn free_inodes_count(&self) -> u64{
         self.free_inodes_count as u64
     }
+    /// store the free block count, the high half only on 64bit filesystems
+    pub fn set_free_block_count(&mut self, count: u64) {
+        self.free_blocks_count_lo = count as u32;
+        if self.is_64bit() {
+            self.free_blocks_count_hi = (count >> 32) as u32;
+        }
+    }
+    #[inline]
+    pub fn set_free_inodes_count(&mut self, count: u64) {
+        self.free_inodes_count = count as u32
+    }
     #[inline]
     pub fn inodes_per_group(&self) -> u64{
         self.inodes_per_group as u64
@@ -316,9 +374,41 @@ impl SuperBlock {
         self.blocks_per_group as u64
     }
     #[inline]
+    pub fn clusters_per_group(&self) -> u64{
+        self.clusters_per_group as u64
+    }
+    #[inline]
     pub fn first_data_block(&self) -> u64{
         self.first_data_block as u64
     }
+    /// first inode not reserved for the filesystem itself, 11 on revision 0
+    pub fn first_ino(&self) -> u64 {
+        match self.rev_level {
+            0 => 11,
+            _ => self.first_ino as u64,
+        }
+    }
+    /// `i_extra_isize` new inodes should get, 0 when unset
+    #[inline]
+    pub fn want_extra_isize(&self) -> u64 {
+        self.want_extra_isize as u64
+    }
+    #[inline]
+    pub fn reserved_gdt_blocks(&self) -> u64 {
+        self.reserved_gdt_blocks as u64
+    }
+    /// groups sharing their bitmaps and inode tables with flex_bg, 1 without it
+    pub fn groups_per_flex(&self) -> u64 {
+        match self.feature_in_compat.contains(FeatureInCompat::Flexbg) {
+            true => 1 << self.log_groups_per_flex,
+            false => 1,
+        }
+    }
+    /// blocks of one group's inode table
+    #[inline]
+    pub fn inode_table_blocks(&self) -> u64 {
+        (self.inodes_per_group() * self.inode_size()).div_ceil(self.block_size())
+    }
     #[inline]
     pub fn block_size(&self) -> u64{
         (1024 << self.log_block_size) as u64
@@ -327,8 +417,93 @@ impl SuperBlock {
     pub fn inode_size(&self) -> u64{
         self.inode_size as u64
     }
+    #[inline]
+    pub fn flags(&self) -> SBFlags {
+        self.flags
+    }
+    #[inline]
+    pub fn hash_seed(&self) -> [u32; 4] {
+        self.hash_seed
+    }
+    #[inline]
+    pub fn def_hash_version(&self) -> HashAlgorithm {
+        self.def_hash_version
+    }
+    /// the hash a directory really uses: signed variants turn unsigned on filesystems flagged so
+    pub fn dir_hash_version(&self, version: HashAlgorithm) -> HashAlgorithm {
+        match self.flags.contains(SBFlags::UnsignedDirectoryHash) {
+            true => version.to_unsigned(),
+            false => version,
+        }
+    }
+    #[inline]
+    pub fn has_metadata_csum(&self) -> bool {
+        self.feature_ro_compat.contains(FeatureRoCompat::MetadataCsum)
+    }
+    /// metadata_csum or the older GDT_CSUM protects the group descriptors
+    #[inline]
+    pub fn has_group_desc_csum(&self) -> bool {
+        self.has_metadata_csum() || self.feature_ro_compat.contains(FeatureRoCompat::GdtCsum)
+    }
+    /// bytes of one group descriptor on disk, 32 unless the filesystem is 64bit
+    pub fn desc_size(&self) -> u64 {
+        match self.is_64bit() {
+            true => self.desc_size as u64,
+            false => 32,
+        }
+    }
+    #[inline]
+    pub fn default_mount_opts(&self) -> MountOpt {
+        self.default_mount_opts
+    }
+    #[inline]
+    pub fn has_journal(&self) -> bool {
+        self.feature_compat.contains(FeatureCompat::HasJournal)
+    }
+    /// inode of the journal, 0 when it lives on another device
+    #[inline]
+    pub fn journal_inum(&self) -> u64 {
+        self.journal_inum as u64
+    }
+    /// the journal holds transactions not yet written to their home blocks
+    #[inline]
+    pub fn needs_recovery(&self) -> bool {
+        self.feature_in_compat.contains(FeatureInCompat::Recove)
+    }
+    #[inline]
+    pub fn set_needs_recovery(&mut self, recover: bool) {
+        self.feature_in_compat.set(FeatureInCompat::Recove, recover)
+    }
+    #[inline]
+    pub fn raw_uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+    /// what every metadata_csum checksum starts from: crc32c of the uuid unless it was stored
+    pub fn csum_seed(&self) -> u32 {
+        match self.feature_in_compat.contains(FeatureInCompat::CsumSeed) {
+            true => self.checksum_seed,
+            false => crc32c(!0, &self.uuid),
+        }
+    }
+    /// crc32c of everything before `s_checksum`, true when metadata_csum is off
+    pub fn checksum_valid(&self) -> bool {
+        if !self.has_metadata_csum() {
+            return true;
+        }
+        let raw = to_slice!(self, SuperBlock);
+        /* 1 is the only checksum type, crc32c */
+        self.checksum_type == 1 && crc32c(!0, &raw[..Self::SIZE - 4]) == self.checksum
+    }
+    /// recompute `s_checksum` after changing a field, nothing to do without metadata_csum
+    pub fn update_checksum(&mut self) {
+        if self.has_metadata_csum() {
+            let raw = to_slice!(self, SuperBlock);
+            self.checksum = crc32c(!0, &raw[..Self::SIZE - 4]);
+        }
+    }
     pub fn get_groups_count(&self) -> usize {
-        let block_count = self.block_count();
+        /* groups start counting at s_first_data_block, block 1 on 1k-block filesystems */
+        let block_count = self.block_count() - self.first_data_block();
         let blocks_per_group = self.blocks_per_group();
         let count = (block_count / blocks_per_group) as usize;
         match block_count % blocks_per_group == 0 {
@@ -351,6 +526,51 @@ impl SuperBlock {
 
     }
     #[inline]
+    pub fn first_meta_bg(&self) -> u64 {
+        self.first_meta_bg as u64
+    }
+    /// whether `group` starts with a superblock (and, without META_BG, a descriptor table copy):
+    /// group 0 always, with sparse_super2 the two backup groups, with sparse_super groups 1 and
+    /// powers of 3, 5 and 7, otherwise every group
+    pub fn group_has_super(&self, group: u64) -> bool {
+        let is_power_of = |mut n: u64, base: u64| {
+            while n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        };
+        if group == 0 {
+            return true;
+        }
+        if self.feature_compat.contains(FeatureCompat::SparseSuper2) {
+            return self.backup_bgs.contains(&(group as u32));
+        }
+        if group == 1 || !self.feature_ro_compat.contains(FeatureRoCompat::SparseSuper) {
+            return true;
+        }
+        is_power_of(group, 3) || is_power_of(group, 5) || is_power_of(group, 7)
+    }
+    /// blocks at the start of `group` taken by the superblock and descriptor table copies, as
+    /// ext4_num_base_meta_blocks: the table (plus the reserved GDT blocks without META_BG)
+    /// where there is a superblock, or with META_BG one block in the first, second and last group of a meta group
+    pub fn group_meta_blocks(&self, group: u64) -> u64 {
+        let has_super = self.group_has_super(group) as u64;
+        let per_block = self.block_size() / self.desc_size();
+        let meta_bg = self.feature_in_compat.contains(FeatureInCompat::MetaBg);
+        if !meta_bg || group < self.first_meta_bg() * per_block {
+            let table = match meta_bg {
+                true => self.first_meta_bg(),
+                false => (self.get_groups_count() as u64).div_ceil(per_block) + self.reserved_gdt_blocks(),
+            };
+            return match has_super {
+                1 => 1 + table,
+                _ => 0,
+            };
+        }
+        let first = group / per_block * per_block;
+        has_super + (group == first || group == first + 1 || group == first + per_block - 1) as u64
+    }
+    #[inline]
     pub fn has_sb_backup(&self) -> bool{
         self.feature_compat().contains(FeatureCompat::SparseSuper2)
     }
@@ -358,19 +578,14 @@ impl SuperBlock {
     pub fn backups(&self) -> [u32; 2]{
         self.backup_bgs
     }
-    pub fn find_backup(&self, f: &mut Box<dyn Disk>) -> Vec<u64>{
+    pub fn find_backup(&self, f: &dyn BlockDevice) -> Vec<u64>{
         let mut backup = Vec::new();
         for num in 0..self.get_groups_count(){
             if (num % 2).eq(&1) {
                 let blk_num = self.blocks_per_group() * num as u64 + 1;
-                f.seek_to(blk_num * self.block_size());
-                match f.read_struct::<Self>(){
-                    Ok(sb) => {
-                        if sb.is_valid() {
-                            backup.push(blk_num)
-                        }
-                    },
-                    Err(_) => {}
+                let mut buffer = [0u8; Self::SIZE];
+                if f.read_at(blk_num * self.block_size(), &mut buffer).is_ok() && Self::from_buffer(&buffer).is_some() {
+                    backup.push(blk_num)
                 }
             }
         }
@@ -405,4 +620,37 @@ impl Display for SuperBlock{
 
         )
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::SuperBlock;
+    use crate::ext4::testdata::{unhex, CSUM_SB, GDT_CSUM_SB};
+    use crate::to_slice;
+
+    #[test]
+    fn checksum() {
+        let raw = unhex(SuperBlock::SIZE, CSUM_SB);
+        let mut sb = SuperBlock::from_buffer(&raw).unwrap();
+        assert!(sb.has_metadata_csum());
+        assert!(sb.checksum_valid());
+        assert_eq!(sb.csum_seed(), 0xb720167d);
+        sb.checksum = 0;
+        assert!(!sb.checksum_valid());
+        sb.update_checksum();
+        assert_eq!(to_slice!(&sb, SuperBlock), raw.as_slice());
+    }
+
+    #[test]
+    fn corruption() {
+        let mut raw = unhex(SuperBlock::SIZE, CSUM_SB);
+        raw[0x1a0] ^= 0x10;
+        assert!(!SuperBlock::from_buffer(&raw).unwrap().checksum_valid());
+    }
+
+    #[test]
+    fn without_metadata_csum() {
+        let sb = SuperBlock::from_buffer(&unhex(SuperBlock::SIZE, GDT_CSUM_SB)).unwrap();
+        assert!(!sb.has_metadata_csum() && sb.has_group_desc_csum());
+        assert!(sb.checksum_valid());
+    }
+}
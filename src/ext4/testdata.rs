@@ -0,0 +1,175 @@
+/* metadata taken from images made by mke2fs 1.47 with uuid 00112233-4455-6677-8899-aabbccddeeff
+ * and 1 KiB blocks, kept as (offset, hex) runs of the bytes that are not zero; `xx*n;` stands for
+ * n bytes of xx */
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use crate::{BlockDevice, Error};
+
+/// `len` bytes, zero but for the runs
+pub fn unhex(len: usize, runs: &[(usize, &str)]) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    for (offset, hex) in runs {
+        let (mut at, mut rest) = (*offset, *hex);
+        while !rest.is_empty() {
+            let byte = u8::from_str_radix(&rest[..2], 16).unwrap();
+            rest = &rest[2..];
+            let count = match rest.strip_prefix('*') {
+                Some(tail) => {
+                    let (count, tail) = tail.split_once(';').unwrap();
+                    rest = tail;
+                    count.parse().unwrap()
+                }
+                None => 1,
+            };
+            buf[at..at + count].fill(byte);
+            at += count;
+        }
+    }
+    buf
+}
+
+/// a device in memory, clones share the bytes so a test can look at what a filesystem wrote
+#[derive(Clone)]
+pub struct MemDevice(pub Rc<RefCell<Vec<u8>>>);
+
+impl MemDevice {
+    /// the filesystem of `IMAGE`
+    pub fn image() -> Self {
+        Self(Rc::new(RefCell::new(unhex(IMAGE_SIZE, IMAGE))))
+    }
+    pub fn block(&self, block_num: u64) -> Vec<u8> {
+        let start = block_num as usize * 1024;
+        self.0.borrow()[start..start + 1024].to_vec()
+    }
+    pub fn set_block(&self, block_num: u64, data: &[u8]) {
+        let start = block_num as usize * 1024;
+        self.0.borrow_mut()[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let data = self.0.borrow();
+        let start = offset as usize;
+        let bytes = data.get(start..start + buf.len());
+        let bytes = bytes.ok_or_else(|| Error::UnexpectedEof(alloc::format!("read past {}", data.len())))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        let mut data = self.0.borrow_mut();
+        let start = offset as usize;
+        let len = data.len();
+        let bytes = data.get_mut(start..start + buf.len());
+        bytes.ok_or_else(|| Error::IOError(alloc::format!("write past {}", len)))?.copy_from_slice(buf);
+        Ok(())
+    }
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn size(&self) -> Result<u64, Error> {
+        Ok(self.0.borrow().len() as u64)
+    }
+}
+
+/// superblock of a 64bit, metadata_csum filesystem
+pub const CSUM_SB: &[(usize, &str)] = &[
+    (0, "400000000004000033000000d70300003400000001"),
+    (33, "20000000200000400000000000000031a5d46a0000ffff53ef01000100000031a5d46a"),
+    (76, "01000000000000000b0000000001000028000000c20200006b04000000112233445566778899aabbccddeeff"),
+    (237, "112233445566778899aabbccddeeff010040000c"),
+    (265, "f15365"),
+    (348, "2000200001"),
+    (372, "0401000016"),
+    (584, "15"),
+    (1020, "632f898a"),
+];
+/// its group 0 descriptor, 64 bytes
+pub const CSUM_GROUP: &[(usize, &str)] = &[
+    (0, "030000001300000023000000d70334000200040000000000cfda065e34009b8c"),
+    (56, "05c9a6cb"),
+];
+/// inode 12, a file with generation 0x12345678 and six extents in one leaf block
+pub const CSUM_INODE: &[(usize, &str)] = &[
+    (0, "b68100000000000000f1536500f1536500f1536500000000000001000e00000000000800000000000af30100040001"),
+    (56, "120000000000000002000000018000001400000004000000018000001600000006000000018000001800000078563412"),
+    (124, "4165000020002336"),
+    (145, "f15365"),
+];
+/// superblock of a filesystem with uninit_bg, descriptors checksummed with crc16
+pub const GDT_CSUM_SB: &[(usize, &str)] = &[
+    (0, "400000000004000033000000de0300003500000001"),
+    (33, "2000000020000040"),
+    (49, "f153650000ffff53ef01000100000000f15365"),
+    (76, "01000000000000000b0000000001000028000000420200007b00000000112233445566778899aabbccddeeff"),
+    (236, "6a2ca0f32a224374a4ac9ec9ffeb4a7d010000000c"),
+    (265, "f15365"),
+    (348, "2000200001"),
+    (372, "0400000011"),
+    (584, "15"),
+];
+/// its group 0 descriptor, 32 bytes
+pub const GDT_CSUM_GROUP: &[(usize, &str)] = &[(0, "030000001300000023000000de033500020004"), (28, "35009701")];
+/// block bitmap of group 0, 1023 blocks and then padding
+pub const CSUM_BLOCK_BITMAP: &[(usize, &str)] = &[(0, "ffffaf0afcff03"), (127, "80ff*896;")];
+/// inode bitmap of group 0, 64 inodes and then padding
+pub const CSUM_INODE_BITMAP: &[(usize, &str)] = &[(0, "ff0f000000000000ff*1016;")];
+/// the leaf of inode 12's extent tree, block 18
+pub const CSUM_EXTENT_LEAF: &[(usize, &str)] = &[
+    (0, "0af3060054"),
+    (16, "018000001100000002000000018000001400000004000000018000001600000006000000018000001800000008000000018000001a0000000a000000018000001c"),
+    (1020, "84f492d1"),
+];
+/// the root directory's only block, with a checksum tail
+pub const CSUM_ROOT_DIR: &[(usize, &str)] = &[
+    (0, "020000000c0001022e000000020000000c0002022e2e00000b00000014000a026c6f73742b666f756e6400000c000000c803010166"),
+    (1016, "0c0000debc7b5c02"),
+];
+
+pub const IMAGE_SIZE: usize = 4 << 20;
+/// a whole empty filesystem: 4096 blocks in one group, 64 inodes, 64bit, metadata_csum and a
+/// journal of 1024 blocks in inode 8, its superblock in block 17 and its log in blocks 18, 20 to
+/// 34 and 51 to 1057
+pub const IMAGE: &[(usize, &str)] = &[
+    (1024, "4000000000100000cc000000de0b0000350000000100*12;200000002000004000*8;f153650000ffff53ef01000100000000f1536500*8;01000000000000000b000000000100002c000000c20200006b04000000112233445566778899aabbccddeeff"),
+    (1248, "0800*12;112233445566778899aabbccddeeff010140000c00*8;f153650af303000400*11;0200000011000000020000000f0000001400000011000000ef03000033"),
+    (1358, "1000*13;2000200001"),
+    (1396, "0401000014"),
+    (1608, "1504"),
+    (2044, "e6de3ad1030000001300000023000000de0b350002000400000000004dd855a335003f03"),
+    (2104, "262a8b85"),
+    (3072, "ff*132;01"),
+    (3583, "80ff*512;020000000c0001022e000000020000000c0002022e2e00000b000000dc030a026c6f73742b666f756e64"),
+    (5112, "0c0000dea04f45220b0000000c0001022e00000002000000e80302022e2e"),
+    (6136, "0c0000deb3a6e84800000000f403"),
+    (7160, "0c0000decd973dbc00000000f403"),
+    (8184, "0c0000decd973dbc00000000f403"),
+    (9208, "0c0000decd973dbc00000000f403"),
+    (10232, "0c0000decd973dbc00000000f403"),
+    (11256, "0c0000decd973dbc00000000f403"),
+    (12280, "0c0000decd973dbc00000000f403"),
+    (13304, "0c0000decd973dbc00000000f403"),
+    (14328, "0c0000decd973dbc00000000f403"),
+    (15352, "0c0000decd973dbc00000000f403"),
+    (16376, "0c0000decd973dbc00000000f403"),
+    (17400, "0c0000decd973dbcc03b3998000000040000000000000400000004000000000100000001"),
+    (17457, "112233445566778899aabbccddeeff00000001"),
+    (19456, "ff07000000000000ff*1016;"),
+    (35849, "f1536500f1536500f15365"),
+    (35964, "6069"),
+    (36096, "ed4100000004000000f1536500f1536500f1536500000000000003000200000000000800000000000af301000400*11;0100000004"),
+    (36220, "5cea00002000292f00*13;f15365"),
+    (36476, "9342"),
+    (36732, "79e5"),
+    (36988, "37df"),
+    (37244, "e591"),
+    (37500, "abab"),
+    (37632, "808100000000100000f1536500f1536500f1536500000000000001000008000000000800000000000af303000400*11;0200000011000000020000000f0000001400000011000000ef03000033"),
+    (37756, "fe7900002000220100*13;f15365"),
+    (38012, "7fe4"),
+    (38268, "adaa"),
+    (38400, "c04100000030000000f1536500f1536500f1536500000000000002001800000000000800000000000af301000400*11;0c00000005"),
+    (38524, "396500002000ebde00*13;f15365"),
+];
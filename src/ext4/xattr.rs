@@ -0,0 +1,204 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::CoreRead;
+use crate::to_slice;
+use crate::ext4::checksum::crc32c;
+
+/// header of an external xattr block (`i_file_acl`), possibly shared by several inodes
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct XattrBlockHeader {
+    h_magic: u32,         /* magic number for identification */
+    h_refcount: u32,      /* reference count */
+    h_blocks: u32,        /* number of disk blocks used */
+    h_hash: u32,          /* hash value of all attributes */
+    h_checksum: u32,      /* crc32c(uuid+id+xattrblock) */
+    h_reserved: [u32; 3], /* zero right now */
+}
+
+impl XattrBlockHeader {
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+    /// parse an xattr block, returns its header and entries
+    pub fn from_bytes(block: &[u8]) -> Option<(Self, Vec<XattrEntry>)> {
+        let mut buf = block;
+        let header = buf.read_struct::<Self>().ok()?;
+        match header.h_magic == XattrEntry::MAGIC && header.h_blocks == 1 {
+            true => Some((header, XattrEntry::parse_entries(&block[Self::SIZE..], block))),
+            false => None,
+        }
+    }
+    /// number of inodes sharing this block
+    #[inline]
+    pub fn refcount(&self) -> u32 {
+        self.h_refcount
+    }
+    /// store `refcount` in `block`, the xattr block at `block_num`, and redo its checksum when
+    /// given the filesystem's `seed`
+    pub fn set_refcount(block: &mut [u8], block_num: u64, refcount: u32, seed: Option<u32>) {
+        let refcount_offset = core::mem::offset_of!(Self, h_refcount);
+        block[refcount_offset..refcount_offset + 4].copy_from_slice(&refcount.to_le_bytes());
+        if let Some(seed) = seed {
+            /* crc32c(uuid+blocknr+block), the checksum field counted as zero */
+            let offset = core::mem::offset_of!(Self, h_checksum);
+            let crc = crc32c(seed, &block_num.to_le_bytes());
+            let crc = crc32c(crc, &block[..offset]);
+            let crc = crc32c(crc, &[0u8; 4]);
+            let crc = crc32c(crc, &block[offset + 4..]);
+            block[offset..offset + 4].copy_from_slice(&crc.to_le_bytes());
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct XattrEntryHeader {
+    e_name_len: u8,     /* length of name */
+    e_name_index: u8,   /* attribute name index */
+    e_value_offs: u16,  /* offset in disk block of value */
+    e_value_inum: u32,  /* inode in which the value is stored */
+    e_value_size: u32,  /* size of attribute value */
+    e_hash: u32,        /* hash value of name and value */
+}
+
+#[derive(Debug, Clone)]
+pub struct XattrEntry {
+    header: XattrEntryHeader,
+    name: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl XattrEntry {
+    pub const MAGIC: u32 = 0xEA020000;
+    pub const INDEX_USER: u8 = 1;
+    pub const INDEX_POSIX_ACL_ACCESS: u8 = 2;
+    pub const INDEX_POSIX_ACL_DEFAULT: u8 = 3;
+    pub const INDEX_TRUSTED: u8 = 4;
+    pub const INDEX_LUSTRE: u8 = 5;
+    pub const INDEX_SECURITY: u8 = 6;
+    pub const INDEX_SYSTEM: u8 = 7;
+    pub const INDEX_RICHACL: u8 = 8;
+    pub const INDEX_ENCRYPTION: u8 = 9;
+    pub const INDEX_HURD: u8 = 10;
+    /* name index and the prefix it stands for, the longest prefixes first */
+    const PREFIXES: [(u8, &'static str); 10] = [
+        (Self::INDEX_POSIX_ACL_ACCESS, "system.posix_acl_access"),
+        (Self::INDEX_POSIX_ACL_DEFAULT, "system.posix_acl_default"),
+        (Self::INDEX_RICHACL, "system.richacl"),
+        (Self::INDEX_USER, "user."),
+        (Self::INDEX_TRUSTED, "trusted."),
+        (Self::INDEX_LUSTRE, "lustre."),
+        (Self::INDEX_SECURITY, "security."),
+        (Self::INDEX_SYSTEM, "system."),
+        (Self::INDEX_ENCRYPTION, "encryption."),
+        (Self::INDEX_HURD, "gnu."),
+    ];
+    const HEADER_SIZE: usize = core::mem::size_of::<XattrEntryHeader>();
+    /// parse the entry table starting at `entries`, values are at `e_value_offs` from `values`
+    pub fn parse_entries(entries: &[u8], values: &[u8]) -> Vec<Self> {
+        let mut xattrs = Vec::new();
+        let mut offset = 0usize;
+        while offset + Self::HEADER_SIZE <= entries.len() {
+            let mut buf = &entries[offset..];
+            let header = buf.read_struct::<XattrEntryHeader>().unwrap();
+            /* the table ends with four zero bytes */
+            if header.e_name_len == 0 && header.e_name_index == 0 && header.e_value_offs == 0 && header.e_value_inum == 0 {
+                break;
+            }
+            let name_end = offset + Self::HEADER_SIZE + header.e_name_len as usize;
+            if name_end > entries.len() {
+                break;
+            }
+            let name = entries[offset + Self::HEADER_SIZE..name_end].to_vec();
+            let value_start = header.e_value_offs as usize;
+            let value_end = value_start + header.e_value_size as usize;
+            let value = match header.e_value_inum == 0 && value_end <= values.len() {
+                true => values[value_start..value_end].to_vec(),
+                false => Vec::new(),
+            };
+            xattrs.push(Self { header, name, value });
+            offset = (name_end + 3) & !3;
+        }
+        xattrs
+    }
+    /// entries stored in the inode body after `i_extra_isize`, `raw` is the whole on-disk inode
+    pub fn ibody_entries(raw: &[u8], extra_isize: u64) -> Vec<Self> {
+        let start = 128 + extra_isize as usize;
+        if start + 4 > raw.len() {
+            return Vec::new();
+        }
+        let magic = u32::from_le_bytes(raw[start..start + 4].try_into().unwrap());
+        match magic == Self::MAGIC {
+            true => Self::parse_entries(&raw[start + 4..], &raw[start + 4..]),
+            false => Vec::new(),
+        }
+    }
+    /// drop the entry `name` of name index `index` from the xattrs in the inode body of `raw`,
+    /// the values of the others packed again at the end of the space; false when it is not there
+    pub fn remove_ibody_entry(raw: &mut [u8], extra_isize: u64, index: u8, name: &[u8]) -> bool {
+        let entries = Self::ibody_entries(raw, extra_isize);
+        let found = |x: &Self| x.name_index() == index && x.name == name;
+        if !entries.iter().any(found) {
+            return false;
+        }
+        /* value offsets count from the first entry, right after the magic */
+        let area = &mut raw[128 + extra_isize as usize + 4..];
+        area.fill(0);
+        let (mut offset, mut values) = (0usize, area.len());
+        for mut xattr in entries.into_iter().filter(|x| !found(x)) {
+            if xattr.header.e_value_inum == 0 && !xattr.value.is_empty() {
+                values -= (xattr.value.len() + 3) & !3;
+                area[values..values + xattr.value.len()].copy_from_slice(&xattr.value);
+                xattr.header.e_value_offs = values as u16;
+            }
+            let name_end = offset + Self::HEADER_SIZE + xattr.name.len();
+            area[offset..offset + Self::HEADER_SIZE].copy_from_slice(to_slice!(&xattr.header, XattrEntryHeader));
+            area[offset + Self::HEADER_SIZE..name_end].copy_from_slice(&xattr.name);
+            offset = (name_end + 3) & !3;
+        }
+        true
+    }
+    #[inline]
+    pub fn name_index(&self) -> u8 {
+        self.header.e_name_index
+    }
+    /// the name with the prefix its name index stands for, as getfattr shows it
+    pub fn full_name(&self) -> String {
+        let prefix = Self::PREFIXES
+            .iter()
+            .find(|(index, _)| *index == self.name_index())
+            .map_or("", |(_, prefix)| prefix);
+        prefix.to_string() + &String::from_utf8_lossy(&self.name)
+    }
+    /// split a full name into its name index and the stored suffix
+    pub fn split_name(name: &str) -> Option<(u8, &str)> {
+        Self::PREFIXES
+            .iter()
+            .find(|(_, prefix)| name.starts_with(prefix))
+            .map(|(index, prefix)| (*index, &name[prefix.len()..]))
+    }
+    /// inode holding the value when it did not fit (INCOMPAT_EA_INODE), 0 otherwise
+    #[inline]
+    pub fn value_inum(&self) -> u64 {
+        self.header.e_value_inum as u64
+    }
+    #[inline]
+    pub fn value_size(&self) -> u64 {
+        self.header.e_value_size as u64
+    }
+    /// `system.data`, where inline data continues past `i_block`
+    #[inline]
+    pub fn is_inline_data(&self) -> bool {
+        self.name_index() == Self::INDEX_SYSTEM && self.name == b"data"
+    }
+    pub(crate) fn set_value(&mut self, value: Vec<u8>) {
+        self.value = value
+    }
+    #[inline]
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+    #[inline]
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
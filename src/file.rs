@@ -1,62 +1,481 @@
-use alloc::string::String;
+use alloc::format;
+use alloc::string::{String, ToString};
 use crate::ext4::dir::Entry;
-use crate::{CoreWrite, DirEntry, FileSystem, Inode, Mode};
+use crate::ext4::hash::dir_hash;
+use crate::ext4::htree::{dx_checksum_valid, dx_node_block, dx_node_entries, dx_node_limit, dx_set_checksum, DxEntry, DxRootInfo};
+use crate::ext4::dir::DirEntryTail;
+use crate::error::MetadataKind;
+use crate::ext4::superblock::{FeatureCompat, FeatureInCompat};
+use crate::ext4::xattr::{XattrBlockHeader, XattrEntry};
+use crate::ext4::acl::{mode_permits, Acl};
+use crate::{align_up, BlockMap, BlockDevice, DirEntry, Error, ExtentTree, FileSystem, IFlags, Inode, Mode};
+use alloc::vec;
 use alloc::vec::Vec;
-use core::slice::SlicePattern;
+use core::ops::Range;
+
+/* index entries from an htree root down to a leaf, with the position taken at each level */
+type DxPath = Vec<(Vec<DxEntry>, usize)>;
+
+enum Content {
+    Blocks(BlockMap),
+    /* i_block plus the system.data xattr of an INLINE_DATA inode */
+    Inline(Vec<u8>),
+}
+
 pub struct Ext4File<'a> {
     name: String,
     fs: &'a mut FileSystem,
+    inode_num: u64,
     inode: Inode,
-    blocks: Vec<u64>,
+    content: Content,
     pos: u64,
 }
 
 impl<'a> Ext4File<'a> {
-    pub fn new(inode: Inode, name: String, fs: &'a mut FileSystem) -> Ext4File<'a> {
-        Self {
+    /// levels of an htree, the root included, and with large_dir
+    const DX_MAX_LEVELS: u8 = 2;
+    const DX_MAX_LEVELS_LARGEDIR: u8 = 3;
+    /// a handle on `inode`, failing when its inline data cannot be read or its block map is
+    /// damaged
+    pub fn new(inode_num: u64, inode: Inode, name: String, fs: &'a mut FileSystem) -> Result<Ext4File<'a>, Error> {
+        let content = Self::load_content(inode_num, &inode, fs)?;
+        Ok(Self {
             name,
             fs,
+            inode_num,
             inode,
-            blocks: inode.blocks().unwrap().data_blocks(),
+            content,
             pos: 0,
+        })
+    }
+    /* where the data of `inode` lives: inline, or behind its block map */
+    fn load_content(inode_num: u64, inode: &Inode, fs: &mut FileSystem) -> Result<Content, Error> {
+        match inode.has_inline_data() {
+            true => {
+                let raw = fs.read_inode_raw(inode_num)
+                    .ok_or_else(|| Error::IOError(format!("failed to read inode {}", inode_num)))?;
+                Ok(Content::Inline(inode.inline_data(&raw)))
+            }
+            false => {
+                let mut blocks = inode.block_map()
+                    .ok_or_else(|| Error::InvalidData(format!("bad block map in inode {}", inode_num)))?;
+                if fs.verify_csums() {
+                    blocks.set_csum_seed(inode.csum_seed(inode_num, fs.sb()));
+                }
+                Ok(Content::Blocks(blocks))
+            }
         }
     }
-    pub fn read_block(&mut self, index: usize) -> Result<Vec<u8>, ()> {
-        self.fs.read_block(self.blocks[index])
+    /// run `op` on the block map; a tree block failing its checksum goes through the mount's
+    /// policy and, when let through, is trusted and `op` runs again
+    fn with_blocks<T>(&mut self, op: impl Fn(&mut BlockMap, &dyn BlockDevice, u64) -> Result<T, Error>) -> Result<T, Error> {
+        let block_size = self.fs.sb().block_size();
+        let blocks = match &mut self.content {
+            Content::Blocks(blocks) => blocks,
+            Content::Inline(_) => return Err(Error::InvalidInput(format!("{} has inline data", self.name))),
+        };
+        loop {
+            match op(blocks, &self.fs.disk, block_size) {
+                Err(Error::Checksum(kind, block)) => {
+                    self.fs.check_csum(false, kind, block)?;
+                    blocks.trust_block(block);
+                }
+                result => return result,
+            }
+        }
     }
-    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+    pub fn read_block(&mut self, index: u64) -> Result<Vec<u8>, Error> {
         let block_size = self.fs.sb().block_size();
-        assert!(buffer.len() <= block_size as usize);
-        let block_start_index = (self.pos / block_size) as usize;
-        let block_offset = self.pos % block_size;
-        let buffer_len = buffer.len() as u64;
-        let n_blocks = buffer_len / block_size;
-        let buffer_offset = buffer_len % block_size;
-        let n_blocks = match buffer_offset.eq(&0) {
-            true => n_blocks,
-            false => n_blocks + 1,
-        } as usize;
-        let mut buffer = buffer;
-        let mut write_bytes = 0;
-        let block_end_index = block_start_index + n_blocks;
-        for blk_index in block_start_index..block_end_index {
-            let data = self.read_block(blk_index)?;
-            let mut buf = data.as_slice();
-            if blk_index == block_start_index {
-                buf = buf[block_offset as usize..].as_slice()
-            }
-            if blk_index == block_end_index - 1 && buffer_offset != 0 {
-                buf = buf[..buffer_offset as usize].as_slice()
+        if let Content::Inline(data) = &self.content {
+            let mut block = vec![0u8; block_size as usize];
+            if index == 0 {
+                let n = data.len().min(block.len());
+                block[..n].copy_from_slice(&data[..n]);
             }
-
-            let mut n = buffer.write_bytes(buf).unwrap() as u64;
-            if self.pos + n >= self.size() {
-                n = n - (self.pos + n - self.size())
+            return Ok(block);
+        }
+        match self.with_blocks(|blocks, f, block_size| blocks.map_block(f, block_size, index))? {
+            Some(block_num) => self.fs.read_block(block_num),
+            None => Ok(vec![0u8; block_size as usize]),
+        }
+    }
+    /// read block `index` of a directory and check it as a leaf, or as an htree root or node
+    /// when the directory is indexed
+    fn read_dir_block(&mut self, index: u64) -> Result<Vec<u8>, Error> {
+        let data = self.read_block(index)?;
+        if !self.fs.verify_csums() || matches!(self.content, Content::Inline(_)) {
+            return Ok(data);
+        }
+        let seed = self.inode.csum_seed(self.inode_num, self.fs.sb());
+        let (valid, kind) = match self.is_dx_block(index, &data) {
+            true => (dx_checksum_valid(&data, seed), MetadataKind::DxNode),
+            false => (DirEntryTail::checksum_valid(&data, seed), MetadataKind::DirBlock),
+        };
+        if !valid {
+            let block = self.with_blocks(|blocks, f, block_size| blocks.map_block(f, block_size, index))?;
+            self.fs.check_csum(false, kind, block.unwrap_or(0))?;
+        }
+        Ok(data)
+    }
+    /* whether block `index` of this directory, holding `data`, is an htree root or node */
+    fn is_dx_block(&self, index: u64, data: &[u8]) -> bool {
+        self.inode.flags().contains(IFlags::Index) && (index == 0 || dx_node_entries(data).is_some())
+    }
+    #[inline]
+    fn blocks_count(&self) -> u64 {
+        let block_size = self.fs.sb().block_size();
+        align_up!(self.size(), block_size) / block_size
+    }
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let block_size = self.fs.sb().block_size();
+        let mut read_bytes = 0usize;
+        while read_bytes < buffer.len() && !self.is_eof() {
+            let block_offset = (self.pos % block_size) as usize;
+            let data = self.read_block(self.pos / block_size)?;
+            let n = (data.len() - block_offset)
+                .min(buffer.len() - read_bytes)
+                .min((self.size() - self.pos) as usize);
+            buffer[read_bytes..read_bytes + n].copy_from_slice(&data[block_offset..block_offset + n]);
+            self.pos += n as u64;
+            read_bytes += n;
+        }
+        Ok(read_bytes)
+    }
+    /* seed of the checksums of blocks this file owns, `None` without metadata_csum */
+    fn write_csum_seed(&self) -> Option<u32> {
+        match self.fs.sb().has_metadata_csum() {
+            true => Some(self.inode.csum_seed(self.inode_num, self.fs.sb())),
+            false => None,
+        }
+    }
+    /* only files mapped by an extent tree can grow, inline data is moved to one first */
+    fn make_writable(&mut self) -> Result<(), Error> {
+        self.convert_inline()?;
+        match &self.content {
+            Content::Blocks(BlockMap::Extents(_)) => Ok(()),
+            _ => Err(Error::InvalidInput(format!("{} is not extent mapped", self.name))),
+        }
+    }
+    /* `move_inline` in a transaction of its own, unless one is running */
+    fn convert_inline(&mut self) -> Result<(), Error> {
+        if let Content::Blocks(_) = self.content {
+            return Ok(());
+        }
+        self.fs.start()?;
+        let result = self.move_inline();
+        let result = self.fs.stop(result);
+        if result.is_err() {
+            self.reload();
+        }
+        result
+    }
+    /* move inline data to a block under an extent tree and drop its system.data xattr, as
+     * ext4_convert_inline_data; the records of a directory go behind `.` and `..` */
+    fn move_inline(&mut self) -> Result<(), Error> {
+        let Content::Inline(data) = &self.content else {
+            return Ok(());
+        };
+        if !self.fs.sb().feature_in_compat().contains(FeatureInCompat::Extents) {
+            return Err(Error::InvalidInput(format!("{} has inline data and there are no extents to move it to", self.name)));
+        }
+        let block_size = self.fs.sb().block_size() as usize;
+        let block = match self.mode().is_dir() {
+            true => {
+                let parent = u32::from_le_bytes(data[..4].try_into().unwrap());
+                let file_type = self.dir_file_type(Mode::DIRECTORY);
+                let mut records = vec![DirEntry::record(self.inode_num as u32, b".", file_type), DirEntry::record(parent, b"..", file_type)];
+                records.extend(DirEntry::raw_entries(&data[4..Inode::INLINE_DATA_SIZE], Inode::INLINE_DATA_SIZE - 4));
+                records.extend(DirEntry::raw_entries(&data[Inode::INLINE_DATA_SIZE..], data.len() - Inode::INLINE_DATA_SIZE));
+                Some(DirEntry::pack(&records, block_size, self.dir_block_end()))
+            }
+            false => {
+                let size = (self.size() as usize).min(data.len());
+                (size > 0).then(|| {
+                    let mut block = vec![0u8; block_size];
+                    block[..size].copy_from_slice(&data[..size]);
+                    block
+                })
+            }
+        };
+        let mut raw = self.fs.read_inode_raw(self.inode_num)
+            .ok_or_else(|| Error::IOError(format!("failed to read inode {}", self.inode_num)))?;
+        XattrEntry::remove_ibody_entry(&mut raw, self.inode.extra_isize(), XattrEntry::INDEX_SYSTEM, b"data");
+        self.inode.set_flags((self.inode.flags() - IFlags::InlineData) | IFlags::Extents);
+        self.inode.set_block(ExtentTree::empty().root_bytes());
+        self.content = Self::load_content(self.inode_num, &self.inode, self.fs)?;
+        if let Some(block) = block {
+            let (block_num, _) = self.alloc_blocks(0, 1)?;
+            match self.mode().is_dir() {
+                true => {
+                    self.inode.set_size(block_size as u64);
+                    self.write_dir_block(0, block)?;
+                }
+                false => self.write_data(block_num, &block)?,
+            }
+        }
+        self.fs.store_inode(self.inode_num, &mut self.inode, raw)
+    }
+    /* back `count` logical blocks from `index` on with new blocks, or as many as the allocator
+     * has in one piece; returns the first new block and how many there are */
+    fn alloc_blocks(&mut self, index: u64, count: u64) -> Result<(u64, u64), Error> {
+        let logical = u32::try_from(index)
+            .map_err(|_| Error::InvalidInput(format!("block {} of {} out of range", index, self.name)))?;
+        let prev = match index {
+            0 => None,
+            _ => self.with_blocks(|blocks, f, block_size| blocks.map_extent(f, block_size, index - 1))?.map(|(block, _, _)| block),
+        };
+        /* right after the previous block keeps the file contiguous */
+        let goal = prev.map_or_else(|| self.fs.block_goal(self.inode_num, self.mode()), |block| block + 1);
+        let (start, len) = self.fs.alloc_blocks(goal, count.min(ExtentTree::MAX_LEN))?;
+        let seed = self.write_csum_seed();
+        let Content::Blocks(BlockMap::Extents(tree)) = &mut self.content else {
+            return Err(Error::InvalidInput(format!("{} is not extent mapped", self.name)));
+        };
+        let tree_blocks = match tree.insert_extent(self.fs, logical, start, len, seed) {
+            Ok(tree_blocks) => tree_blocks,
+            Err(e) => {
+                self.fs.free_blocks(start, len)?;
+                return Err(e);
+            }
+        };
+        self.inode.set_block(tree.root_bytes());
+        let sectors = self.fs.sb().block_size() / 512;
+        self.inode.set_blocks_count(self.inode.blocks_count() + (len + tree_blocks) * sectors);
+        Ok((start, len))
+    }
+    /* mark the `len` unwritten blocks from `index` on written, now that they hold data */
+    fn mark_written(&mut self, index: u64, len: u64) -> Result<(), Error> {
+        let seed = self.write_csum_seed();
+        let Content::Blocks(BlockMap::Extents(tree)) = &mut self.content else {
+            return Err(Error::InvalidInput(format!("{} is not extent mapped", self.name)));
+        };
+        let tree_blocks = tree.mark_written(self.fs, index as u32, len, seed)?;
+        self.inode.set_block(tree.root_bytes());
+        let sectors = self.fs.sb().block_size() / 512;
+        self.inode.set_blocks_count(self.inode.blocks_count() + tree_blocks * sectors);
+        Ok(())
+    }
+    /* write `buf` from the current position on, allocating what is not mapped yet; unwritten
+     * blocks are written in place and marked written once they hold the data */
+    fn write_blocks(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let block_size = self.fs.sb().block_size();
+        let mut written = 0usize;
+        /* blocks holding nothing worth keeping, just allocated or unwritten: the logical blocks,
+         * the first of their physical blocks and whether they are unwritten */
+        let mut fresh: Option<(Range<u64>, u64, bool)> = None;
+        while written < buf.len() {
+            let index = self.pos / block_size;
+            let offset = (self.pos % block_size) as usize;
+            let n = (block_size as usize - offset).min(buf.len() - written);
+            let block_num = match &fresh {
+                Some((range, start, _)) if range.contains(&index) => start + index - range.start,
+                _ => {
+                    if let Some((range, _, true)) = fresh.take() {
+                        self.mark_written(range.start, range.end - range.start)?;
+                    }
+                    /* everything up to the end of `buf` */
+                    let end = (self.pos + (buf.len() - written) as u64).div_ceil(block_size);
+                    match self.with_blocks(|blocks, f, block_size| blocks.map_extent(f, block_size, index))? {
+                        Some((block_num, _, false)) => block_num,
+                        Some((block_num, left, true)) => {
+                            fresh = Some((index..index + left.min(end - index), block_num, true));
+                            block_num
+                        }
+                        None => {
+                            /* but not into what is mapped after the hole */
+                            let end = self.with_blocks(|blocks, f, block_size| blocks.next_mapped(f, block_size, index, end))?.unwrap_or(end);
+                            let (start, len) = self.alloc_blocks(index, end - index)?;
+                            fresh = Some((index..index + len, start, false));
+                            start
+                        }
+                    }
+                }
+            };
+            /* the rest of a fresh block is zeroed, as Linux does for an unwritten one */
+            let mut data = match fresh.as_ref().is_some_and(|(range, _, _)| range.contains(&index)) || n == block_size as usize {
+                true => vec![0u8; block_size as usize],
+                false => self.fs.read_block(block_num)?,
+            };
+            data[offset..offset + n].copy_from_slice(&buf[written..written + n]);
+            self.write_data(block_num, &data)?;
+            self.pos += n as u64;
+            written += n;
+        }
+        if let Some((range, _, true)) = fresh {
+            self.mark_written(range.start, range.end - range.start)?;
+        }
+        Ok(written)
+    }
+    /// write `buf` at the current position, filling holes and growing the file past its end;
+    /// the inode, with its new size, blocks and times, is written back before returning. An
+    /// error leaves the file as it was, unless part of `buf` is on the device already: written
+    /// without a journal, or in an earlier transaction of a large journaled write. That part
+    /// is then returned as a short write
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.mode().is_dir() {
+            return Err(Error::InvalidInput(format!("{} is a directory", self.name)));
+        }
+        self.make_writable()?;
+        /* logged data the journal cannot take at once goes in several transactions */
+        let chunk = match self.fs.data_credits(&self.inode) {
+            Some(blocks) => (blocks * self.fs.sb().block_size()) as usize,
+            None => buf.len(),
+        };
+        let mut written = 0;
+        loop {
+            let n = (buf.len() - written).min(chunk);
+            match self.write_transaction(&buf[written..written + n]) {
+                Ok(done) => {
+                    written += done;
+                    if written == buf.len() || done < n {
+                        return Ok(written);
+                    }
+                }
+                Err(_) if written > 0 => return Ok(written),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /* `write` of as much as one transaction takes: all of it, or after an error nothing with a
+     * journal and without one what reached the device, the inode accounting for it */
+    fn write_transaction(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let start = self.pos;
+        self.fs.start()?;
+        let result = self.write_blocks(buf);
+        if self.pos > self.size() {
+            self.inode.set_size(self.pos);
+        }
+        let now = self.fs.now();
+        self.inode.set_mtime(now);
+        self.inode.set_ctime(now);
+        let stored = self.fs.write_inode(self.inode_num, &mut self.inode);
+        let result = match (result, stored) {
+            (_, Err(e)) => Err(e),
+            (Ok(n), Ok(())) => Ok(n),
+            (Err(_), Ok(())) if !self.fs.is_journaled() && self.pos > start => Ok((self.pos - start) as usize),
+            (Err(e), Ok(())) => Err(e),
+        };
+        match self.fs.stop(result) {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                self.pos = start;
+                self.reload();
+                Err(e)
+            }
+        }
+    }
+    /// write `buf` at the end of the file, like a file opened with O_APPEND
+    pub fn append(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.pos = self.size();
+        self.write(buf)
+    }
+    /// change the size to `len` like ftruncate: growing leaves a hole, shrinking frees the
+    /// blocks past the new end and zeroes the rest of the new last block
+    pub fn set_len(&mut self, len: u64) -> Result<(), Error> {
+        if self.mode().is_dir() {
+            return Err(Error::InvalidInput(format!("{} is a directory", self.name)));
+        }
+        self.make_writable()?;
+        self.fs.start()?;
+        let result = self.resize(len);
+        let result = self.fs.stop(result);
+        if result.is_err() {
+            self.reload();
+        }
+        result
+    }
+    /* after a failed transaction the inode and its block map are whatever the device holds,
+     * which may not be what this handle changed them to */
+    fn reload(&mut self) {
+        if self.fs.in_transaction() {
+            return;
+        }
+        let reloaded = self.fs.read_inode(self.inode_num)
+            .and_then(|inode| Ok((Self::load_content(self.inode_num, &inode, self.fs)?, inode)));
+        match reloaded {
+            Ok((content, inode)) => {
+                self.content = content;
+                self.inode = inode;
+            }
+            Err(e) => self.fs.rollback_failed(e),
+        }
+    }
+    fn resize(&mut self, len: u64) -> Result<(), Error> {
+        let block_size = self.fs.sb().block_size();
+        if len < self.size() {
+            /* what lies past the end must read as zeros should the file grow again */
+            let offset = (len % block_size) as usize;
+            if offset != 0 {
+                let index = len / block_size;
+                if let Some(block_num) = self.with_blocks(|blocks, f, block_size| blocks.map_block(f, block_size, index))? {
+                    let mut data = self.fs.read_block(block_num)?;
+                    data[offset..].fill(0);
+                    self.write_data(block_num, &data)?;
+                }
+            }
+            let seed = self.write_csum_seed();
+            let Content::Blocks(BlockMap::Extents(tree)) = &mut self.content else {
+                return Err(Error::InvalidInput(format!("{} is not extent mapped", self.name)));
+            };
+            let result = tree.truncate(self.fs, len.div_ceil(block_size), seed);
+            self.inode.set_block(tree.root_bytes());
+            if let Ok(freed) = result {
+                let sectors = block_size / 512;
+                self.inode.set_blocks_count(self.inode.blocks_count().saturating_sub(freed * sectors));
+            }
+            result?;
+        }
+        self.inode.set_size(len);
+        let now = self.fs.now();
+        self.inode.set_mtime(now);
+        self.inode.set_ctime(now);
+        self.fs.write_inode(self.inode_num, &mut self.inode)
+    }
+    /// make what was written durable on the device
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.fs.flush()
+    }
+    /// like lseek(SEEK_DATA): move to the first data at or after `offset`, `None` past the last data
+    pub fn seek_data(&mut self, offset: u64) -> Result<Option<u64>, Error> {
+        if offset >= self.size() {
+            return Ok(None);
+        }
+        if let Content::Inline(_) = self.content {
+            self.pos = offset;
+            return Ok(Some(offset));
+        }
+        let block_size = self.fs.sb().block_size();
+        let end = self.blocks_count();
+        match self.with_blocks(|blocks, f, block_size| blocks.next_data(f, block_size, offset / block_size, end))? {
+            Some(block) => {
+                self.pos = offset.max(block * block_size);
+                Ok(Some(self.pos))
             }
-            self.pos += n;
-            write_bytes += n;
+            None => Ok(None),
         }
-        Ok(write_bytes as usize)
+    }
+    /// like lseek(SEEK_HOLE): move to the first hole at or after `offset`, the end of file counts as one
+    pub fn seek_hole(&mut self, offset: u64) -> Result<Option<u64>, Error> {
+        if offset >= self.size() {
+            return Ok(None);
+        }
+        if let Content::Inline(_) = self.content {
+            self.pos = self.size();
+            return Ok(Some(self.pos));
+        }
+        let block_size = self.fs.sb().block_size();
+        let end = self.blocks_count();
+        let block = self.with_blocks(|blocks, f, block_size| blocks.next_hole(f, block_size, offset / block_size, end))?;
+        self.pos = offset.max(block * block_size).min(self.size());
+        Ok(Some(self.pos))
+    }
+    #[inline]
+    pub fn seek(&mut self, pos: u64) {
+        self.pos = pos
+    }
+    #[inline]
+    pub fn pos(&self) -> u64 {
+        self.pos
     }
     #[inline]
     pub fn is_eof(&self) -> bool {
@@ -74,25 +493,737 @@ impl<'a> Ext4File<'a> {
     pub fn size(&self) -> u64 {
         self.inode.size()
     }
-    pub fn read_dir(&mut self) -> Result<Vec<Entry>, ()> {
+    /// target of a symbolic link, fast (in `i_block`) or stored like file data
+    pub fn read_link(&mut self) -> Result<String, Error> {
+        if !self.mode().is_symlink() {
+            return Err(Error::InvalidInput(format!("{} is not a symlink", self.name)));
+        }
+        if let Some(target) = self.inode.fast_symlink(self.fs.sb().block_size()) {
+            return Ok(String::from_utf8_lossy(target).to_string());
+        }
+        let mut target = vec![0u8; self.size() as usize];
+        self.pos = 0;
+        let n = self.read(&mut target)?;
+        target.truncate(n);
+        Ok(String::from_utf8_lossy(&target).to_string())
+    }
+    /// open `path` relative to this directory, absolute paths still start at the root
+    pub fn open(&mut self, path: &str) -> Result<Ext4File<'_>, Error> {
+        let (inode_num, inode, name) = self.fs.resolve((self.inode_num, self.inode, &self.name), path, true)?;
+        Ext4File::new(inode_num, inode, name, self.fs)
+    }
+    /// like `open`, but a symlink at the end of `path` is opened itself
+    pub fn open_nofollow(&mut self, path: &str) -> Result<Ext4File<'_>, Error> {
+        let (inode_num, inode, name) = self.fs.resolve((self.inode_num, self.inode, &self.name), path, false)?;
+        Ext4File::new(inode_num, inode, name, self.fs)
+    }
+    /// extended attributes from the inode body and the external xattr block, values filled in
+    pub fn xattrs(&mut self) -> Result<Vec<XattrEntry>, Error> {
+        let mut xattrs = Vec::new();
+        if self.fs.sb().inode_size() > Inode::GOOD_OLD_SIZE {
+            let raw = self.fs.read_inode_raw(self.inode_num)
+                .ok_or_else(|| Error::IOError(format!("failed to read inode {}", self.inode_num)))?;
+            xattrs.extend(XattrEntry::ibody_entries(&raw, self.inode.extra_isize()));
+        }
+        if let Some((_, entries)) = self.xattr_block()? {
+            xattrs.extend(entries);
+        }
+        for xattr in xattrs.iter_mut().filter(|x| x.value_inum() != 0) {
+            if !self.fs.sb().feature_in_compat().contains(FeatureInCompat::EAInode) {
+                return Err(Error::InvalidData(format!("xattr {} in an inode without EA_INODE", xattr.full_name())));
+            }
+            let inode = self.fs.read_inode(xattr.value_inum())?;
+            let mut value = vec![0u8; xattr.value_size() as usize];
+            let n = Ext4File::new(xattr.value_inum(), inode, xattr.full_name(), self.fs)?.read(&mut value)?;
+            value.truncate(n);
+            xattr.set_value(value);
+        }
+        Ok(xattrs)
+    }
+    fn xattr_block(&mut self) -> Result<Option<(XattrBlockHeader, Vec<XattrEntry>)>, Error> {
+        let acl_block = self.inode.file_acl();
+        if acl_block == 0 {
+            return Ok(None);
+        }
+        let block = self.fs.read_block(acl_block)?;
+        XattrBlockHeader::from_bytes(&block)
+            .map(Some)
+            .ok_or_else(|| Error::InvalidData(format!("bad xattr block {}", acl_block)))
+    }
+    /// how many inodes share this file's external xattr block, 0 without one
+    pub fn xattr_refcount(&mut self) -> Result<u32, Error> {
+        Ok(self.xattr_block()?.map_or(0, |(header, _)| header.refcount()))
+    }
+    /// names of the extended attributes, like listxattr
+    pub fn list_xattrs(&mut self) -> Result<Vec<String>, Error> {
+        Ok(self.xattrs()?
+            .iter()
+            /* system.data only holds the rest of the inline data, Linux does not list it either */
+            .filter(|x| !x.is_inline_data())
+            .map(|x| x.full_name())
+            .collect())
+    }
+    /// value of the extended attribute `name`, e.g. `security.selinux`
+    pub fn get_xattr(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        let (index, suffix) = XattrEntry::split_name(name)
+            .ok_or_else(|| Error::InvalidInput(format!("unknown xattr prefix in {}", name)))?;
+        self.xattrs()?
+            .into_iter()
+            .find(|x| x.name_index() == index && x.name() == suffix.as_bytes())
+            .map(|x| x.value().to_vec())
+            .ok_or_else(|| Error::NotFound(name.to_string()))
+    }
+    fn posix_acl(&mut self, name: &str) -> Result<Option<Acl>, Error> {
+        match self.get_xattr(name) {
+            Ok(value) => Acl::from_bytes(&value)
+                .map(Some)
+                .ok_or_else(|| Error::InvalidData(format!("bad {} on inode {}", name, self.inode_num))),
+            Err(Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    /// the access ACL, `None` when permissions come from the mode alone
+    pub fn acl(&mut self) -> Result<Option<Acl>, Error> {
+        self.posix_acl("system.posix_acl_access")
+    }
+    /// the ACL new entries of this directory inherit
+    pub fn default_acl(&mut self) -> Result<Option<Acl>, Error> {
+        self.posix_acl("system.posix_acl_default")
+    }
+    /// may `uid` with groups `gids` do `want` (`Acl::READ | Acl::WRITE | Acl::EXECUTE`), deciding
+    /// like Linux does: root passes except for executing a file nobody may execute, an ACL
+    /// is used when the caller is not the owner, otherwise the mode bits
+    pub fn access(&mut self, uid: u32, gids: &[u32], want: u16) -> Result<bool, Error> {
+        let mode = self.mode();
+        if uid == 0 {
+            return Ok(want & Acl::EXECUTE == 0 || mode.is_dir() || mode.perm() & 0o111 != 0);
+        }
+        let (owner, group) = (self.inode.uid(), self.inode.gid());
+        if owner != uid {
+            if let Some(acl) = self.acl()? {
+                return Ok(acl.permits(owner, group, uid, gids, want));
+            }
+        }
+        Ok(mode_permits(mode, owner, group, uid, gids, want))
+    }
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.inode.uid()
+    }
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.inode.gid()
+    }
+    #[inline]
+    pub fn inode_num(&self) -> u64 {
+        self.inode_num
+    }
+    pub fn read_dir(&mut self) -> Result<Vec<Entry>, Error> {
         let mut entrys = Vec::new();
-        for index in 0..self.blocks.len() {
-            let data = self.read_block(index)?;
-            let buffer = data.as_slice();
-            let mut offset = 0usize;
-            while offset < buffer.len() {
-                let entry = DirEntry::from_bytes(buffer[offset..].as_slice());
-                match entry {
-                    None => break,
-                    Some((entry, name)) => {
-                        offset += entry.len();
-                        let i_num = entry.inode;
-                        let inode = self.fs.read_inode(i_num as u64);
-                        entrys.push(Entry::new(entry, name, inode));
-                    }
-                }
+        if let Content::Inline(data) = &self.content {
+            /* an inline directory starts with the parent inode number instead of `.` and `..` */
+            let parent = u32::from_le_bytes(data[..4].try_into().unwrap());
+            let mut entries = vec![
+                (DirEntry::synthetic(self.inode_num as u32, "."), ".".to_string()),
+                (DirEntry::synthetic(parent, ".."), "..".to_string()),
+            ];
+            entries.extend(DirEntry::entries(&data[4..Inode::INLINE_DATA_SIZE]));
+            entries.extend(DirEntry::entries(&data[Inode::INLINE_DATA_SIZE..]));
+            for (entry, name) in entries {
+                let inode = self.fs.read_inode(entry.inode as u64)?;
+                entrys.push(Entry::new(entry, name, inode));
+            }
+            return Ok(entrys);
+        }
+        for index in 0..self.blocks_count() {
+            let data = self.read_dir_block(index)?;
+            for (entry, name) in DirEntry::entries(&data) {
+                let inode = self.fs.read_inode(entry.inode as u64)?;
+                entrys.push(Entry::new(entry, name, inode));
             }
         }
         Ok(entrys)
     }
+    /// find `name` in this directory, through the hash tree when the directory has one
+    pub fn lookup(&mut self, name: &str) -> Result<Option<Entry>, Error> {
+        if !self.mode().is_dir() {
+            return Err(Error::NotADirectory(self.name.clone()));
+        }
+        let indexed = self.inode.flags().contains(IFlags::Index) && matches!(self.content, Content::Blocks(_));
+        let found = match indexed {
+            /* `.` and `..` head the root block, which no leaf holds */
+            true if name == "." || name == ".." => {
+                let data = self.read_dir_block(0)?;
+                DirEntry::entries(&data).into_iter().find(|(_, n)| n == name)
+            }
+            /* a damaged tree still leaves the leaves readable as a linear directory */
+            true => match self.dx_lookup(name) {
+                Ok(found) => found,
+                Err(e @ Error::Checksum(..)) => return Err(e),
+                Err(_) => self.linear_lookup(name)?,
+            },
+            false => self.linear_lookup(name)?,
+        };
+        match found {
+            Some((entry, name)) => {
+                let inode = self.fs.read_inode(entry.inode as u64)?;
+                Ok(Some(Entry::new(entry, name, inode)))
+            }
+            None => Ok(None),
+        }
+    }
+    /* write file data to `block_num`, through the journal when data is journaled */
+    fn write_data(&mut self, block_num: u64, data: &[u8]) -> Result<(), Error> {
+        match self.fs.journals_data(&self.inode) {
+            true => self.fs.write_meta_block(block_num, data),
+            false => self.fs.write_block(block_num, data),
+        }
+    }
+    /* checksum a directory block, as a leaf or as an htree root or node, and write it over
+     * logical block `index` */
+    fn write_dir_block(&mut self, index: u64, mut data: Vec<u8>) -> Result<(), Error> {
+        if let Some(seed) = self.write_csum_seed() {
+            match self.is_dx_block(index, &data) {
+                true => dx_set_checksum(&mut data, seed),
+                false => DirEntryTail::write(&mut data, seed),
+            }
+        }
+        let block_num = self.with_blocks(|blocks, f, block_size| blocks.map_block(f, block_size, index))?
+            .ok_or_else(|| Error::InvalidData(format!("block {} of {} is a hole", index, self.name)))?;
+        self.fs.write_meta_block(block_num, &data)
+    }
+    /* where the records of a leaf block end, before the checksum tail if there is one */
+    fn dir_block_end(&self) -> usize {
+        let block_size = self.fs.sb().block_size() as usize;
+        match self.fs.sb().has_metadata_csum() {
+            true => block_size - DirEntryTail::SIZE,
+            false => block_size,
+        }
+    }
+    /* the file type a record for `mode` carries, 0 without the filetype feature */
+    fn dir_file_type(&self, mode: Mode) -> u8 {
+        match self.fs.feature_file_type() {
+            true => mode.dir_file_type(),
+            false => 0,
+        }
+    }
+    /// give a new directory its first block, holding `.` and `..` linked to `parent`
+    pub(crate) fn init_dir(&mut self, parent: u64) -> Result<(), Error> {
+        self.make_writable()?;
+        let block_size = self.fs.sb().block_size();
+        let end = self.dir_block_end();
+        let file_type = self.dir_file_type(Mode::DIRECTORY);
+        let dot = DirEntry::rec_len_for(1);
+        let mut data = vec![0u8; block_size as usize];
+        DirEntry::write(&mut data, 0, self.inode_num as u32, dot, b".", file_type);
+        DirEntry::write(&mut data, dot, parent as u32, end - dot, b"..", file_type);
+        self.alloc_blocks(0, 1)?;
+        self.inode.set_size(block_size);
+        self.write_dir_block(0, data)?;
+        self.fs.write_inode(self.inode_num, &mut self.inode)
+    }
+    /// link `name` to `inode_num` in this directory: in the leaf its hash belongs to when the
+    /// directory is indexed, or else in the first block with room. A full single block turns
+    /// into an index with dir_index, more linear blocks are added at the end otherwise
+    pub(crate) fn add_entry(&mut self, name: &str, inode_num: u64, mode: Mode) -> Result<(), Error> {
+        if !self.mode().is_dir() {
+            return Err(Error::NotADirectory(self.name.clone()));
+        }
+        self.make_writable()?;
+        let file_type = self.dir_file_type(mode);
+        if !self.inode.flags().contains(IFlags::Index) && !self.linear_add_entry(name, inode_num, file_type)? {
+            self.make_indexed()?;
+        }
+        if self.inode.flags().contains(IFlags::Index) {
+            self.dx_add_entry(name, inode_num, file_type)?;
+        }
+        let now = self.fs.now();
+        self.inode.set_mtime(now);
+        self.inode.set_ctime(now);
+        self.fs.write_inode(self.inode_num, &mut self.inode)
+    }
+    /* add a record to the first block with room or a new one at the end; false, with nothing
+     * written, when the only block is full and the directory should be indexed instead */
+    fn linear_add_entry(&mut self, name: &str, inode_num: u64, file_type: u8) -> Result<bool, Error> {
+        let block_size = self.fs.sb().block_size();
+        let end = self.dir_block_end();
+        for index in 0..self.blocks_count() {
+            let mut data = self.read_dir_block(index)?;
+            if DirEntry::insert(&mut data, end, inode_num as u32, name, file_type) {
+                self.write_dir_block(index, data)?;
+                return Ok(true);
+            }
+        }
+        let index = self.blocks_count();
+        if index == 1 && self.fs.sb().feature_compat().contains(FeatureCompat::DirIndex) {
+            return Ok(false);
+        }
+        let mut data = vec![0u8; block_size as usize];
+        DirEntry::write(&mut data, 0, inode_num as u32, end, name.as_bytes(), file_type);
+        self.alloc_blocks(index, 1)?;
+        self.inode.set_size((index + 1) * block_size);
+        self.write_dir_block(index, data)?;
+        Ok(true)
+    }
+    /* turn this single block directory into an indexed one, as make_indexed_dir: its records
+     * move to a new leaf and block 0 becomes the root indexing it */
+    fn make_indexed(&mut self) -> Result<(), Error> {
+        let block_size = self.fs.sb().block_size() as usize;
+        let csum = self.fs.sb().has_metadata_csum();
+        let end = self.dir_block_end();
+        let data = self.read_dir_block(0)?;
+        let (dots, records): (Vec<_>, Vec<_>) = DirEntry::raw_entries(&data, end)
+            .into_iter()
+            .partition(|(_, name)| name == b"." || name == b"..");
+        let parent = dots.iter()
+            .find(|(_, name)| name == b"..")
+            .map(|(entry, _)| entry.inode)
+            .ok_or_else(|| Error::InvalidData(format!("{} has no ..", self.name)))?;
+        self.alloc_blocks(1, 1)?;
+        self.inode.set_size(2 * block_size as u64);
+        self.inode.set_flags(self.inode.flags() | IFlags::Index);
+        self.write_dir_block(1, DirEntry::pack(&records, block_size, end))?;
+        let file_type = self.dir_file_type(Mode::DIRECTORY);
+        let dot = DirEntry::rec_len_for(1);
+        let mut root = vec![0u8; block_size];
+        DirEntry::write(&mut root, 0, self.inode_num as u32, dot, b".", file_type);
+        DirEntry::write(&mut root, dot, parent, block_size - dot, b"..", file_type);
+        DxRootInfo::new(self.fs.sb().def_hash_version() as u8).write(&mut root, &[DxEntry { hash: 0, block: 1 }], csum);
+        self.write_dir_block(0, root)
+    }
+    /* add a record to the leaf of an indexed directory the hash of `name` belongs to; a full
+     * leaf is split by hash as do_split does, the upper half going to a new block indexed
+     * right after it */
+    fn dx_add_entry(&mut self, name: &str, inode_num: u64, file_type: u8) -> Result<(), Error> {
+        let block_size = self.fs.sb().block_size() as usize;
+        let end = self.dir_block_end();
+        let (hash, mut info, mut path) = self.dx_path(name)?;
+        let leaf = path.last().map(|(entries, pos)| entries[*pos].block as u64).unwrap();
+        let mut data = self.read_dir_block(leaf)?;
+        if DirEntry::insert(&mut data, end, inode_num as u32, name, file_type) {
+            return self.write_dir_block(leaf, data);
+        }
+        /* a split goes up as far as the nodes are full; a full root can only add a level while
+         * below the depth the filesystem allows */
+        let max_levels = match self.fs.sb().feature_in_compat().contains(FeatureInCompat::LargeDir) {
+            true => Self::DX_MAX_LEVELS_LARGEDIR,
+            false => Self::DX_MAX_LEVELS,
+        };
+        let full = path.iter().enumerate().all(|(level, (entries, _))| entries.len() >= self.dx_limit(level));
+        if full && info.indirect_levels() + 1 >= max_levels {
+            return Err(Error::NoSpace(format!("htree of {} is full", self.name)));
+        }
+        let mut records = DirEntry::raw_entries(&data, end)
+            .into_iter()
+            .map(|(entry, name)| Ok((self.dx_hash(&info, &name)?, (entry, name))))
+            .collect::<Result<Vec<_>, Error>>()?;
+        if records.len() < 2 {
+            return Err(Error::NoSpace(format!("no room for {} in {}", name, self.name)));
+        }
+        records.sort_by_key(|(hash, _)| *hash);
+        /* half of the bytes move, the hash of the first of them keys the new leaf, with bit 0
+         * set when the names of that hash continue from the old one */
+        let total: usize = records.iter().map(|(_, (_, name))| DirEntry::rec_len_for(name.len())).sum();
+        let mut size = 0;
+        let split = records.iter()
+            .position(|(_, (_, name))| {
+                size += DirEntry::rec_len_for(name.len());
+                size > total / 2
+            })
+            .unwrap_or(0)
+            .clamp(1, records.len() - 1);
+        let split_hash = records[split].0;
+        let continued = split_hash == records[split - 1].0;
+        let upper = records.split_off(split).into_iter().map(|(_, record)| record).collect::<Vec<_>>();
+        let lower = records.into_iter().map(|(_, record)| record).collect::<Vec<_>>();
+        let (mut lower, mut upper) = (DirEntry::pack(&lower, block_size, end), DirEntry::pack(&upper, block_size, end));
+        let target = match hash >= split_hash {
+            true => &mut upper,
+            false => &mut lower,
+        };
+        if !DirEntry::insert(target, end, inode_num as u32, name, file_type) {
+            return Err(Error::NoSpace(format!("no room for {} in {}", name, self.name)));
+        }
+        let new_leaf = self.blocks_count();
+        self.alloc_blocks(new_leaf, 1)?;
+        self.inode.set_size((new_leaf + 1) * block_size as u64);
+        self.write_dir_block(new_leaf, upper)?;
+        self.write_dir_block(leaf, lower)?;
+        let level = path.len() - 1;
+        self.dx_insert(&mut info, &mut path, level, split_hash | continued as u32, new_leaf as u32)
+    }
+    /* index `block`, whose names hash from `hash` on, right after the entry `path` took at
+     * `level`: a full node is split in two, the upper half indexed one level up in turn, and a
+     * full root hands its entries down to a new node, the tree growing by a level */
+    fn dx_insert(&mut self, info: &mut DxRootInfo, path: &mut DxPath, level: usize, hash: u32, block: u32) -> Result<(), Error> {
+        let block_size = self.fs.sb().block_size();
+        let node = match level {
+            0 => 0,
+            _ => path[level - 1].0[path[level - 1].1].block as u64,
+        };
+        let limit = self.dx_limit(level);
+        let (entries, pos) = &mut path[level];
+        entries.insert(*pos + 1, DxEntry { hash, block });
+        if entries.len() <= limit {
+            let entries = entries.clone();
+            return self.write_dx_node(node, level, info, &entries);
+        }
+        let new_node = self.blocks_count();
+        if level > 0 {
+            let upper = entries.split_off(entries.len() / 2);
+            let lower = entries.clone();
+            self.alloc_blocks(new_node, 1)?;
+            self.inode.set_size((new_node + 1) * block_size);
+            self.write_dx_node(new_node, level, info, &upper)?;
+            self.write_dx_node(node, level, info, &lower)?;
+            return self.dx_insert(info, path, level - 1, upper[0].hash, new_node as u32);
+        }
+        let moved = core::mem::take(entries);
+        self.alloc_blocks(new_node, 1)?;
+        self.inode.set_size((new_node + 1) * block_size);
+        self.write_dx_node(new_node, 1, info, &moved)?;
+        info.set_indirect_levels(info.indirect_levels() + 1);
+        self.write_dx_node(0, 0, info, &[DxEntry { hash: 0, block: new_node as u32 }])
+    }
+    /* write `entries` to the index block `index`, the root at `level` 0 and a node below it */
+    fn write_dx_node(&mut self, index: u64, level: usize, info: &DxRootInfo, entries: &[DxEntry]) -> Result<(), Error> {
+        let block_size = self.fs.sb().block_size() as usize;
+        let csum = self.fs.sb().has_metadata_csum();
+        let data = match level {
+            0 => {
+                let mut root = self.read_dir_block(0)?;
+                info.write(&mut root, entries, csum);
+                root
+            }
+            _ => dx_node_block(block_size, entries, csum),
+        };
+        self.write_dir_block(index, data)
+    }
+    /* index entries the root, `level` 0, or a node of this directory holds */
+    fn dx_limit(&self, level: usize) -> usize {
+        let block_size = self.fs.sb().block_size() as usize;
+        let csum = self.fs.sb().has_metadata_csum();
+        match level {
+            0 => DxRootInfo::limit(block_size, csum),
+            _ => dx_node_limit(block_size, csum),
+        }
+    }
+    /* the hash of `name` in this directory, indexed as `info` says */
+    fn dx_hash(&self, info: &DxRootInfo, name: &[u8]) -> Result<u32, Error> {
+        let version = self.fs.sb().dir_hash_version(info.hash_version()
+            .ok_or_else(|| Error::InvalidData(format!("unknown hash version in {}", self.name)))?);
+        Ok(dir_hash(version, name, &self.fs.sb().hash_seed()).0)
+    }
+    /* run `edit` on the block holding `name`, trying the leaf its hash points to first, then
+     * write the block back and touch the directory; returns what `edit` found */
+    fn edit_entry(&mut self, name: &str, edit: impl Fn(&mut [u8], usize) -> Option<u32>) -> Result<u64, Error> {
+        if !self.mode().is_dir() {
+            return Err(Error::NotADirectory(self.name.clone()));
+        }
+        /* blocks are only rewritten in place, whatever maps them */
+        self.convert_inline()?;
+        let indexed = self.inode.flags().contains(IFlags::Index);
+        let mut candidates = Vec::new();
+        if indexed && name != "." && name != ".." {
+            let (_, _, path) = self.dx_path(name)?;
+            let (entries, pos) = path.last().unwrap();
+            candidates.push(entries[*pos].block as u64);
+        }
+        candidates.extend(0..self.blocks_count());
+        for index in candidates {
+            let mut data = self.read_dir_block(index)?;
+            /* `..` of an htree root spans the rest of the block, over the dx entries */
+            let end = match indexed && index == 0 {
+                true => data.len(),
+                false => self.dir_block_end(),
+            };
+            if let Some(inode_num) = edit(&mut data, end) {
+                self.write_dir_block(index, data)?;
+                let now = self.fs.now();
+                self.inode.set_mtime(now);
+                self.inode.set_ctime(now);
+                self.fs.write_inode(self.inode_num, &mut self.inode)?;
+                return Ok(inode_num as u64);
+            }
+        }
+        Err(Error::NotFound(format!("{} in {}", name, self.name)))
+    }
+    /// unlink `name` from this directory, returning the inode it linked to
+    pub(crate) fn remove_entry(&mut self, name: &str) -> Result<u64, Error> {
+        self.edit_entry(name, |data, end| DirEntry::remove(data, end, name))
+    }
+    /// point the existing entry `name` at `inode_num` of type `mode` in place, returning the
+    /// inode it linked to before
+    pub(crate) fn set_entry(&mut self, name: &str, inode_num: u64, mode: Mode) -> Result<u64, Error> {
+        let file_type = self.dir_file_type(mode);
+        self.edit_entry(name, |data, end| DirEntry::retarget(data, end, name, inode_num as u32, file_type))
+    }
+    /// free what the inode owns once its last link is gone: its blocks, its share of an
+    /// external xattr block and its references to xattr value inodes
+    pub(crate) fn release(&mut self) -> Result<(), Error> {
+        let block_size = self.fs.sb().block_size();
+        let mode = self.mode();
+        /* device numbers and fast symlink targets live in i_block, no blocks behind them */
+        let has_blocks = mode.is_file() || mode.is_dir() || (mode.is_symlink() && self.inode.fast_symlink(block_size).is_none());
+        let seed = self.write_csum_seed();
+        if has_blocks {
+            match &mut self.content {
+                Content::Blocks(BlockMap::Extents(tree)) => tree.truncate(self.fs, 0, seed)?,
+                Content::Blocks(BlockMap::Indirect(map)) => map.release(self.fs)?,
+                Content::Inline(_) => 0,
+            };
+        }
+        let mut value_inodes = Vec::new();
+        if self.fs.sb().inode_size() > Inode::GOOD_OLD_SIZE {
+            let raw = self.fs.read_inode_raw(self.inode_num)
+                .ok_or_else(|| Error::IOError(format!("failed to read inode {}", self.inode_num)))?;
+            value_inodes.extend(XattrEntry::ibody_entries(&raw, self.inode.extra_isize()).iter().map(|x| x.value_inum()));
+        }
+        let acl_block = self.inode.file_acl();
+        if let Some((header, entries)) = self.xattr_block()? {
+            match header.refcount() {
+                /* the values of a block are referenced once, by the block */
+                0 | 1 => {
+                    self.fs.free_blocks(acl_block, 1)?;
+                    value_inodes.extend(entries.iter().map(|x| x.value_inum()));
+                }
+                refcount => {
+                    let seed = self.fs.sb().has_metadata_csum().then(|| self.fs.sb().csum_seed());
+                    let mut data = self.fs.read_block(acl_block)?;
+                    XattrBlockHeader::set_refcount(&mut data, acl_block, refcount - 1, seed);
+                    self.fs.write_meta_block(acl_block, &data)?;
+                }
+            }
+        }
+        for inode_num in value_inodes.into_iter().filter(|n| *n != 0) {
+            self.fs.drop_ea_ref(inode_num)?;
+        }
+        Ok(())
+    }
+    fn linear_lookup(&mut self, name: &str) -> Result<Option<(DirEntry, String)>, Error> {
+        if let Content::Inline(data) = &self.content {
+            let parent = u32::from_le_bytes(data[..4].try_into().unwrap());
+            let found = match name {
+                "." => Some((DirEntry::synthetic(self.inode_num as u32, "."), ".".to_string())),
+                ".." => Some((DirEntry::synthetic(parent, ".."), "..".to_string())),
+                _ => DirEntry::entries(&data[4..Inode::INLINE_DATA_SIZE])
+                    .into_iter()
+                    .chain(DirEntry::entries(&data[Inode::INLINE_DATA_SIZE..]))
+                    .find(|(_, n)| n == name),
+            };
+            return Ok(found);
+        }
+        for index in 0..self.blocks_count() {
+            let data = self.read_dir_block(index)?;
+            if let Some(found) = DirEntry::entries(&data).into_iter().find(|(_, n)| n == name) {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+    /* the hash of `name` in this indexed directory, the root info and the path down to the leaf
+     * the hash falls in */
+    fn dx_path(&mut self, name: &str) -> Result<(u32, DxRootInfo, DxPath), Error> {
+        let root = self.read_dir_block(0)?;
+        let (info, entries) = DxRootInfo::from_bytes(&root)
+            .ok_or_else(|| Error::InvalidData(format!("bad htree root in {}", self.name)))?;
+        let hash = self.dx_hash(&info, name.as_bytes())?;
+        let mut path: DxPath = Vec::new();
+        let mut entries = entries;
+        loop {
+            let pos = entries[1..].partition_point(|e| e.hash <= hash);
+            let block = entries[pos].block as u64;
+            path.push((entries, pos));
+            if path.len() > info.indirect_levels() as usize {
+                break;
+            }
+            entries = dx_node_entries(&self.read_dir_block(block)?)
+                .ok_or_else(|| Error::InvalidData(format!("bad htree node in {}", self.name)))?;
+        }
+        Ok((hash, info, path))
+    }
+    fn dx_lookup(&mut self, name: &str) -> Result<Option<(DirEntry, String)>, Error> {
+        let (hash, info, mut path) = self.dx_path(name)?;
+        loop {
+            let (entries, pos) = path.last().unwrap();
+            let data = self.read_dir_block(entries[*pos].block as u64)?;
+            if let Some(found) = DirEntry::entries(&data).into_iter().find(|(_, n)| n == name) {
+                return Ok(Some(found));
+            }
+            /* names with the same hash may continue in the next leaf, whose hash then has bit 0 set */
+            while path.last().is_some_and(|(entries, pos)| pos + 1 == entries.len()) {
+                path.pop();
+            }
+            let Some((entries, pos)) = path.last_mut() else {
+                return Ok(None);
+            };
+            *pos += 1;
+            if entries[*pos].hash & !1 != hash {
+                return Ok(None);
+            }
+            while path.len() <= info.indirect_levels() as usize {
+                let (entries, pos) = path.last().unwrap();
+                let node = dx_node_entries(&self.read_dir_block(entries[*pos].block as u64)?)
+                    .ok_or_else(|| Error::InvalidData(format!("bad htree node in {}", self.name)))?;
+                path.push((node, 0));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use crate::{to_slice, Error, Ext4File, FileSystem, IFlags, Inode, Mode, MountOptions, SuperBlock, XattrEntry};
+    use crate::ext4::testdata::MemDevice;
+
+    /* a new inode of `mode` linked as `name` in the root, its `data` in i_block and past 60
+     * bytes in system.data, next to a user.x xattr */
+    fn inline(fs: &mut FileSystem, name: &str, mode: Mode, data: &[u8], size: u64) -> u64 {
+        let inode_num = fs.alloc_inode(2, mode).unwrap();
+        let mut inode = Inode::default();
+        inode.set_mode(mode);
+        inode.set_links_count(if mode.is_dir() { 2 } else { 1 });
+        inode.set_extra_isize(32);
+        inode.set_flags(IFlags::InlineData);
+        let mut block = [0u8; 60];
+        let n = data.len().min(60);
+        block[..n].copy_from_slice(&data[..n]);
+        inode.set_block(block);
+        inode.set_size(size);
+        /* the magic, the entries of system.data and user.x, and their values at the end */
+        let mut raw = vec![0u8; 256];
+        let rest = data.len().saturating_sub(60);
+        raw[160..164].copy_from_slice(&XattrEntry::MAGIC.to_le_bytes());
+        let entry = |raw: &mut [u8], at: usize, index: u8, name: &[u8], offs: u16, size: usize| {
+            raw[at] = name.len() as u8;
+            raw[at + 1] = index;
+            raw[at + 2..at + 4].copy_from_slice(&offs.to_le_bytes());
+            raw[at + 8..at + 12].copy_from_slice(&(size as u32).to_le_bytes());
+            raw[at + 16..at + 16 + name.len()].copy_from_slice(name);
+        };
+        entry(&mut raw, 164, XattrEntry::INDEX_SYSTEM, b"data", 48, rest);
+        entry(&mut raw, 184, XattrEntry::INDEX_USER, b"x", 88, 3);
+        raw[164 + 48..164 + 48 + rest].copy_from_slice(&data[60.min(data.len())..]);
+        raw[164 + 88..164 + 91].copy_from_slice(b"yes");
+        fs.store_inode(inode_num, &mut inode, raw).unwrap();
+        let root = fs.root_inode().unwrap();
+        Ext4File::new(2, root, String::from("/"), fs).unwrap().add_entry(name, inode_num, mode).unwrap();
+        inode_num
+    }
+
+    fn ibody_xattrs(fs: &mut FileSystem, inode_num: u64) -> Vec<String> {
+        let raw = fs.read_inode_raw(inode_num).unwrap();
+        XattrEntry::ibody_entries(&raw, 32).iter().map(|x| x.full_name()).collect()
+    }
+
+    /* a change to an inline file moves its data to a block first */
+    #[test]
+    fn write_inline_file() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        let data = (0..80u8).collect::<Vec<u8>>();
+        let f = inline(&mut fs, "f", Mode::FILE | Mode::from_bits_retain(0o644), &data, 80);
+        assert_eq!(ibody_xattrs(&mut fs, f), ["system.data", "user.x"]);
+        let mut file = fs.open("/f").unwrap();
+        file.append(b"tail").unwrap();
+        let inode = fs.read_inode(f).unwrap();
+        assert!(inode.flags().contains(IFlags::Extents) && !inode.has_inline_data());
+        assert_eq!(ibody_xattrs(&mut fs, f), ["user.x"]);
+        drop(fs);
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        let mut file = fs.open("/f").unwrap();
+        let mut read = vec![0u8; 100];
+        let n = file.read(&mut read).unwrap();
+        assert_eq!(&read[..n], [data.as_slice(), b"tail"].concat().as_slice());
+        assert_eq!(file.get_xattr("user.x").unwrap(), b"yes");
+        /* an empty one needs no block */
+        let g = inline(&mut fs, "g", Mode::FILE | Mode::from_bits_retain(0o644), &[], 0);
+        fs.open("/g").unwrap().set_len(10).unwrap();
+        let inode = fs.read_inode(g).unwrap();
+        assert_eq!((inode.size(), inode.blocks_count(), inode.has_inline_data()), (10, 0, false));
+    }
+
+    /* adding to, and moving, an inline directory turns it into a block of records */
+    #[test]
+    fn change_inline_dir() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        let x = fs.create("/x", Mode::from_bits_retain(0o644)).unwrap().inode_num();
+        let dir = Mode::DIRECTORY | Mode::from_bits_retain(0o755);
+        /* the parent, then a record of `a` linking to /x spanning the rest of i_block */
+        let mut data = vec![0u8; 60];
+        data[..4].copy_from_slice(&2u32.to_le_bytes());
+        data[4..8].copy_from_slice(&(x as u32).to_le_bytes());
+        data[8..10].copy_from_slice(&56u16.to_le_bytes());
+        data[10] = 1;
+        data[11] = 1;
+        data[12] = b'a';
+        let d = inline(&mut fs, "d", dir, &data, 60);
+        let e = inline(&mut fs, "e", dir, &2u32.to_le_bytes(), 60);
+        assert_eq!(fs.open("/d/a").unwrap().inode_num(), x);
+        fs.create("/d/b", Mode::from_bits_retain(0o644)).unwrap();
+        assert!(!fs.read_inode(d).unwrap().has_inline_data());
+        let mut names = fs.open("/d").unwrap().read_dir().unwrap().iter().map(|e| String::from(e.name())).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [".", "..", "a", "b"]);
+        assert_eq!(fs.open("/d/a").unwrap().inode_num(), x);
+        assert_eq!(fs.open("/d/..").unwrap().inode_num(), 2);
+        fs.rename("/e", "/d/e").unwrap();
+        assert!(!fs.read_inode(e).unwrap().has_inline_data());
+        assert_eq!(fs.open("/d/e/..").unwrap().inode_num(), d);
+        assert_eq!(ibody_xattrs(&mut fs, e), ["user.x"]);
+    }
+
+    /* use up the free blocks but `left` of those an ordinary write may take */
+    fn fill(fs: &mut FileSystem, left: u64) {
+        let mut free = fs.sb().free_block_count() - fs.sb().reserved_block_count();
+        while free > left {
+            let (_, len) = fs.alloc_blocks(0, free - left).unwrap();
+            free -= len;
+        }
+    }
+
+    /* a write running out of blocks halfway: with a journal nothing of it stays, without one
+     * what was written is kept as a short write */
+    #[test]
+    fn failed_write() {
+        for journal in [true, false] {
+            let device = MemDevice::image();
+            if !journal {
+                let mut raw = device.block(1);
+                /* s_feature_compat loses has_journal */
+                raw[0x5c] &= !4;
+                let mut sb = SuperBlock::from_buffer(&raw).unwrap();
+                sb.update_checksum();
+                device.set_block(1, to_slice!(&sb, SuperBlock));
+            }
+            let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+            fs.create("/f", Mode::from_bits_retain(0o644)).unwrap().write(&[1u8; 1024]).unwrap();
+            fill(&mut fs, 4);
+            let free = fs.sb().free_block_count();
+            let mut file = fs.open("/f").unwrap();
+            file.seek(1024);
+            let result = file.write(&[2u8; 8 * 1024]);
+            let expect = match journal {
+                true => {
+                    assert!(matches!(result, Err(Error::NoSpace(_))), "{:?}", result);
+                    (1024, free)
+                }
+                false => {
+                    assert_eq!(result.unwrap(), 4 * 1024);
+                    (5 * 1024, free - 4)
+                }
+            };
+            assert_eq!(file.pos(), expect.0);
+            drop(file);
+            assert_eq!(fs.sb().free_block_count(), expect.1);
+            drop(fs);
+            let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+            assert_eq!((fs.open("/f").unwrap().size(), fs.sb().free_block_count()), expect);
+        }
+    }
 }
@@ -1,20 +1,377 @@
-use super::{Inode, SuperBlock, ROOT_INODE_NUM, Ext4File};
-use crate::ext4::superblock::FeatureInCompat;
+use super::{Error, Inode, SuperBlock, ROOT_INODE_NUM, Ext4File};
+use crate::{ExtentTree, IFlags, Mode};
+use crate::error::MetadataKind;
+use crate::ext4::superblock::{FeatureInCompat, FeatureRoCompat, MountOpt};
+use crate::ext4::group::GroupFlags;
+use crate::{CoreRead, Group};
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
 use alloc::{format, vec};
 use alloc::boxed::Box;
-use crate::ext4::Disk;
+use crate::ext4::{BlockDevice, Disk, DiskDevice};
+use crate::cache::{BlockCache, CacheStats};
+use crate::to_slice;
+use crate::journal::Journal;
+
+/// what to do when a metadata checksum does not match
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    /// fail with `Error::Checksum`, as Linux does
+    #[default]
+    Reject,
+    /// go on with the data and remember the mismatch in `FileSystem::checksum_errors`
+    Warn,
+    /// do not verify checksums at all
+    Ignore,
+}
+
+/// how file data is ordered against the journal, the journal mode of the default mount options
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum DataMode {
+    /// data is logged with the metadata, data=journal
+    Journal,
+    /// data is on the device before the transaction pointing at it commits, data=ordered
+    #[default]
+    Ordered,
+    /// data may come after its transaction, data=writeback; written as in ordered mode
+    Writeback,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MountOptions {
+    pub checksum: ChecksumPolicy,
+    /// blocks the `BlockCache` keeps, 0 reads everything from the disk
+    pub cache_blocks: usize,
+    /// keep written blocks in the cache until `FileSystem::flush`, a journal commit or the cache
+    /// running out of room, rather than writing them through; needs `cache_blocks`
+    pub write_back: bool,
+    /// seconds since the epoch for the timestamps of changed inodes, there is no clock in core
+    pub clock: fn() -> u64,
+    /// allocate from the blocks `s_r_blocks_count` keeps back for root, as ext4 lets a privileged
+    /// caller; without it allocations fail with `Error::NoSpace` once only those are left
+    pub use_reserved: bool,
+    /// leave the device as found: a journal that needs recovery is replayed into memory and
+    /// every change fails with `Error::ReadOnly`
+    pub read_only: bool,
+}
+
+impl MountOptions {
+    pub const DEFAULT_CACHE_BLOCKS: usize = 256;
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self { checksum: ChecksumPolicy::default(), cache_blocks: Self::DEFAULT_CACHE_BLOCKS, write_back: false, clock: || 0, use_reserved: false, read_only: false }
+    }
+}
 
 pub struct FileSystem {
     sb: SuperBlock,
-    pub(crate) disk: Box<dyn Disk>,
+    pub(crate) disk: BlockCache,
+    options: MountOptions,
+    csum_errors: Vec<Error>,
+    /* failures to drop a failed transaction, whose own error is the one returned */
+    rollback_errors: Vec<Error>,
+    /* the group descriptor table, `desc_size` bytes per group */
+    gdt: Vec<u8>,
+    /* where transactions are logged, none without an internal journal or on a read-only mount */
+    journal: Option<Journal>,
+    /* handles in the running transaction, 0 when there is none */
+    handles: u32,
+    /* blocks the running transaction may still add to the log */
+    credits: usize,
 }
 
 impl FileSystem {
-    pub fn new(mut f: Box<(dyn Disk)>) -> Option<Self> {
-        let sb = SuperBlock::new(&mut f)?;
-        Some(Self { disk: f, sb })
+    /// symlinks followed in one path walk before giving up, as Linux' MAXSYMLINKS
+    pub const MAX_SYMLINKS: usize = 40;
+    /// most links an inode can have, EXT4_LINK_MAX
+    pub const LINK_MAX: u16 = 65000;
+    /// mount a `Disk` with the default options, read-only as the old trait cannot write
+    pub fn new(f: Box<dyn Disk>) -> Option<Self> {
+        Self::mount(Box::new(DiskDevice::new(f)), MountOptions { read_only: true, ..MountOptions::default() }).ok()
+    }
+    /// read the superblock and the group descriptor table, checking them as `options` asks, after
+    /// replaying the journal if the filesystem was not cleanly unmounted
+    pub fn mount(device: Box<dyn BlockDevice>, options: MountOptions) -> Result<Self, Error> {
+        let sb = SuperBlock::new(device.as_ref()).ok_or_else(|| Error::InvalidData("invalid superblock".to_string()))?;
+        let disk = match options.write_back {
+            true => BlockCache::new_write_back(device, sb.block_size(), options.cache_blocks),
+            false => BlockCache::new(device, sb.block_size(), options.cache_blocks),
+        };
+        let gdt = Group::read_table(&disk, &sb)?;
+        let mut fs = Self { disk, sb, options, csum_errors: Vec::new(), rollback_errors: Vec::new(), gdt, journal: None, handles: 0, credits: 0 };
+        if fs.sb.needs_recovery() {
+            fs.recover_journal()?;
+        }
+        let sb_block = SuperBlock::OFFSET as u64 / fs.sb.block_size();
+        fs.check_csum(fs.sb.checksum_valid(), MetadataKind::SuperBlock, sb_block)?;
+        if fs.sb.has_group_desc_csum() && options.checksum != ChecksumPolicy::Ignore {
+            let bad = fs.groups().filter(|g| !g.checksum_valid()).map(|g| g.num()).collect::<Vec<u64>>();
+            for group_num in bad {
+                let block = Group::desc_offset(&fs.sb, group_num) / fs.sb.block_size();
+                fs.check_csum(false, MetadataKind::GroupDesc, block)?;
+            }
+        }
+        if !options.read_only && fs.sb.has_journal() && fs.sb.journal_inum() != 0 {
+            fs.journal = Some(Journal::open(&mut fs)?);
+        }
+        Ok(fs)
+    }
+    /* replay the journal left by an unclean unmount, then read the superblock and the group
+     * descriptors again as the journal may hold newer copies; a writable mount also empties the
+     * journal and clears the recovery flag, as e2fsck does */
+    fn recover_journal(&mut self) -> Result<(), Error> {
+        let mut journal = Journal::open(self)?;
+        let end = journal.replay(self)?;
+        self.reload()?;
+        if !self.options.read_only {
+            self.disk.flush()?;
+            journal.mark_empty(self, end.wrapping_add(1))?;
+            self.disk.flush()?;
+            self.update_sb(|sb| sb.set_needs_recovery(false))?;
+            self.disk.flush()?;
+        }
+        Ok(())
+    }
+    /* read the superblock and the group descriptors again, after the device changed under them */
+    fn reload(&mut self) -> Result<(), Error> {
+        self.sb = SuperBlock::new(&self.disk).ok_or_else(|| Error::InvalidData("invalid superblock".to_string()))?;
+        self.gdt = Group::read_table(&self.disk, &self.sb)?;
+        Ok(())
+    }
+    /// run `f` as one transaction: with a journal, everything it changes reaches the disk
+    /// together, or on an error not at all; without one, changes are written as they are made.
+    /// Transactions nest, the outermost one commits. Every change made through `FileSystem`
+    /// and `Ext4File` is a transaction of its own unless it runs inside one, but for a write of
+    /// logged data too large for one, which takes several (see `Ext4File::write`); metadata
+    /// written outside any, e.g. by a bare `alloc_blocks`, goes straight to its place. A transaction
+    /// changing more blocks than the journal can log fails with `Error::NoSpace`; one failing
+    /// to commit leaves the mount read-only, as Linux aborting its journal
+    pub fn transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        self.start()?;
+        let result = f(self);
+        self.stop(result)
+    }
+    /// join the running transaction, or start one, until the matching `stop`
+    #[inline]
+    pub(crate) fn start(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        if self.handles == 0 {
+            /* a transaction may fill the log, it is never committed before its last handle */
+            self.credits = self.journal.as_ref().map_or(0, |journal| journal.max_blocks());
+        }
+        self.handles += 1;
+        Ok(())
+    }
+    /// leave the transaction with the outcome of the work done in it; the last handle commits
+    /// it, or drops its changes after an error
+    pub(crate) fn stop<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        self.handles -= 1;
+        match (self.handles, result) {
+            (0, Ok(value)) => self.commit().map(|_| value),
+            (0, Err(e)) => {
+                if let Err(rollback) = self.abort() {
+                    self.rollback_failed(rollback);
+                }
+                Err(e)
+            }
+            (_, result) => result,
+        }
+    }
+    /// transactions go through the journal, so a failed one leaves nothing behind
+    #[inline]
+    pub(crate) fn is_journaled(&self) -> bool {
+        self.journal.is_some()
+    }
+    /// a transaction is running, its changes not on the device yet
+    #[inline]
+    pub(crate) fn in_transaction(&self) -> bool {
+        self.handles > 0
+    }
+    /// remember that going back to what the device holds failed after `e`, the caller getting
+    /// the error of the transaction instead
+    pub(crate) fn rollback_failed(&mut self, e: Error) {
+        self.rollback_errors.push(e)
+    }
+    /// failures to drop the changes of a failed transaction, oldest first; after one, what is
+    /// held in memory may not match the device and the filesystem is best mounted again
+    #[inline]
+    pub fn rollback_errors(&self) -> &[Error] {
+        &self.rollback_errors
+    }
+    /// how file data is written, from the default mount options
+    pub fn data_mode(&self) -> DataMode {
+        const JOURNAL: u32 = MountOpt::JmodeData.bits();
+        const WRITEBACK: u32 = MountOpt::JmodeWback.bits();
+        match self.sb.default_mount_opts().bits() & WRITEBACK {
+            JOURNAL => DataMode::Journal,
+            WRITEBACK => DataMode::Writeback,
+            _ => DataMode::Ordered,
+        }
+    }
+    /// the data of `inode` is logged like metadata, by data=journal or its journal_data flag
+    pub(crate) fn journals_data(&self, inode: &Inode) -> bool {
+        self.journal.is_some()
+            && (self.data_mode() == DataMode::Journal || inode.flags().contains(IFlags::JournalData))
+    }
+    /* log the blocks the transaction held back, then write them home; from before the commit
+     * until the journal is empty again the superblock asks for recovery, which replays them */
+    fn commit(&mut self) -> Result<(), Error> {
+        let Some(mut journal) = self.journal.take() else {
+            return Ok(());
+        };
+        let result = self.commit_to(&mut journal);
+        self.journal = Some(journal);
+        if result.is_err() {
+            /* as Linux aborting its journal: the device may hold part of the transaction, which
+             * the next mount recovers or not, and nothing more is written until then */
+            self.options.read_only = true;
+        }
+        result
+    }
+    fn commit_to(&mut self, journal: &mut Journal) -> Result<(), Error> {
+        if self.disk.shadowed() == 0 {
+            return Ok(());
+        }
+        self.sb.set_needs_recovery(true);
+        self.sb.update_checksum();
+        self.disk.shadow_write(SuperBlock::OFFSET as u64, to_slice!(&self.sb, SuperBlock))?;
+        let blocks = self.disk.take_shadows();
+        let result = self.write_out(journal, &blocks);
+        if result.is_err() {
+            /* what the transaction changed stays readable, whatever the device holds */
+            for (block_num, data) in blocks {
+                self.disk.shadow(block_num, data);
+            }
+        }
+        result
+    }
+    fn write_out(&mut self, journal: &mut Journal, blocks: &BTreeMap<u64, Vec<u8>>) -> Result<(), Error> {
+        /* Linux wipes a journal whose filesystem does not need recovery, so the flag goes home
+         * before the log names the transaction, on the superblock as it was before it */
+        let mut home = SuperBlock::new(&self.disk).ok_or_else(|| Error::InvalidData("invalid superblock".to_string()))?;
+        home.set_needs_recovery(true);
+        home.update_checksum();
+        self.disk.write_at(SuperBlock::OFFSET as u64, to_slice!(&home, SuperBlock))?;
+        self.disk.flush()?;
+        journal.log(self, blocks)?;
+        for (block_num, data) in blocks {
+            self.write_block(*block_num, data)?;
+        }
+        self.disk.flush()?;
+        let next_tid = journal.next_tid();
+        journal.mark_empty(self, next_tid)?;
+        /* the empty log is on the device before the flag stops asking for it to be replayed, which
+         * write-back alone would not order */
+        self.disk.flush()?;
+        self.update_sb(|sb| sb.set_needs_recovery(false))
+    }
+    /* forget the changes of a failed transaction, going back to what the device holds */
+    fn abort(&mut self) -> Result<(), Error> {
+        match self.journal.is_some() {
+            true => {
+                self.disk.take_shadows();
+                self.reload()
+            }
+            false => Ok(()),
+        }
+    }
+    /// write metadata at byte `offset`: in the running transaction when there is a journal,
+    /// which fails with nothing written once the blocks it holds would outgrow the log, and
+    /// straight to the device otherwise
+    pub(crate) fn write_meta(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.check_writable()?;
+        if self.journal.is_none() || self.handles == 0 {
+            return self.disk.write_at(offset, data);
+        }
+        let blocks = self.disk.unshadowed(offset, data.len());
+        if blocks > self.credits {
+            return Err(Error::NoSpace("the transaction does not fit the journal".to_string()));
+        }
+        self.credits -= blocks;
+        self.disk.shadow_write(offset, data)
+    }
+    /// how many blocks of the data of `inode` one transaction of their own may write, `None`
+    /// when data is not logged or the transaction would not be theirs: half of what the
+    /// journal holds, leaving the rest to the metadata
+    pub(crate) fn data_credits(&self, inode: &Inode) -> Option<u64> {
+        match self.journals_data(inode) && self.handles == 0 {
+            true => self.journal.as_ref().map(|journal| (journal.max_blocks() / 2).max(1) as u64),
+            false => None,
+        }
+    }
+    /// `write_meta` of a whole block
+    #[inline]
+    pub(crate) fn write_meta_block(&mut self, block_num: u64, data: &[u8]) -> Result<(), Error> {
+        self.write_meta(block_num * self.sb.block_size(), data)
+    }
+    /// group `group_num` out of the descriptor table read at mount
+    pub fn group(&self, group_num: u64) -> Option<Group<'_>> {
+        let size = self.sb.desc_size() as usize;
+        let start = group_num as usize * size;
+        Group::from_bytes(&self.sb, group_num, self.gdt.get(start..start + size)?)
+    }
+    /// every block group in order, with its free counts, bitmaps and inode table
+    pub fn groups(&self) -> impl Iterator<Item = Group<'_>> + '_ {
+        (0..self.sb.get_groups_count() as u64).filter_map(|group_num| self.group(group_num))
+    }
+    /// byte offset of `inode_num` on disk
+    pub fn inode_offset(&self, inode_num: u64) -> Result<u64, Error> {
+        if inode_num == 0 || inode_num > self.sb.inodes_count() {
+            return Err(Error::InvalidInput(format!("inode {} out of range", inode_num)));
+        }
+        self.group((inode_num - 1) / self.sb.inodes_per_group())
+            .map(|group| group.inode_offset(inode_num))
+            .ok_or_else(|| Error::InvalidData(format!("no group descriptor for inode {}", inode_num)))
+    }
+    #[inline]
+    pub fn options(&self) -> MountOptions {
+        self.options
+    }
+    /// the time to stamp inodes with, from `MountOptions::clock`
+    #[inline]
+    pub fn now(&self) -> u64 {
+        (self.options.clock)()
+    }
+    /// push everything written so far to the device
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.disk.flush()
+    }
+    /// hits, misses and write-backs of the block cache since mount
+    #[inline]
+    pub fn cache_stats(&self) -> CacheStats {
+        self.disk.stats()
+    }
+    /// mismatches let through by `ChecksumPolicy::Warn`, oldest first
+    #[inline]
+    pub fn checksum_errors(&self) -> &[Error] {
+        &self.csum_errors
+    }
+    /// fail with `Error::ReadOnly` on a read-only mount, which must leave the device as found
+    #[inline]
+    pub fn check_writable(&self) -> Result<(), Error> {
+        match self.options.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
+    /// metadata_csum is on and the policy asks to compute checksums
+    #[inline]
+    pub(crate) fn verify_csums(&self) -> bool {
+        self.sb.has_metadata_csum() && self.options.checksum != ChecksumPolicy::Ignore
+    }
+    /// apply the checksum policy to the outcome of a verification
+    pub(crate) fn check_csum(&mut self, valid: bool, kind: MetadataKind, block: u64) -> Result<(), Error> {
+        match (valid, self.options.checksum) {
+            (true, _) | (false, ChecksumPolicy::Ignore) => Ok(()),
+            (false, ChecksumPolicy::Warn) => {
+                self.csum_errors.push(Error::Checksum(kind, block));
+                Ok(())
+            }
+            (false, ChecksumPolicy::Reject) => Err(Error::Checksum(kind, block)),
+        }
     }
     pub const fn sb(&self) -> &SuperBlock {
         &self.sb
@@ -27,40 +384,599 @@ impl FileSystem {
     pub fn info(&self) -> String {
         format!("{}", self.sb)
     }
-    pub fn root_inode(&mut self) -> Inode {
-        Inode::new(&mut self.disk, ROOT_INODE_NUM, &self.sb).unwrap()
+    pub fn root_inode(&mut self) -> Result<Inode, Error> {
+        self.read_inode(ROOT_INODE_NUM)
+    }
+    pub fn read_inode(&mut self, inode_num: u64) -> Result<Inode, Error> {
+        let offset = self.inode_offset(inode_num)?;
+        let mut raw = vec![0u8; self.sb.inode_size() as usize];
+        self.disk.read_at(offset, &mut raw)?;
+        let inode = raw.as_slice().read_struct::<Inode>()?;
+        if self.verify_csums() {
+            let valid = inode.checksum_valid(&raw, inode_num, &self.sb);
+            self.check_csum(valid, MetadataKind::Inode, offset / self.sb.block_size())?;
+        }
+        Ok(inode)
+    }
+    /// write `inode` back over its on-disk copy, checksummed, keeping its in-inode xattrs
+    pub fn write_inode(&mut self, inode_num: u64, inode: &mut Inode) -> Result<(), Error> {
+        self.check_writable()?;
+        let raw = self.read_inode_raw(inode_num)
+            .ok_or_else(|| Error::IOError(format!("failed to read inode {}", inode_num)))?;
+        self.store_inode(inode_num, inode, raw)
+    }
+    /// lay `inode` over `raw`, the whole on-disk inode with its in-inode xattrs, and write it to
+    /// the inode table
+    pub(crate) fn store_inode(&mut self, inode_num: u64, inode: &mut Inode, mut raw: Vec<u8>) -> Result<(), Error> {
+        let offset = self.inode_offset(inode_num)?;
+        inode.write_raw(&mut raw, inode_num, &self.sb);
+        self.write_meta(offset, &raw)
     }
-    pub fn read_inode(&mut self, inode_num: u64) -> Inode {
-        Inode::new(&mut self.disk, inode_num, &self.sb).unwrap()
+    pub fn read_inode_raw(&mut self, inode_num: u64) -> Option<Vec<u8>> {
+        let offset = self.inode_offset(inode_num).ok()?;
+        let mut raw = vec![0u8; self.sb.inode_size() as usize];
+        self.disk.read_at(offset, &mut raw).ok()?;
+        Some(raw)
     }
-    pub fn read_block(&mut self, block_num: u64) -> Result<Vec<u8>, ()> {
+    pub fn read_block(&mut self, block_num: u64) -> Result<Vec<u8>, Error> {
         let block_size = self.sb.block_size();
         let mut buffer = vec![0u8; block_size as usize];
-        self.disk.seek_to(block_size * block_num);
-        match self.disk.read(&mut buffer) {
-            Ok(_) => Ok(buffer),
-            Err(_) => Err(()),
-        }
-    }
-    pub fn open(&mut self, path: &str) -> Option<Ext4File> {
-        if path == "/" {
-            return Some(Ext4File::new(self.root_inode(), path.to_string(), self))
-        }
-        let path_parts = path.split("/").filter(|p| !p.is_empty()).collect::<Vec<&str>>();
-        let mut current = self.root_inode();
-        let mut current_entry= Default::default();
-        for part in &path_parts {
-            'found: for entry in Ext4File::new(current, part.to_string(), self).read_dir().unwrap() {
-                if entry.name() == *part{
-                    current = entry.inode();
-                    current_entry = entry;
-                    break 'found
+        self.disk.read_at(block_num * block_size, &mut buffer)?;
+        Ok(buffer)
+    }
+    /// the block bitmap of `group_num`, computed for a BLOCK_UNINIT group and checked otherwise
+    pub fn read_block_bitmap(&mut self, group_num: u64) -> Result<Vec<u8>, Error> {
+        let group = self.group(group_num)
+            .ok_or_else(|| Error::InvalidInput(format!("no block group {}", group_num)))?;
+        if group.flags().contains(GroupFlags::BlockUninit) {
+            return Ok(group.init_block_bitmap());
+        }
+        let block = group.block_bitmap();
+        let bitmap = self.read_block(block)?;
+        if self.verify_csums() {
+            let valid = self.group(group_num).is_some_and(|group| group.block_bitmap_checksum_valid(&bitmap));
+            self.check_csum(valid, MetadataKind::BlockBitmap, block)?;
+        }
+        Ok(bitmap)
+    }
+    /// the inode bitmap of `group_num`, empty for an INODE_UNINIT group and checked otherwise
+    pub fn read_inode_bitmap(&mut self, group_num: u64) -> Result<Vec<u8>, Error> {
+        let group = self.group(group_num)
+            .ok_or_else(|| Error::InvalidInput(format!("no block group {}", group_num)))?;
+        if group.flags().contains(GroupFlags::InodeUninit) {
+            return Ok(group.init_inode_bitmap());
+        }
+        let block = group.inode_bitmap();
+        let bitmap = self.read_block(block)?;
+        if self.verify_csums() {
+            let valid = self.group(group_num).is_some_and(|group| group.inode_bitmap_checksum_valid(&bitmap));
+            self.check_csum(valid, MetadataKind::InodeBitmap, block)?;
+        }
+        Ok(bitmap)
+    }
+    /// write a whole block to the device, or the cache with write-back, past any transaction
+    pub fn write_block(&mut self, block_num: u64, data: &[u8]) -> Result<(), Error> {
+        self.check_writable()?;
+        self.disk.write_at(block_num * self.sb.block_size(), data)
+    }
+    /// change the descriptor of `group_num` through `update`, then checksum it and write it to
+    /// the table on disk and the copy kept since mount
+    pub(crate) fn update_group(&mut self, group_num: u64, update: impl FnOnce(&mut Group)) -> Result<(), Error> {
+        let raw = {
+            let mut group = self.group(group_num)
+                .ok_or_else(|| Error::InvalidInput(format!("no block group {}", group_num)))?;
+            update(&mut group);
+            group.update_checksum();
+            group.raw().to_vec()
+        };
+        let start = group_num as usize * raw.len();
+        self.gdt[start..start + raw.len()].copy_from_slice(&raw);
+        self.write_meta(Group::desc_offset(&self.sb, group_num), &raw)
+    }
+    /// change the superblock through `update`, then checksum it and write it back
+    pub(crate) fn update_sb(&mut self, update: impl FnOnce(&mut SuperBlock)) -> Result<(), Error> {
+        update(&mut self.sb);
+        self.sb.update_checksum();
+        let raw = to_slice!(&self.sb, SuperBlock).to_vec();
+        self.write_meta(SuperBlock::OFFSET as u64, &raw)
+    }
+    /// walk `path` from the directory `start` (the root for absolute paths), following
+    /// symlinks in the middle and, if `follow`, at the end
+    pub(crate) fn resolve(&mut self, start: (u64, Inode, &str), path: &str, follow: bool) -> Result<(u64, Inode, String), Error> {
+        let components = |path: &str| path.split('/').filter(|p| !p.is_empty()).rev().map(|p| p.to_string()).collect::<Vec<String>>();
+        if path.is_empty() {
+            return Err(Error::NotFound(path.to_string()));
+        }
+        /* remaining components, next one last */
+        let mut parts = components(path);
+        let (mut current_num, mut current, mut name) = match path.starts_with('/') {
+            true => (ROOT_INODE_NUM, self.root_inode()?, "/".to_string()),
+            false => (start.0, start.1, start.2.to_string()),
+        };
+        let mut links = 0;
+        while let Some(part) = parts.pop() {
+            if !current.mode().is_dir() {
+                return Err(Error::NotADirectory(name));
+            }
+            let entry = Ext4File::new(current_num, current, name.clone(), self)?
+                .lookup(&part)?
+                .ok_or_else(|| Error::NotFound(part.clone()))?;
+            /* a trailing slash asks for a directory, so a final symlink is followed too */
+            if entry.mode().is_symlink() && (follow || !parts.is_empty() || path.ends_with('/')) {
+                links += 1;
+                if links > Self::MAX_SYMLINKS {
+                    return Err(Error::SymlinkLoop(path.to_string()));
+                }
+                let target = Ext4File::new(entry.inode_num(), entry.inode(), part.clone(), self)?
+                    .read_link()?;
+                if target.is_empty() {
+                    return Err(Error::NotFound(part));
+                }
+                /* relative targets resolve from the directory holding the link */
+                if target.starts_with('/') {
+                    current_num = ROOT_INODE_NUM;
+                    current = self.root_inode()?;
+                    name = "/".to_string();
+                }
+                parts.extend(components(&target));
+                continue;
+            }
+            current_num = entry.inode_num();
+            current = entry.inode();
+            name = part;
+        }
+        if path.ends_with('/') && !current.mode().is_dir() {
+            return Err(Error::NotADirectory(name));
+        }
+        Ok((current_num, current, name))
+    }
+    pub fn open(&mut self, path: &str) -> Result<Ext4File<'_>, Error> {
+        let root = (ROOT_INODE_NUM, self.root_inode()?, "/");
+        let (inode_num, inode, name) = self.resolve(root, path, true)?;
+        Ext4File::new(inode_num, inode, name, self)
+    }
+    /// like lstat: a symlink at the end of `path` is opened itself instead of followed
+    pub fn open_nofollow(&mut self, path: &str) -> Result<Ext4File<'_>, Error> {
+        let root = (ROOT_INODE_NUM, self.root_inode()?, "/");
+        let (inode_num, inode, name) = self.resolve(root, path, false)?;
+        Ext4File::new(inode_num, inode, name, self)
+    }
+    /// create an empty regular file at `path` with the permission bits of `mode`, failing when
+    /// the name is taken
+    pub fn create(&mut self, path: &str, mode: Mode) -> Result<Ext4File<'_>, Error> {
+        let (inode_num, inode, name) = self.transaction(|fs| fs.new_file(path, mode))?;
+        Ext4File::new(inode_num, inode, name, self)
+    }
+    fn new_file(&mut self, path: &str, mode: Mode) -> Result<(u64, Inode, String), Error> {
+        let mode = Mode::from_bits_retain(mode.bits() & 0o7777) | Mode::FILE;
+        let (dir_num, dir_name, name) = self.new_entry(path)?;
+        let inode_num = self.alloc_inode(dir_num, mode)?;
+        let mut inode = self.new_inode(mode);
+        inode.set_block(ExtentTree::empty().root_bytes());
+        inode.set_flags(IFlags::Extents);
+        let raw = vec![0u8; self.sb.inode_size() as usize];
+        let linked = self.store_inode(inode_num, &mut inode, raw).and_then(|_| {
+            let dir = self.read_inode(dir_num)?;
+            Ext4File::new(dir_num, dir, dir_name, self)?.add_entry(&name, inode_num, mode)
+        });
+        if let Err(e) = linked {
+            self.discard_inode(inode_num, mode)?;
+            return Err(e);
+        }
+        Ok((inode_num, inode, name))
+    }
+    /// create an empty directory at `path` with the permission bits of `mode`
+    pub fn mkdir(&mut self, path: &str, mode: Mode) -> Result<Ext4File<'_>, Error> {
+        let (inode_num, inode, name) = self.transaction(|fs| fs.new_dir(path, mode))?;
+        Ext4File::new(inode_num, inode, name, self)
+    }
+    fn new_dir(&mut self, path: &str, mode: Mode) -> Result<(u64, Inode, String), Error> {
+        let mode = Mode::from_bits_retain(mode.bits() & 0o7777) | Mode::DIRECTORY;
+        let (dir_num, dir_name, name) = self.new_entry(path)?;
+        let dir_links = self.read_inode(dir_num)?.links_count();
+        let parent_links = self.link_added(dir_links, true)?;
+        let inode_num = self.alloc_inode(dir_num, mode)?;
+        let mut inode = self.new_inode(mode);
+        inode.set_links_count(2);
+        inode.set_block(ExtentTree::empty().root_bytes());
+        inode.set_flags(IFlags::Extents);
+        let raw = vec![0u8; self.sb.inode_size() as usize];
+        let linked = self.store_inode(inode_num, &mut inode, raw).and_then(|_| {
+            Ext4File::new(inode_num, inode, name.clone(), self)?.init_dir(dir_num)?;
+            let dir = self.read_inode(dir_num)?;
+            Ext4File::new(dir_num, dir, dir_name, self)?.add_entry(&name, inode_num, mode)
+        });
+        if let Err(e) = linked {
+            self.release_inode(inode_num)?;
+            return Err(e);
+        }
+        /* the new `..` links to the parent */
+        let mut dir = self.read_inode(dir_num)?;
+        dir.set_links_count(parent_links);
+        self.write_inode(dir_num, &mut dir)?;
+        let inode = self.read_inode(inode_num)?;
+        Ok((inode_num, inode, name))
+    }
+    /// make `new` another name of the file at `old`, which cannot be a directory; a symlink at
+    /// the end of `old` is linked itself, like link(2)
+    pub fn link(&mut self, old: &str, new: &str) -> Result<(), Error> {
+        self.transaction(|fs| fs.add_link(old, new))
+    }
+    fn add_link(&mut self, old: &str, new: &str) -> Result<(), Error> {
+        let root = (ROOT_INODE_NUM, self.root_inode()?, "/");
+        let (inode_num, mut inode, _) = self.resolve(root, old, false)?;
+        if inode.mode().is_dir() {
+            return Err(Error::IsADirectory(old.to_string()));
+        }
+        let links = self.link_added(inode.links_count(), false)?;
+        let (dir_num, dir_name, name) = self.new_entry(new)?;
+        let dir = self.read_inode(dir_num)?;
+        Ext4File::new(dir_num, dir, dir_name, self)?.add_entry(&name, inode_num, inode.mode())?;
+        inode.set_links_count(links);
+        inode.set_ctime(self.now());
+        self.write_inode(inode_num, &mut inode)
+    }
+    /// remove the name `path` of anything but a directory; the file goes with its last link
+    pub fn unlink(&mut self, path: &str) -> Result<(), Error> {
+        self.transaction(|fs| fs.remove_link(path))
+    }
+    fn remove_link(&mut self, path: &str) -> Result<(), Error> {
+        let (dir_num, dir, dir_name, name) = self.parent_of(path)?;
+        let entry = Ext4File::new(dir_num, dir, dir_name.clone(), self)?
+            .lookup(&name)?
+            .ok_or_else(|| Error::NotFound(path.to_string()))?;
+        if entry.mode().is_dir() {
+            return Err(Error::IsADirectory(path.to_string()));
+        }
+        let dir = self.read_inode(dir_num)?;
+        Ext4File::new(dir_num, dir, dir_name, self)?.remove_entry(&name)?;
+        self.drop_link(entry.inode_num())
+    }
+    /// remove the empty directory `path`
+    pub fn rmdir(&mut self, path: &str) -> Result<(), Error> {
+        self.transaction(|fs| fs.remove_dir(path))
+    }
+    fn remove_dir(&mut self, path: &str) -> Result<(), Error> {
+        let (dir_num, dir, dir_name, name) = self.parent_of(path)?;
+        let entry = Ext4File::new(dir_num, dir, dir_name.clone(), self)?
+            .lookup(&name)?
+            .ok_or_else(|| Error::NotFound(path.to_string()))?;
+        if !entry.mode().is_dir() {
+            return Err(Error::NotADirectory(path.to_string()));
+        }
+        let empty = Ext4File::new(entry.inode_num(), entry.inode(), name.clone(), self)?
+            .read_dir()?
+            .iter()
+            .all(|e| e.name() == "." || e.name() == "..");
+        if !empty {
+            return Err(Error::DirectoryNotEmpty(path.to_string()));
+        }
+        let dir = self.read_inode(dir_num)?;
+        Ext4File::new(dir_num, dir, dir_name, self)?.remove_entry(&name)?;
+        self.release_inode(entry.inode_num())?;
+        /* its `..` no longer links to the parent */
+        let mut dir = self.read_inode(dir_num)?;
+        dir.set_links_count(Self::link_dropped(dir.links_count(), true));
+        self.write_inode(dir_num, &mut dir)
+    }
+    /// move the entry at `from` to `to`, in the same directory or another one, replacing what
+    /// `to` names: a file by anything but a directory, an empty directory by a directory. An
+    /// existing `to` is pointed at the moved inode in a single write, so it never goes missing
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        self.transaction(|fs| fs.move_entry(from, to))
+    }
+    fn move_entry(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        let (from_dir_num, from_dir, from_dir_name, from_name) = self.parent_of(from)?;
+        let entry = Ext4File::new(from_dir_num, from_dir, from_dir_name.clone(), self)?
+            .lookup(&from_name)?
+            .ok_or_else(|| Error::NotFound(from.to_string()))?;
+        let (inode_num, mode) = (entry.inode_num(), entry.mode());
+        let (to_dir_num, to_dir, to_dir_name, to_name) = self.parent_of(to)?;
+        if mode.is_dir() && self.is_ancestor(inode_num, to_dir_num)? {
+            return Err(Error::InvalidInput(format!("cannot move {} below itself", from)));
+        }
+        let target = Ext4File::new(to_dir_num, to_dir, to_dir_name.clone(), self)?.lookup(&to_name)?;
+        if let Some(target) = &target {
+            /* two names of the same file */
+            if target.inode_num() == inode_num {
+                return Ok(());
+            }
+            match (mode.is_dir(), target.mode().is_dir()) {
+                (true, false) => return Err(Error::NotADirectory(to.to_string())),
+                (false, true) => return Err(Error::IsADirectory(to.to_string())),
+                (true, true) => {
+                    let empty = Ext4File::new(target.inode_num(), target.inode(), to_name.clone(), self)?
+                        .read_dir()?
+                        .iter()
+                        .all(|e| e.name() == "." || e.name() == "..");
+                    if !empty {
+                        return Err(Error::DirectoryNotEmpty(to.to_string()));
+                    }
                 }
+                (false, false) => {}
             }
         }
-        match &current_entry.name() == path_parts.last().unwrap(){
-            true => Some(Ext4File::new(current, current_entry.name().to_string(),self)),
-            false => None
+        /* a directory moving elsewhere takes the link of its `..` along */
+        let moves_dir = mode.is_dir() && from_dir_num != to_dir_num;
+        let replaces_dir = target.as_ref().is_some_and(|t| t.mode().is_dir());
+        let to_dir_links = match (moves_dir, replaces_dir) {
+            (true, false) => {
+                let links = self.read_inode(to_dir_num)?.links_count();
+                Some(self.link_added(links, true)?)
+            }
+            (false, true) => Some(Self::link_dropped(self.read_inode(to_dir_num)?.links_count(), true)),
+            _ => None,
+        };
+        let to_dir = self.read_inode(to_dir_num)?;
+        let mut dir = Ext4File::new(to_dir_num, to_dir, to_dir_name, self)?;
+        match &target {
+            Some(_) => dir.set_entry(&to_name, inode_num, mode).map(|_| ())?,
+            None => dir.add_entry(&to_name, inode_num, mode)?,
+        }
+        let from_dir = self.read_inode(from_dir_num)?;
+        Ext4File::new(from_dir_num, from_dir, from_dir_name, self)?.remove_entry(&from_name)?;
+        if moves_dir {
+            let inode = self.read_inode(inode_num)?;
+            Ext4File::new(inode_num, inode, to_name, self)?.set_entry("..", to_dir_num, Mode::DIRECTORY)?;
+            let mut dir = self.read_inode(from_dir_num)?;
+            dir.set_links_count(Self::link_dropped(dir.links_count(), true));
+            self.write_inode(from_dir_num, &mut dir)?;
+        }
+        if let Some(links) = to_dir_links {
+            let mut dir = self.read_inode(to_dir_num)?;
+            dir.set_links_count(links);
+            self.write_inode(to_dir_num, &mut dir)?;
+        }
+        let mut inode = self.read_inode(inode_num)?;
+        inode.set_ctime(self.now());
+        self.write_inode(inode_num, &mut inode)?;
+        match target {
+            Some(target) if replaces_dir => self.release_inode(target.inode_num()),
+            Some(target) => self.drop_link(target.inode_num()),
+            None => Ok(()),
         }
     }
+    /* whether the directory `ancestor` is `inode_num` or above it */
+    fn is_ancestor(&mut self, ancestor: u64, mut inode_num: u64) -> Result<bool, Error> {
+        while inode_num != ancestor {
+            if inode_num == ROOT_INODE_NUM {
+                return Ok(false);
+            }
+            let inode = self.read_inode(inode_num)?;
+            inode_num = Ext4File::new(inode_num, inode, String::new(), self)?
+                .lookup("..")?
+                .ok_or_else(|| Error::InvalidData(format!("directory {} has no ..", inode_num)))?
+                .inode_num();
+        }
+        Ok(true)
+    }
+    /* one link less to `inode_num`, which goes with the last one */
+    fn drop_link(&mut self, inode_num: u64) -> Result<(), Error> {
+        let mut inode = self.read_inode(inode_num)?;
+        match inode.links_count() {
+            0 | 1 => self.release_inode(inode_num),
+            links => {
+                inode.set_links_count(links - 1);
+                inode.set_ctime(self.now());
+                self.write_inode(inode_num, &mut inode)
+            }
+        }
+    }
+    /* the link count after one more link, as ext4_inc_count: a directory past EXT4_LINK_MAX
+     * goes to 1, which dir_nlink reads as too many to count */
+    fn link_added(&self, links: u16, is_dir: bool) -> Result<u16, Error> {
+        match (links, is_dir) {
+            (1, true) => Ok(1),
+            (links, _) if links < Self::LINK_MAX => Ok(links + 1),
+            (_, true) if self.sb.feature_ro_compat().contains(FeatureRoCompat::DirNlink) => Ok(1),
+            _ => Err(Error::InvalidInput("too many links".to_string())),
+        }
+    }
+    /* the link count after one link less, as ext4_dec_count, which leaves an uncounted
+     * directory at 1 and never takes one below 2 */
+    fn link_dropped(links: u16, is_dir: bool) -> u16 {
+        match is_dir && links <= 2 {
+            true => links,
+            false => links.saturating_sub(1),
+        }
+    }
+    /* free an inode no longer linked anywhere together with its blocks */
+    fn release_inode(&mut self, inode_num: u64) -> Result<(), Error> {
+        let inode = self.read_inode(inode_num)?;
+        let mode = inode.mode();
+        Ext4File::new(inode_num, inode, String::new(), self)?.release()?;
+        self.discard_inode(inode_num, mode)
+    }
+    /// drop one reference to the xattr value inode `inode_num`, which goes with the last one
+    pub(crate) fn drop_ea_ref(&mut self, inode_num: u64) -> Result<(), Error> {
+        let mut inode = self.read_inode(inode_num)?;
+        if !inode.flags().contains(IFlags::EAInode) {
+            return Err(Error::InvalidData(format!("inode {} holds no xattr value", inode_num)));
+        }
+        match inode.ea_refcount() {
+            0 | 1 => self.release_inode(inode_num),
+            refcount => {
+                inode.set_ea_refcount(refcount - 1);
+                self.write_inode(inode_num, &mut inode)
+            }
+        }
+    }
+    /* give back an inode that is not linked anywhere, zeroed as if never used */
+    fn discard_inode(&mut self, inode_num: u64, mode: Mode) -> Result<(), Error> {
+        let offset = self.inode_offset(inode_num)?;
+        self.write_meta(offset, &vec![0u8; self.sb.inode_size() as usize])?;
+        self.free_inode(inode_num, mode)
+    }
+    /* the directory holding the last component of `path`, its name and that component, which
+     * must name an entry that may be added or removed */
+    fn parent_of(&mut self, path: &str) -> Result<(u64, Inode, String, String), Error> {
+        let trimmed = path.trim_end_matches('/');
+        let (dir, name) = match trimmed.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((dir, name)) => (dir, name),
+            None => ("/", trimmed),
+        };
+        if name.is_empty() || name == "." || name == ".." || name.len() > 255 {
+            return Err(Error::InvalidInput(format!("invalid name {}", path)));
+        }
+        let root = (ROOT_INODE_NUM, self.root_inode()?, "/");
+        let (dir_num, dir, dir_name) = self.resolve(root, dir, true)?;
+        Ok((dir_num, dir, dir_name, name.to_string()))
+    }
+    /* the directory a new entry for `path` goes in, its name and the entry's name, which must
+     * be free */
+    fn new_entry(&mut self, path: &str) -> Result<(u64, String, String), Error> {
+        if !self.sb.feature_in_compat().contains(FeatureInCompat::Extents) {
+            return Err(Error::InvalidInput("creating files needs the extent feature".to_string()));
+        }
+        let (dir_num, dir, dir_name, name) = self.parent_of(path)?;
+        if Ext4File::new(dir_num, dir, dir_name.clone(), self)?.lookup(&name)?.is_some() {
+            return Err(Error::FileExists(path.to_string()));
+        }
+        Ok((dir_num, dir_name, name))
+    }
+    /* a fresh inode of `mode`, one link, owned by root and stamped with the current time */
+    fn new_inode(&self, mode: Mode) -> Inode {
+        let now = self.now();
+        let mut inode = Inode::default();
+        inode.set_mode(mode);
+        inode.set_links_count(1);
+        if self.sb.inode_size() > Inode::GOOD_OLD_SIZE {
+            let extra_isize = match self.sb.want_extra_isize() {
+                0 => Inode::SIZE as u64 - Inode::GOOD_OLD_SIZE,
+                want => want,
+            };
+            inode.set_extra_isize(extra_isize.min(self.sb.inode_size() - Inode::GOOD_OLD_SIZE) as u16);
+        }
+        inode.set_atime(now);
+        inode.set_ctime(now);
+        inode.set_mtime(now);
+        inode.set_crtime(now);
+        inode
+    }
+    pub fn readlink(&mut self, path: &str) -> Result<String, Error> {
+        self.open_nofollow(path)?.read_link()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::format;
+    use crate::{Error, FileSystem, IFlags, Mode, MountOptions};
+    use crate::ext4::testdata::MemDevice;
+
+    fn links(fs: &mut FileSystem, inode_num: u64) -> u16 {
+        fs.read_inode(inode_num).unwrap().links_count()
+    }
+
+    /* the directory `path` with names long enough that it is indexed, returning its inode */
+    fn indexed_dir(fs: &mut FileSystem, path: &str) -> u64 {
+        let dir = fs.mkdir(path, Mode::from_bits_retain(0o755)).unwrap().inode_num();
+        fs.create(&format!("{}/f", path), Mode::from_bits_retain(0o644)).unwrap();
+        for i in 0..5 {
+            fs.link(&format!("{}/f", path), &format!("{}/{:0>200}", path, i)).unwrap();
+        }
+        assert!(fs.read_inode(dir).unwrap().flags().contains(IFlags::Index));
+        dir
+    }
+
+    /* what the links and the directories hold, and that everything is given back at the end */
+    #[test]
+    fn mkdir_link_unlink_rmdir() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        let (free_blocks, free_inodes) = (fs.sb().free_block_count(), fs.sb().free_inodes_count());
+        let root_links = links(&mut fs, 2);
+        let a = fs.mkdir("/a", Mode::from_bits_retain(0o755)).unwrap().inode_num();
+        assert_eq!((links(&mut fs, a), links(&mut fs, 2)), (2, root_links + 1));
+        assert!(matches!(fs.mkdir("/a", Mode::from_bits_retain(0o755)), Err(Error::FileExists(_))));
+        let f = fs.create("/a/f", Mode::from_bits_retain(0o644)).unwrap().inode_num();
+        fs.link("/a/f", "/b").unwrap();
+        assert_eq!(fs.open("/b").unwrap().inode_num(), f);
+        assert_eq!(links(&mut fs, f), 2);
+        assert!(matches!(fs.link("/a", "/c"), Err(Error::IsADirectory(_))));
+        assert!(matches!(fs.link("/a/f", "/b"), Err(Error::FileExists(_))));
+        assert!(matches!(fs.link("/a/g", "/c"), Err(Error::NotFound(_))));
+        assert!(matches!(fs.unlink("/a"), Err(Error::IsADirectory(_))));
+        assert!(matches!(fs.rmdir("/a"), Err(Error::DirectoryNotEmpty(_))));
+        assert!(matches!(fs.rmdir("/b"), Err(Error::NotADirectory(_))));
+        fs.unlink("/b").unwrap();
+        assert_eq!(links(&mut fs, f), 1);
+        fs.unlink("/a/f").unwrap();
+        assert!(matches!(fs.unlink("/a/f"), Err(Error::NotFound(_))));
+        fs.rmdir("/a").unwrap();
+        assert_eq!(links(&mut fs, 2), root_links);
+        assert_eq!((fs.sb().free_block_count(), fs.sb().free_inodes_count()), (free_blocks, free_inodes));
+        drop(fs);
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        assert!(matches!(fs.open("/a"), Err(Error::NotFound(_))));
+        assert_eq!(fs.open("/").unwrap().read_dir().unwrap().len(), 3);
+    }
+
+    /* within a directory, into an indexed one and over what the target names */
+    #[test]
+    fn rename() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        let f = fs.create("/f", Mode::from_bits_retain(0o644)).unwrap().inode_num();
+        fs.rename("/f", "/g").unwrap();
+        assert!(matches!(fs.open("/f"), Err(Error::NotFound(_))));
+        assert_eq!(fs.open("/g").unwrap().inode_num(), f);
+        let s = fs.mkdir("/s", Mode::from_bits_retain(0o755)).unwrap().inode_num();
+        fs.create("/s/x", Mode::from_bits_retain(0o644)).unwrap();
+        let d = indexed_dir(&mut fs, "/d");
+        let (root_links, d_links) = (links(&mut fs, 2), links(&mut fs, d));
+        fs.rename("/s", "/d/s").unwrap();
+        assert_eq!(fs.open("/d/s").unwrap().inode_num(), s);
+        assert_eq!(fs.open("/d/s/..").unwrap().inode_num(), d);
+        assert_eq!((links(&mut fs, 2), links(&mut fs, d)), (root_links - 1, d_links + 1));
+        assert!(matches!(fs.rename("/d", "/d/s/d"), Err(Error::InvalidInput(_))));
+        /* a file over a file, which goes with its last link */
+        let h = fs.create("/d/s/h", Mode::from_bits_retain(0o644)).unwrap().inode_num();
+        let free_inodes = fs.sb().free_inodes_count();
+        fs.rename("/g", "/d/s/h").unwrap();
+        assert_eq!(fs.open("/d/s/h").unwrap().inode_num(), f);
+        assert_eq!(fs.sb().free_inodes_count(), free_inodes + 1);
+        assert_eq!(links(&mut fs, h), 0);
+        /* a directory over an empty directory only */
+        fs.mkdir("/e", Mode::from_bits_retain(0o755)).unwrap();
+        assert!(matches!(fs.rename("/e", "/d/s"), Err(Error::DirectoryNotEmpty(_))));
+        assert!(matches!(fs.rename("/e", "/d/f"), Err(Error::NotADirectory(_))));
+        assert!(matches!(fs.rename("/d/f", "/e"), Err(Error::IsADirectory(_))));
+        let e = fs.open("/e").unwrap().inode_num();
+        fs.mkdir("/d/e", Mode::from_bits_retain(0o755)).unwrap();
+        fs.rename("/e", "/d/e").unwrap();
+        assert_eq!(fs.open("/d/e/..").unwrap().inode_num(), d);
+        assert_eq!(links(&mut fs, d), d_links + 2);
+        drop(fs);
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        assert_eq!(fs.open("/d/e").unwrap().inode_num(), e);
+        assert!(fs.open("/d/s/x").is_ok());
+    }
+
+    /* dots, repeated slashes and a file in the middle of a path, through an indexed directory */
+    #[test]
+    fn resolve() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device), MountOptions::default()).unwrap();
+        let d = indexed_dir(&mut fs, "/d");
+        let sub = fs.mkdir("/d/sub", Mode::from_bits_retain(0o755)).unwrap().inode_num();
+        let f = fs.open("/d/f").unwrap().inode_num();
+        assert_eq!(fs.open("/d/sub/./..").unwrap().inode_num(), d);
+        assert_eq!(fs.open("/d/sub/../..").unwrap().inode_num(), 2);
+        assert_eq!(fs.open("/..").unwrap().inode_num(), 2);
+        assert_eq!(fs.open("//d///sub//").unwrap().inode_num(), sub);
+        assert_eq!(fs.open("/./d/./sub/../f").unwrap().inode_num(), f);
+        assert!(matches!(fs.open("/d/f/"), Err(Error::NotADirectory(_))));
+        assert!(matches!(fs.open("/d/f/."), Err(Error::NotADirectory(_))));
+        assert!(matches!(fs.open("/d/f/x"), Err(Error::NotADirectory(_))));
+        assert!(matches!(fs.open("/d/sub/x"), Err(Error::NotFound(_))));
+        assert!(matches!(fs.open(""), Err(Error::NotFound(_))));
+        let mut dir = fs.open("/d/sub").unwrap();
+        assert_eq!(dir.open("./..").unwrap().inode_num(), d);
+        assert_eq!(dir.open("../f").unwrap().inode_num(), f);
+        assert_eq!(dir.open("/d").unwrap().inode_num(), d);
+        assert!(matches!(dir.open("../f/.."), Err(Error::NotADirectory(_))));
+    }
 }
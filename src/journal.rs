@@ -0,0 +1,401 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use crate::ext4::journal::{BlockTag, JournalBlockType, JournalHeader, JournalSuperBlock, TagFlags, JBD2_MAGIC};
+use crate::{BlockMap, ChecksumPolicy, Error, FileSystem, MetadataKind};
+
+/* transaction `a` comes after `b`, sequence numbers wrapping around as tid_gt */
+#[inline]
+fn tid_gt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/* the newest committed copy of a filesystem block in the log */
+struct Logged {
+    journal_block: u64,
+    sequence: u32,
+    tag: BlockTag,
+}
+
+/// the jbd2 journal kept in the journal inode
+pub(crate) struct Journal {
+    sb: JournalSuperBlock,
+    /* the superblock as read, for writing it back */
+    raw_sb: Vec<u8>,
+    map: BlockMap,
+    /* checksums of the log are verified unless the mount ignores checksums */
+    verify: bool,
+    /* the transaction `log` writes next */
+    next_tid: u32,
+}
+
+impl Journal {
+    /// read the superblock of the journal of `fs`, which must live in an inode
+    pub(crate) fn open(fs: &mut FileSystem) -> Result<Self, Error> {
+        let inode_num = fs.sb().journal_inum();
+        if !fs.sb().has_journal() {
+            return Err(Error::InvalidData("needs recovery but has no journal".to_string()));
+        }
+        if inode_num == 0 {
+            return Err(Error::InvalidInput("the journal is on an external device".to_string()));
+        }
+        let inode = fs.read_inode(inode_num)?;
+        let mut map = inode.block_map()
+            .ok_or_else(|| Error::InvalidData(format!("journal inode {} has no blocks", inode_num)))?;
+        if fs.verify_csums() {
+            map.set_csum_seed(inode.csum_seed(inode_num, fs.sb()));
+        }
+        let verify = fs.options().checksum != ChecksumPolicy::Ignore;
+        let raw_sb = fs.read_block(Self::locate(&mut map, fs, 0)?)?;
+        let sb = JournalSuperBlock::parse(&raw_sb)
+            .ok_or_else(|| Error::InvalidData("invalid journal superblock".to_string()))?;
+        if verify {
+            fs.check_csum(sb.checksum_valid(&raw_sb), MetadataKind::Journal, 0)?;
+        }
+        if sb.block_size() != fs.sb().block_size() {
+            return Err(Error::InvalidData(format!("journal block size {} is not the filesystem's", sb.block_size())));
+        }
+        if sb.unknown_features() != 0 {
+            return Err(Error::InvalidData(format!("unsupported journal features {:#x}", sb.unknown_features())));
+        }
+        if sb.first() == 0 || sb.first() >= sb.last() || sb.max_len() > inode.size() / sb.block_size() {
+            return Err(Error::InvalidData("journal superblock does not fit the journal inode".to_string()));
+        }
+        /* as Linux, the transaction after the one an empty journal names */
+        let next_tid = sb.sequence().wrapping_add(1);
+        Ok(Self { sb, raw_sb, map, verify, next_tid })
+    }
+    /* where block `index` of the journal lives, holes are an error */
+    fn locate(map: &mut BlockMap, fs: &FileSystem, index: u64) -> Result<u64, Error> {
+        map.map_block(&fs.disk, fs.sb().block_size(), index)?
+            .ok_or_else(|| Error::InvalidData(format!("hole at block {} of the journal", index)))
+    }
+    fn read(&mut self, fs: &mut FileSystem, index: u64) -> Result<Vec<u8>, Error> {
+        let block = Self::locate(&mut self.map, fs, index)?;
+        fs.read_block(block)
+    }
+    fn write(&mut self, fs: &mut FileSystem, index: u64, data: &[u8]) -> Result<(), Error> {
+        let block = Self::locate(&mut self.map, fs, index)?;
+        fs.write_block(block, data)
+    }
+    /* the block after `index` in the log, which wraps around to `s_first` */
+    #[inline]
+    fn next(&self, index: u64) -> u64 {
+        match index + 1 >= self.sb.last() {
+            true => self.sb.first(),
+            false => index + 1,
+        }
+    }
+    /* walk the log from `s_start` as the scan and revoke passes of recovery.c at once, taking a
+     * transaction only when its commit block is found; returns the newest copy of every logged
+     * block, the newest transaction revoking each revoked block and the first transaction that
+     * did not commit */
+    #[allow(clippy::type_complexity)]
+    fn scan(&mut self, fs: &mut FileSystem) -> Result<(BTreeMap<u64, Logged>, BTreeMap<u64, u32>, u32), Error> {
+        let mut logged = BTreeMap::new();
+        let mut revoked = BTreeMap::new();
+        let mut sequence = self.sb.sequence();
+        let mut index = self.sb.start();
+        /* blocks and revocations of the transaction being read */
+        let mut blocks = Vec::new();
+        let mut revokes = Vec::new();
+        let mut remaining = self.sb.last() - self.sb.first();
+        while remaining > 0 {
+            remaining -= 1;
+            let block = self.read(fs, index)?;
+            let header = match JournalHeader::parse(&block) {
+                Some(header) if header.sequence == sequence => header,
+                _ => break,
+            };
+            match header.block_type {
+                JournalBlockType::Descriptor => {
+                    /* a stale descriptor of an older lap around the log can be anything */
+                    if self.verify && !self.sb.block_tail_valid(&block) {
+                        break;
+                    }
+                    for tag in self.sb.tags(&block) {
+                        index = self.next(index);
+                        remaining = remaining.saturating_sub(1);
+                        blocks.push((tag.block, Logged { journal_block: index, sequence, tag }));
+                    }
+                }
+                JournalBlockType::Revoke => {
+                    if self.verify && !self.sb.block_tail_valid(&block) {
+                        break;
+                    }
+                    revokes.extend(self.sb.revoked(&block));
+                }
+                JournalBlockType::Commit => {
+                    if self.verify && !self.sb.commit_valid(&block) {
+                        break;
+                    }
+                    logged.extend(blocks.drain(..));
+                    for block in revokes.drain(..) {
+                        revoked.insert(block, sequence);
+                    }
+                    sequence = sequence.wrapping_add(1);
+                }
+                _ => break,
+            }
+            index = self.next(index);
+        }
+        Ok((logged, revoked, sequence))
+    }
+    /// bring every block of a committed transaction home: written to the device, or shadowed
+    /// in the block cache for a read-only mount; returns the first transaction not committed
+    pub(crate) fn replay(&mut self, fs: &mut FileSystem) -> Result<u32, Error> {
+        if self.sb.start() == 0 {
+            return Ok(self.sb.sequence());
+        }
+        let (logged, revoked, end) = self.scan(fs)?;
+        for (block_num, copy) in logged {
+            /* a revocation holds back the block logged by its own transaction and earlier ones */
+            if revoked.get(&block_num).is_some_and(|seq| !tid_gt(copy.sequence, *seq)) {
+                continue;
+            }
+            if block_num >= fs.sb().block_count() {
+                return Err(Error::InvalidData(format!("journal logs block {} past the end", block_num)));
+            }
+            let mut data = self.read(fs, copy.journal_block)?;
+            if self.verify && !self.sb.data_valid(&copy.tag, copy.sequence, &data) {
+                /* as Linux, a block failing its checksum is left alone when that is allowed */
+                fs.check_csum(false, MetadataKind::Journal, copy.journal_block)?;
+                continue;
+            }
+            if copy.tag.flags.contains(TagFlags::Escape) {
+                data[..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+            }
+            match fs.options().read_only {
+                true => fs.disk.shadow(block_num, data),
+                false => fs.write_block(block_num, &data)?,
+            }
+        }
+        Ok(end)
+    }
+    /// record that the log holds nothing to replay, the next transaction being `sequence`
+    pub(crate) fn mark_empty(&mut self, fs: &mut FileSystem, sequence: u32) -> Result<(), Error> {
+        self.write_sb(fs, 0, sequence)
+    }
+    fn write_sb(&mut self, fs: &mut FileSystem, start: u64, sequence: u32) -> Result<(), Error> {
+        let mut raw_sb = core::mem::take(&mut self.raw_sb);
+        self.sb.write_start(&mut raw_sb, start, sequence);
+        let result = self.write(fs, 0, &raw_sb);
+        self.raw_sb = raw_sb;
+        result
+    }
+    #[inline]
+    pub(crate) fn next_tid(&self) -> u32 {
+        self.next_tid
+    }
+    /// most blocks one transaction logs: the log holds them, their descriptor blocks and the
+    /// commit block, with room for the filesystem superblock that every commit adds
+    pub(crate) fn max_blocks(&self) -> usize {
+        let tags = self.sb.tags_per_descriptor();
+        let space = (self.sb.last() - self.sb.first()) as usize - 2;
+        space * tags / (tags + 1) - 1
+    }
+    /// write `blocks` to the log as the next transaction and commit it, the commit block going
+    /// out only once everything written before it is on the device, the data of ordered mode
+    /// included; then point the journal superblock at the transaction so it is replayed until
+    /// `mark_empty`, and have that on the device too before the blocks may go home
+    pub(crate) fn log(&mut self, fs: &mut FileSystem, blocks: &BTreeMap<u64, Vec<u8>>) -> Result<(), Error> {
+        if blocks.len() > self.max_blocks() + 1 {
+            return Err(Error::NoSpace(format!("{} blocks do not fit the journal", blocks.len())));
+        }
+        if !self.sb.is_64bit() && blocks.keys().any(|block| *block > u32::MAX as u64) {
+            return Err(Error::InvalidInput("the journal cannot log blocks past 2^32".to_string()));
+        }
+        let sequence = self.next_tid;
+        let mut index = self.sb.first();
+        let blocks = blocks.iter().collect::<Vec<_>>();
+        for chunk in blocks.chunks(self.sb.tags_per_descriptor()) {
+            /* a copy starting with the magic number would read as a journal block, it is logged
+             * with those bytes zeroed and its tag says so */
+            let copies = chunk.iter()
+                .map(|(home, data)| {
+                    let mut copy = data.to_vec();
+                    let flags = match copy[..4] == JBD2_MAGIC.to_be_bytes() {
+                        true => {
+                            copy[..4].fill(0);
+                            TagFlags::Escape
+                        }
+                        false => TagFlags::empty(),
+                    };
+                    (**home, flags, copy)
+                })
+                .collect::<Vec<_>>();
+            let tags = copies.iter().map(|(home, flags, copy)| (*home, *flags, copy.as_slice())).collect::<Vec<_>>();
+            let descriptor = self.sb.descriptor_block(sequence, &tags);
+            self.write(fs, index, &descriptor)?;
+            for (_, _, copy) in &copies {
+                index += 1;
+                self.write(fs, index, copy)?;
+            }
+            index += 1;
+        }
+        fs.flush()?;
+        let commit = self.sb.commit_block(sequence, fs.now());
+        self.write(fs, index, &commit)?;
+        fs.flush()?;
+        self.next_tid = sequence.wrapping_add(1);
+        self.write_sb(fs, self.sb.first(), sequence)?;
+        fs.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use super::Journal;
+    use crate::ext4::journal::JBD2_MAGIC;
+    use crate::ext4::testdata::MemDevice;
+    use crate::{to_slice, FileSystem, MountOptions, SuperBlock};
+
+    /* where the log blocks of the test image's journal live */
+    const JOURNAL_SB: u64 = 17;
+    fn log_block(index: u64) -> u64 {
+        match index {
+            1 => 18,
+            2..17 => index + 18,
+            _ => index + 34,
+        }
+    }
+
+    fn header(block_type: u32, sequence: u32) -> Vec<u8> {
+        let mut block = vec![0u8; 1024];
+        block[..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+        block[4..8].copy_from_slice(&block_type.to_be_bytes());
+        block[8..12].copy_from_slice(&sequence.to_be_bytes());
+        block
+    }
+
+    /* a descriptor for `tags` of (block, flags), 8 byte tags as the journal has neither 64bit nor
+     * checksums, the uuid after the first */
+    fn descriptor(sequence: u32, tags: &[(u32, u16)]) -> Vec<u8> {
+        let mut block = header(1, sequence);
+        let mut offset = 12;
+        for (i, (block_num, flags)) in tags.iter().enumerate() {
+            let flags = match i + 1 == tags.len() {
+                true => flags | 0x8,
+                false => *flags,
+            };
+            let flags = match i {
+                0 => flags,
+                _ => flags | 0x2,
+            };
+            block[offset..offset + 4].copy_from_slice(&block_num.to_be_bytes());
+            block[offset + 6..offset + 8].copy_from_slice(&flags.to_be_bytes());
+            offset += match i {
+                0 => 8 + 16,
+                _ => 8,
+            };
+        }
+        block
+    }
+
+    fn revoke(sequence: u32, blocks: &[u32]) -> Vec<u8> {
+        let mut block = header(5, sequence);
+        let used = 16 + 4 * blocks.len() as u32;
+        block[12..16].copy_from_slice(&used.to_be_bytes());
+        for (i, block_num) in blocks.iter().enumerate() {
+            block[16 + 4 * i..20 + 4 * i].copy_from_slice(&block_num.to_be_bytes());
+        }
+        block
+    }
+
+    /* point the journal at the log from block 1 on, transaction `sequence` first, and flag the
+     * filesystem as needing recovery */
+    fn crash(device: &MemDevice, sequence: u32) {
+        let mut jsb = device.block(JOURNAL_SB);
+        jsb[0x18..0x1c].copy_from_slice(&sequence.to_be_bytes());
+        jsb[0x1c..0x20].copy_from_slice(&1u32.to_be_bytes());
+        device.set_block(JOURNAL_SB, &jsb);
+        let mut sb = SuperBlock::from_buffer(&device.block(1)).unwrap();
+        sb.set_needs_recovery(true);
+        sb.update_checksum();
+        device.set_block(1, to_slice!(&sb, SuperBlock));
+    }
+
+    /* transaction 1 logs blocks 200 to 202, 201 escaped; transaction 2 revokes 202 and logs 203;
+     * transaction 3 logs 204 but never commits */
+    fn write_log(device: &MemDevice) {
+        let escaped = [vec![0u8; 4], vec![0x55; 1020]].concat();
+        let log = [
+            descriptor(1, &[(200, 0), (201, 0x1), (202, 0)]),
+            vec![0xaa; 1024],
+            escaped,
+            vec![0xbb; 1024],
+            header(2, 1),
+            revoke(2, &[202]),
+            descriptor(2, &[(203, 0)]),
+            vec![0xcc; 1024],
+            header(2, 2),
+            descriptor(3, &[(204, 0)]),
+            vec![0xdd; 1024],
+        ];
+        for (i, block) in log.iter().enumerate() {
+            device.set_block(log_block(i as u64 + 1), block);
+        }
+        crash(device, 1);
+    }
+
+    fn unescaped() -> Vec<u8> {
+        [JBD2_MAGIC.to_be_bytes().to_vec(), vec![0x55; 1020]].concat()
+    }
+
+    #[test]
+    fn replay() {
+        let device = MemDevice::image();
+        write_log(&device);
+        let fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        drop(fs);
+        assert_eq!(device.block(200), vec![0xaa; 1024]);
+        assert_eq!(device.block(201), unescaped());
+        /* revoked by a later transaction */
+        assert_eq!(device.block(202), vec![0; 1024]);
+        assert_eq!(device.block(203), vec![0xcc; 1024]);
+        /* not committed */
+        assert_eq!(device.block(204), vec![0; 1024]);
+        /* emptied, the next transaction one past the first that did not commit */
+        let jsb = device.block(JOURNAL_SB);
+        assert_eq!(&jsb[0x18..0x20], &[0, 0, 0, 4, 0, 0, 0, 0]);
+        let sb = SuperBlock::from_buffer(&device.block(1)).unwrap();
+        assert!(!sb.needs_recovery() && sb.checksum_valid());
+    }
+
+    #[test]
+    fn replay_read_only() {
+        let device = MemDevice::image();
+        write_log(&device);
+        let before = device.0.borrow().clone();
+        let options = MountOptions { read_only: true, ..MountOptions::default() };
+        let mut fs = FileSystem::mount(Box::new(device.clone()), options).unwrap();
+        assert_eq!(fs.read_block(201).unwrap(), unescaped());
+        assert_eq!(fs.read_block(202).unwrap(), vec![0; 1024]);
+        assert_eq!(fs.read_block(203).unwrap(), vec![0xcc; 1024]);
+        assert!(*device.0.borrow() == before);
+    }
+
+    /* what `log` writes is what `replay` reads back */
+    #[test]
+    fn log_round_trip() {
+        let device = MemDevice::image();
+        let mut fs = FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        let mut journal = Journal::open(&mut fs).unwrap();
+        let sequence = journal.next_tid();
+        let mut blocks = BTreeMap::new();
+        blocks.insert(300, unescaped());
+        blocks.insert(301, vec![0xee; 1024]);
+        journal.log(&mut fs, &blocks).unwrap();
+        drop(fs);
+        assert_eq!(device.block(300), vec![0; 1024]);
+        crash(&device, sequence);
+        FileSystem::mount(Box::new(device.clone()), MountOptions::default()).unwrap();
+        assert_eq!(device.block(300), unescaped());
+        assert_eq!(device.block(301), vec![0xee; 1024]);
+    }
+}
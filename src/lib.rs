@@ -11,6 +11,9 @@ mod error;
 mod fs;
 pub mod io;
 mod file;
+mod cache;
+mod allocator;
+mod journal;
 
 #[allow(unused_imports)]
 pub use ext4::{superblock::SuperBlock,
@@ -20,17 +23,25 @@ pub use ext4::{superblock::SuperBlock,
                ROOT_INODE_NUM
 };
 #[allow(unused_imports)]
-pub use fs::FileSystem;
+pub use fs::{ChecksumPolicy, DataMode, FileSystem, MountOptions};
 #[allow(unused_imports)]
 pub use io::{CoreWrite, CoreRead};
 #[allow(unused_imports)]
-pub use error::Error;
+pub use error::{Error, MetadataKind};
 #[allow(unused_imports)]
 pub use ext4::extent_tree::ExtentTree;
 #[allow(unused_imports)]
+pub use ext4::block_map::BlockMap;
+#[allow(unused_imports)]
+pub use ext4::xattr::XattrEntry;
+#[allow(unused_imports)]
+pub use ext4::acl::{Acl, AclEntry, AclTag};
+#[allow(unused_imports)]
+pub use cache::{BlockCache, CacheStats};
+#[allow(unused_imports)]
 pub use file::Ext4File;
 #[allow(unused_imports)]
 pub use ext4::dir::DirEntry;
 #[allow(unused_imports)]
-pub use ext4::Disk;
+pub use ext4::{BlockDevice, Disk, DiskDevice};
 